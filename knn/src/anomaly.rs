@@ -0,0 +1,202 @@
+use index::Neighbor;
+use scaling::quantile;
+
+/// Scores for detecting anomalies with nearest neighbors.
+///
+/// Higher scores are more anomalous. The training instances of a model
+/// are used as the reference distribution.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AnomalyScore {
+    /// The distance to the k-th nearest neighbor.
+    KthDistance,
+
+    /// The mean distance to the k nearest neighbors.
+    MeanDistance,
+
+    /// The Local Outlier Factor of Breunig et al. (2000).
+    ///
+    /// The ratio of the mean local reachability density of the k nearest
+    /// neighbors to the local reachability density of the data point.
+    /// Data points in regions that are as dense as those of their
+    /// neighbors have a score of about 1. Unlike distance-based scores,
+    /// the factor adapts to regions with different densities.
+    LocalOutlierFactor,
+}
+
+impl AnomalyScore {
+    /// Compute the score of a data point from its nearest neighbors.
+    ///
+    /// The densities of the training instances are required for the
+    /// Local Outlier Factor.
+    pub(crate) fn score(
+        self,
+        nearest_neighbors: &[Neighbor],
+        densities: Option<&Densities>,
+    ) -> f32 {
+        let distances = nearest_neighbors
+            .iter()
+            .map(|neighbor| neighbor.distance.into_inner());
+
+        match self {
+            AnomalyScore::KthDistance => distances.fold(0., f32::max),
+            AnomalyScore::MeanDistance => distances.sum::<f32>() / nearest_neighbors.len() as f32,
+            AnomalyScore::LocalOutlierFactor => densities
+                .expect("The Local Outlier Factor requires training densities")
+                .local_outlier_factor(nearest_neighbors),
+        }
+    }
+}
+
+/// Local reachability densities of training instances.
+pub(crate) struct Densities {
+    k_distances: Vec<f32>,
+    densities: Vec<f32>,
+}
+
+impl Densities {
+    /// Estimate the densities from the k nearest neighbors of every
+    /// training instance, excluding the instance itself.
+    ///
+    /// Instances without neighbors, such as removed instances, are not
+    /// used as neighbors and may have any density.
+    pub fn new(nearest_others: &[Vec<Neighbor>]) -> Self {
+        let k_distances: Vec<_> = nearest_others
+            .iter()
+            .map(|nearest_neighbors| AnomalyScore::KthDistance.score(nearest_neighbors, None))
+            .collect();
+
+        let densities = nearest_others
+            .iter()
+            .map(|nearest_neighbors| reachability_density(&k_distances, nearest_neighbors))
+            .collect();
+
+        Densities {
+            k_distances,
+            densities,
+        }
+    }
+
+    fn local_outlier_factor(&self, nearest_neighbors: &[Neighbor]) -> f32 {
+        let neighbor_density = nearest_neighbors
+            .iter()
+            .map(|neighbor| self.densities[neighbor.idx])
+            .sum::<f32>()
+            / nearest_neighbors.len() as f32;
+
+        neighbor_density / reachability_density(&self.k_distances, nearest_neighbors)
+    }
+}
+
+/// Compute the local reachability density of a data point.
+///
+/// This is the inverse of the mean reachability distance to the nearest
+/// neighbors, where the reachability distance to a neighbor is at least
+/// the k-distance of the neighbor. A small constant is added to the
+/// mean, so that densities of duplicate instances are finite.
+fn reachability_density(k_distances: &[f32], nearest_neighbors: &[Neighbor]) -> f32 {
+    let reachability = nearest_neighbors
+        .iter()
+        .map(|neighbor| {
+            neighbor
+                .distance
+                .into_inner()
+                .max(k_distances[neighbor.idx])
+        })
+        .sum::<f32>()
+        / nearest_neighbors.len() as f32;
+
+    1. / (reachability + 1e-10)
+}
+
+/// Get the score threshold for a contamination rate.
+///
+/// The contamination rate is the expected fraction of anomalies. The
+/// threshold is the quantile of the scores at one minus the rate, so
+/// that roughly that fraction of the scores is above the threshold.
+pub fn contamination_threshold(scores: &[f32], contamination: f32) -> f32 {
+    assert!(
+        !scores.is_empty(),
+        "Cannot compute a threshold without scores"
+    );
+    assert!(
+        contamination > 0. && contamination < 1.,
+        "The contamination should be in (0, 1), was: {}",
+        contamination
+    );
+
+    let mut sorted = scores.to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).expect("Score is NaN"));
+    quantile(&sorted, 1. - contamination)
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::Array2;
+
+    use index::tests::{feature_matrix, read_instances};
+    use {contamination_threshold, AnomalyScore, IndexType, Instance, KNNBuilder, KNN};
+
+    fn model(points: &[f32]) -> KNN {
+        let mut builder = KNNBuilder::default();
+        builder.set_index_type(IndexType::BruteForce);
+        for &x in points {
+            builder.push(Instance {
+                label: 0,
+                features: vec![x],
+            });
+        }
+        builder.into()
+    }
+
+    #[test]
+    fn anomaly_scores_test() {
+        // A dense cluster around 0 and a sparse cluster around 100.
+        let model = model(&[0., 1., 2., 3., 100., 110., 120., 130.]);
+        let queries = Array2::from_shape_vec((3, 1), vec![1.5, 125., 50.]).unwrap();
+
+        let kth = model.anomaly_scores(&queries.view(), 2, AnomalyScore::KthDistance);
+        assert_eq!(kth, vec![0.5, 5., 48.]);
+
+        let mean = model.anomaly_scores(&queries.view(), 2, AnomalyScore::MeanDistance);
+        assert_eq!(mean, vec![0.5, 5., 47.5]);
+
+        // The query in the sparse cluster is as typical as the query in
+        // the dense cluster, the query between the clusters is not.
+        let lof = model.anomaly_scores(&queries.view(), 2, AnomalyScore::LocalOutlierFactor);
+        assert!(lof[0] < 1.5);
+        assert!(lof[1] < 1.5);
+        assert!(lof[2] > 2.);
+
+        // Inserted and removed instances are taken into account, also
+        // when the training densities were computed before the update.
+        let mut updated = self::model(&[0., 1., 2., 3., 100., 110., 120., 5.]);
+        updated.anomaly_scores(&queries.view(), 2, AnomalyScore::LocalOutlierFactor);
+        updated.remove(7);
+        updated.insert(Instance {
+            label: 0,
+            features: vec![130.],
+        });
+        assert_eq!(
+            updated.anomaly_scores(&queries.view(), 2, AnomalyScore::LocalOutlierFactor),
+            lof
+        );
+    }
+
+    #[test]
+    fn contamination_threshold_test() {
+        let train = read_instances(include_str!("../moons-train.txt"));
+        let mut builder = KNNBuilder::default();
+        for instance in train.iter().cloned() {
+            builder.push(instance);
+        }
+        let model: KNN = builder.into();
+
+        let test = feature_matrix(&read_instances(include_str!("../moons-test.txt")));
+        let scores = model.anomaly_scores(&test.view(), 5, AnomalyScore::LocalOutlierFactor);
+        let threshold = contamination_threshold(&scores, 0.1);
+        let n_outliers = scores.iter().filter(|&&score| score > threshold).count();
+        assert_eq!(n_outliers, test.rows() / 10);
+
+        assert_eq!(contamination_threshold(&[4., 1., 3., 2., 5.], 0.25), 4.);
+    }
+}
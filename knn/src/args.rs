@@ -1,4 +1,4 @@
-use clap::{App, AppSettings, Arg, ArgMatches};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 
 static DEFAULT_CLAP_SETTINGS: &[AppSettings] = &[
     AppSettings::DontCollapseArgsInUsage,
@@ -8,13 +8,21 @@ static DEFAULT_CLAP_SETTINGS: &[AppSettings] = &[
 pub fn parse_args() -> ArgMatches<'static> {
     App::new("final-frontier")
         .settings(DEFAULT_CLAP_SETTINGS)
+        .setting(AppSettings::ArgsNegateSubcommands)
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .args(&data_args())
+        .args(&model_args())
+        .args(&weight_args())
+        .args(&prediction_args())
         .arg(
-            Arg::with_name("knearest")
-                .short("k")
-                .long("knearest")
-                .value_name("K")
-                .help("Number of nearest neighbors to consider in voting: (default: 3)")
-                .takes_value(true),
+            Arg::with_name("regression")
+                .long("regression")
+                .help("Predict real-valued targets rather than classes"),
+        )
+        .arg(
+            Arg::with_name("recall")
+                .long("recall")
+                .help("Report the recall of the index against brute force search"),
         )
         .arg(
             Arg::with_name("TRAIN")
@@ -28,5 +36,393 @@ pub fn parse_args() -> ArgMatches<'static> {
                 .index(2)
                 .required(true),
         )
+        .subcommand(
+            SubCommand::with_name("train")
+                .about("Train a classifier and save it to a model file")
+                .settings(DEFAULT_CLAP_SETTINGS)
+                .args(&data_args())
+                .args(&model_args())
+                .args(&weight_args())
+                .arg(
+                    Arg::with_name("TRAIN")
+                        .help("Train data")
+                        .index(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("MODEL")
+                        .help("Model file")
+                        .index(2)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("predict")
+                .about("Classify data with a saved model, printing one label per line")
+                .settings(DEFAULT_CLAP_SETTINGS)
+                .args(&data_args())
+                .args(&weight_args())
+                .args(&prediction_args())
+                .arg(
+                    Arg::with_name("seed")
+                        .long("seed")
+                        .value_name("SEED")
+                        .help("Seed for random tie breaking (default: 42)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .value_name("FILE")
+                        .help("Write the class probabilities of every instance to a tab-separated file")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("neighbors")
+                        .long("neighbors")
+                        .value_name("FILE")
+                        .help("Write the k nearest neighbors of every instance to a tab-separated file")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("smoothing")
+                        .long("smoothing")
+                        .value_name("ALPHA")
+                        .help("Additive (Laplace) smoothing of the class probabilities (default: 0)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("MODEL")
+                        .help("Model file")
+                        .index(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("TEST")
+                        .help("Data to classify")
+                        .index(2)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("cv")
+                .about("Evaluate a classifier with k-fold cross-validation")
+                .settings(DEFAULT_CLAP_SETTINGS)
+                .args(&data_args())
+                .args(&model_args())
+                .args(&weight_args())
+                .args(&prediction_args())
+                .args(&validation_args())
+                .arg(Arg::with_name("DATA").help("Data").index(1).required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("select")
+                .about("Select k, the metric, and the neighbor weights by validation")
+                .settings(DEFAULT_CLAP_SETTINGS)
+                .args(&data_args())
+                .args(&model_args())
+                .arg(
+                    Arg::with_name("bandwidth")
+                        .long("bandwidth")
+                        .value_name("H")
+                        .help("Bandwidth of the Gaussian kernel (default: 1)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("threads")
+                        .long("threads")
+                        .value_name("N")
+                        .help("Number of classification threads (default: number of CPUs)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("ties")
+                        .long("ties")
+                        .value_name("POLICY")
+                        .help("Tie breaking between classes: smallest-label, nearest, distance, random, or decrease-k (default: smallest-label)")
+                        .possible_values(&["smallest-label", "nearest", "distance", "random", "decrease-k"])
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("ks")
+                        .long("ks")
+                        .value_name("KS")
+                        .help("Comma-separated values and ranges of k, such as 1-9,15 (default: 1-15)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("metrics")
+                        .long("metrics")
+                        .value_name("METRICS")
+                        .help("Comma-separated distance metrics (default: euclidean)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("weightings")
+                        .long("weightings")
+                        .value_name("WEIGHTS")
+                        .help("Comma-separated neighbor weights (default: uniform)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("score")
+                        .long("score")
+                        .value_name("SCORE")
+                        .help("Score to select by: accuracy, macro-f1, weighted-f1, or kappa (default: accuracy)")
+                        .possible_values(&["accuracy", "macro-f1", "weighted-f1", "kappa"])
+                        .takes_value(true),
+                )
+                .args(&validation_args())
+                .arg(
+                    Arg::with_name("holdout")
+                        .long("holdout")
+                        .value_name("FRACTION")
+                        .help("Validate on a random held-out fraction of the data rather than with cross-validation")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("DATA")
+                        .help("Data")
+                        .index(1)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("score")
+                .about("Score data for anomalies against the training data of a saved model, printing one score per line")
+                .settings(DEFAULT_CLAP_SETTINGS)
+                .args(&data_args())
+                .arg(
+                    Arg::with_name("knearest")
+                        .short("k")
+                        .long("knearest")
+                        .value_name("K")
+                        .help("Number of nearest neighbors to compute scores from (default: 3)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("threads")
+                        .long("threads")
+                        .value_name("N")
+                        .help("Number of scoring threads (default: number of CPUs)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("score")
+                        .long("score")
+                        .value_name("SCORE")
+                        .help("Anomaly score: kth (distance to the k-th neighbor), mean (mean neighbor distance), or lof (Local Outlier Factor) (default: kth)")
+                        .possible_values(&["kth", "mean", "lof"])
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("contamination")
+                        .long("contamination")
+                        .value_name("FRACTION")
+                        .help("Mark the given fraction of the instances with the highest scores as outliers")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("MODEL")
+                        .help("Model file")
+                        .index(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("DATA")
+                        .help("Data to score")
+                        .index(2)
+                        .required(true),
+                ),
+        )
         .get_matches()
 }
+
+/// Options for reading data.
+fn data_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("format")
+            .long("format")
+            .value_name("FORMAT")
+            .help("Data format: dense, svmlight, or csv (default: dense). Sparse svmlight data is classified with brute force search and cannot be saved")
+            .possible_values(&["dense", "svmlight", "csv"])
+            .takes_value(true),
+        Arg::with_name("delimiter")
+            .long("delimiter")
+            .value_name("CHAR")
+            .help("CSV field delimiter (default: ,)")
+            .takes_value(true),
+        Arg::with_name("no_header")
+            .long("no-header")
+            .help("CSV files do not have a header row"),
+        Arg::with_name("label_column")
+            .long("label-column")
+            .value_name("COLUMN")
+            .help("CSV column with the labels, by name or zero-based index (default: 0)")
+            .takes_value(true),
+        Arg::with_name("columns")
+            .long("columns")
+            .value_name("COLUMNS")
+            .help("Comma-separated CSV feature columns, by name or zero-based index (default: all except the label column)")
+            .takes_value(true),
+    ]
+}
+
+/// Options for the construction of models.
+fn model_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("index")
+            .long("index")
+            .value_name("INDEX")
+            .help("Nearest neighbor search: brute, kdtree, balltree, hnsw, or lsh (default: kdtree, brute for cosine)")
+            .possible_values(&["brute", "kdtree", "balltree", "hnsw", "lsh"])
+            .takes_value(true),
+        Arg::with_name("metric")
+            .long("metric")
+            .value_name("METRIC")
+            .help("Distance metric: euclidean, sqeuclidean, manhattan, chebyshev, minkowski, cosine, or mahalanobis (default: euclidean)")
+            .possible_values(&["euclidean", "sqeuclidean", "manhattan", "chebyshev", "minkowski", "cosine", "mahalanobis"])
+            .takes_value(true),
+        Arg::with_name("scaling")
+            .long("scaling")
+            .value_name("SCALING")
+            .help("Feature scaling, fitted on the training data: standard, minmax, robust, or l2 (default: none)")
+            .possible_values(&["standard", "minmax", "robust", "l2"])
+            .takes_value(true),
+        Arg::with_name("reduce")
+            .long("reduce")
+            .value_name("METHODS")
+            .help("Comma-separated prototype selection applied in order to the training data: cnn (condensed), enn (edited), or tomek (Tomek links)")
+            .takes_value(true),
+        Arg::with_name("reduce_k")
+            .long("reduce-k")
+            .value_name("K")
+            .help("Number of neighbors in edited nearest neighbors (default: 3)")
+            .takes_value(true),
+        Arg::with_name("minkowski_p")
+            .long("minkowski-p")
+            .value_name("P")
+            .help("Order of the Minkowski distance (default: 2)")
+            .takes_value(true),
+        Arg::with_name("covariance")
+            .long("covariance")
+            .value_name("ESTIMATOR")
+            .help("Covariance estimator of the Mahalanobis distance: empirical, shrinkage, or ledoit-wolf (default: ledoit-wolf)")
+            .possible_values(&["empirical", "shrinkage", "ledoit-wolf"])
+            .takes_value(true),
+        Arg::with_name("shrinkage")
+            .long("shrinkage")
+            .value_name("S")
+            .help("Covariance shrinkage intensity in [0, 1] (default: 0.1)")
+            .takes_value(true),
+        Arg::with_name("hnsw_m")
+            .long("hnsw-m")
+            .value_name("M")
+            .help("Number of links per instance in the HNSW graph (default: 16)")
+            .validator(at_least(2))
+            .takes_value(true),
+        Arg::with_name("ef_construction")
+            .long("ef-construction")
+            .value_name("EF")
+            .help("Candidate list size for HNSW construction (default: 200)")
+            .validator(at_least(1))
+            .takes_value(true),
+        Arg::with_name("ef_search")
+            .long("ef-search")
+            .value_name("EF")
+            .help("Candidate list size for HNSW search (default: 64)")
+            .takes_value(true),
+        Arg::with_name("lsh_tables")
+            .long("lsh-tables")
+            .value_name("N")
+            .help("Number of LSH hash tables (default: 8)")
+            .takes_value(true),
+        Arg::with_name("lsh_bits")
+            .long("lsh-bits")
+            .value_name("N")
+            .help("Number of hyperplanes per LSH hash table (default: 12)")
+            .takes_value(true),
+        Arg::with_name("lsh_probes")
+            .long("lsh-probes")
+            .value_name("N")
+            .help("Number of additional LSH buckets to probe per table (default: 4)")
+            .takes_value(true),
+        Arg::with_name("seed")
+            .long("seed")
+            .value_name("SEED")
+            .help("Seed for randomized indexes, cross-validation folds, and random tie breaking (default: 42)")
+            .takes_value(true),
+    ]
+}
+
+/// Options for the weighting of neighbors and voting.
+fn weight_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("weights")
+            .long("weights")
+            .value_name("WEIGHTS")
+            .help("Neighbor weights: uniform, distance, distance2, or gaussian (default: uniform)")
+            .possible_values(&["uniform", "distance", "distance2", "gaussian"])
+            .takes_value(true),
+        Arg::with_name("bandwidth")
+            .long("bandwidth")
+            .value_name("H")
+            .help("Bandwidth of the Gaussian kernel (default: 1)")
+            .takes_value(true),
+        Arg::with_name("ties")
+            .long("ties")
+            .value_name("POLICY")
+            .help("Tie breaking between classes: smallest-label, nearest, distance, random, or decrease-k (default: smallest-label)")
+            .possible_values(&["smallest-label", "nearest", "distance", "random", "decrease-k"])
+            .takes_value(true),
+        Arg::with_name("outlier")
+            .long("outlier")
+            .value_name("LABEL")
+            .help("Label for instances without training instances within the radius (default: fail)")
+            .takes_value(true),
+    ]
+}
+
+/// Options for cross-validation.
+fn validation_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("folds")
+            .long("folds")
+            .value_name("N")
+            .help("Number of cross-validation folds (default: 5)")
+            .takes_value(true),
+        Arg::with_name("stratified")
+            .long("stratified")
+            .help("Preserve the class distribution in every fold"),
+    ]
+}
+
+/// Options for prediction.
+fn prediction_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("knearest")
+            .short("k")
+            .long("knearest")
+            .value_name("K")
+            .help("Number of nearest neighbors to consider in voting: (default: 3)")
+            .takes_value(true),
+        Arg::with_name("radius")
+            .long("radius")
+            .value_name("R")
+            .help("Vote among the training instances within distance R rather than the k nearest")
+            .takes_value(true),
+        Arg::with_name("threads")
+            .long("threads")
+            .value_name("N")
+            .help("Number of classification threads (default: number of CPUs)")
+            .takes_value(true),
+    ]
+}
+
+/// Validator for integer options with a minimum value.
+fn at_least(min: usize) -> impl Fn(String) -> Result<(), String> {
+    move |value| match value.parse::<usize>() {
+        Ok(v) if v >= min => Ok(()),
+        _ => Err(format!("should be an integer of at least {}", min)),
+    }
+}
@@ -0,0 +1,357 @@
+use std::io::{self, Write};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use failure::Error;
+use ndarray::prelude::*;
+
+use index::{partition_median, push_within_radius, widest_feature, NearestNeighbors, Neighbor};
+use serialization::{invalid_model, ModelReader, ModelWriter};
+use Metric;
+
+/// Maximum number of instances in a leaf node.
+const LEAF_SIZE: usize = 16;
+
+/// A ball containing the instances `indices[start..end]`.
+struct Node {
+    center: Array1<f32>,
+    radius: f32,
+    start: usize,
+    end: usize,
+    children: Option<(usize, usize)>,
+}
+
+/// A ball tree for exact nearest neighbor search.
+///
+/// Every node of the tree is a ball that contains all the instances of
+/// its subtree. Using the triangle inequality, the distance from a query
+/// point to the center of a ball gives a lower bound on the distance to
+/// the instances in the ball. The metric must therefore be a true metric.
+///
+/// The tree does not own the feature matrix, it stores the indices of
+/// the instances in the feature matrix that the tree was built from.
+pub(crate) struct BallTree {
+    indices: Vec<usize>,
+    nodes: Vec<Node>,
+}
+
+impl BallTree {
+    /// Construct a ball tree for the rows of a feature matrix.
+    pub fn new<M>(features: ArrayView2<f32>, metric: &M) -> Self
+    where
+        M: Metric,
+    {
+        let mut tree = BallTree {
+            indices: (0..features.rows()).collect(),
+            nodes: Vec::new(),
+        };
+
+        if !tree.indices.is_empty() {
+            tree.build(features, metric, 0, features.rows());
+        }
+
+        tree
+    }
+
+    /// Build the subtree for `indices[start..end]`, returning the
+    /// index of its root node.
+    fn build<M>(&mut self, features: ArrayView2<f32>, metric: &M, start: usize, end: usize) -> usize
+    where
+        M: Metric,
+    {
+        // Use the centroid of the instances as the center of the ball.
+        let mut center = Array1::zeros(features.cols());
+        for &idx in &self.indices[start..end] {
+            center += &features.row(idx);
+        }
+        center /= (end - start) as f32;
+
+        let radius = self.indices[start..end]
+            .iter()
+            .map(|&idx| metric.distance(center.view(), features.row(idx)))
+            .fold(0f32, f32::max);
+
+        let (feature, spread) = widest_feature(features, &self.indices[start..end]);
+
+        // Split on the feature with the largest spread, unless the node
+        // is small enough or all its instances are identical.
+        let children = if end - start <= LEAF_SIZE || spread <= 0. {
+            None
+        } else {
+            let mid = start + partition_median(features, &mut self.indices[start..end], feature);
+            Some((
+                self.build(features, metric, start, mid),
+                self.build(features, metric, mid, end),
+            ))
+        };
+
+        self.nodes.push(Node {
+            center,
+            radius,
+            start,
+            end,
+            children,
+        });
+        self.nodes.len() - 1
+    }
+
+    pub fn write<W>(&self, writer: &mut ModelWriter<W>) -> io::Result<()>
+    where
+        W: Write,
+    {
+        writer.write_usizes(&self.indices)?;
+        writer.write_usize(self.nodes.len())?;
+        for node in &self.nodes {
+            writer.write_f32s(&node.center)?;
+            writer.write_f32::<LittleEndian>(node.radius)?;
+            writer.write_usize(node.start)?;
+            writer.write_usize(node.end)?;
+            match node.children {
+                None => writer.write_u8(0)?,
+                Some((left, right)) => {
+                    writer.write_u8(1)?;
+                    writer.write_usize(left)?;
+                    writer.write_usize(right)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Read a ball tree for a feature matrix with the given shape.
+    ///
+    /// Children must precede their parents, which guarantees that the
+    /// tree does not contain cycles.
+    pub fn read(
+        reader: &mut ModelReader,
+        n_instances: usize,
+        n_features: usize,
+    ) -> Result<Self, Error> {
+        let indices = reader.read_indices(n_instances)?;
+        let node_size = n_features
+            .checked_mul(4)
+            .and_then(|center_size| center_size.checked_add(21))
+            .ok_or_else(|| invalid_model("file is truncated"))?;
+        let n_nodes = reader.read_len(node_size)?;
+
+        let mut nodes = Vec::with_capacity(n_nodes);
+        for node_idx in 0..n_nodes {
+            let center = Array1::from_vec(reader.read_f32s(n_features)?);
+            let radius = reader.read_f32()?;
+            let start = reader.read_usize()?;
+            let end = reader.read_usize()?;
+            if start > end || end > indices.len() {
+                return Err(invalid_model("ball tree node out of bounds"));
+            }
+
+            let children = match reader.read_u8()? {
+                0 => None,
+                1 => Some((reader.read_index(node_idx)?, reader.read_index(node_idx)?)),
+                _ => return Err(invalid_model("unknown ball tree node type")),
+            };
+
+            nodes.push(Node {
+                center,
+                radius,
+                start,
+                end,
+                children,
+            });
+        }
+
+        Ok(BallTree { indices, nodes })
+    }
+
+    /// Find the k nearest neighbors of a query point.
+    pub fn k_nearest<M>(
+        &self,
+        features: ArrayView2<f32>,
+        metric: &M,
+        query: ArrayView1<f32>,
+        k: usize,
+    ) -> Vec<Neighbor>
+    where
+        M: Metric,
+    {
+        let mut nearest_neighbors = NearestNeighbors::new(k);
+
+        if let Some(root) = self.nodes.len().checked_sub(1) {
+            let min_distance = self.min_distance(metric, query, root);
+            self.search(
+                features,
+                metric,
+                query,
+                root,
+                min_distance,
+                &mut nearest_neighbors,
+            );
+        }
+
+        nearest_neighbors.into_sorted_vec()
+    }
+
+    /// Find the neighbors of a query point within the given radius,
+    /// sorted from nearest to farthest.
+    pub fn within_radius<M>(
+        &self,
+        features: ArrayView2<f32>,
+        metric: &M,
+        query: ArrayView1<f32>,
+        radius: f32,
+    ) -> Vec<Neighbor>
+    where
+        M: Metric,
+    {
+        let mut neighbors = Vec::new();
+
+        if let Some(root) = self.nodes.len().checked_sub(1) {
+            self.search_radius(features, metric, query, radius, root, &mut neighbors);
+        }
+
+        neighbors.sort_unstable();
+        neighbors
+    }
+
+    fn search_radius<M>(
+        &self,
+        features: ArrayView2<f32>,
+        metric: &M,
+        query: ArrayView1<f32>,
+        radius: f32,
+        node: usize,
+        neighbors: &mut Vec<Neighbor>,
+    ) where
+        M: Metric,
+    {
+        if self.min_distance(metric, query, node) > radius {
+            return;
+        }
+
+        let node = &self.nodes[node];
+        match node.children {
+            None => {
+                for &idx in &self.indices[node.start..node.end] {
+                    let distance = metric.distance(features.row(idx), query);
+                    push_within_radius(neighbors, idx, distance, radius);
+                }
+            }
+            Some((left, right)) => {
+                self.search_radius(features, metric, query, radius, left, neighbors);
+                self.search_radius(features, metric, query, radius, right, neighbors);
+            }
+        }
+    }
+
+    /// Lower bound on the distance from the query to the instances of
+    /// a node, following from the triangle inequality.
+    fn min_distance<M>(&self, metric: &M, query: ArrayView1<f32>, node: usize) -> f32
+    where
+        M: Metric,
+    {
+        let node = &self.nodes[node];
+        (metric.distance(query, node.center.view()) - node.radius).max(0.)
+    }
+
+    fn search<M>(
+        &self,
+        features: ArrayView2<f32>,
+        metric: &M,
+        query: ArrayView1<f32>,
+        node: usize,
+        min_distance: f32,
+        nearest_neighbors: &mut NearestNeighbors,
+    ) where
+        M: Metric,
+    {
+        if !nearest_neighbors.may_contain(min_distance) {
+            return;
+        }
+
+        let node = &self.nodes[node];
+        match node.children {
+            None => {
+                for &idx in &self.indices[node.start..node.end] {
+                    nearest_neighbors.push(idx, metric.distance(features.row(idx), query));
+                }
+            }
+            Some((left, right)) => {
+                // Visit the closest child first, to tighten the bound on the
+                // k-th nearest neighbor distance as quickly as possible.
+                let left_distance = self.min_distance(metric, query, left);
+                let right_distance = self.min_distance(metric, query, right);
+                let (near, near_distance, far, far_distance) = if left_distance <= right_distance {
+                    (left, left_distance, right, right_distance)
+                } else {
+                    (right, right_distance, left, left_distance)
+                };
+
+                self.search(
+                    features,
+                    metric,
+                    query,
+                    near,
+                    near_distance,
+                    nearest_neighbors,
+                );
+                self.search(
+                    features,
+                    metric,
+                    query,
+                    far,
+                    far_distance,
+                    nearest_neighbors,
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use balltree::BallTree;
+    use index::tests::{check_against_brute_force, feature_matrix, random_matrix, read_instances};
+    use index::IndexType;
+    use serialization::{ModelReader, ModelWriter};
+    use {Chebyshev, Euclidean, Manhattan, Minkowski};
+
+    #[test]
+    fn ball_tree_moons_test() {
+        let train = feature_matrix(&read_instances(include_str!("../moons-train.txt")));
+        let test = feature_matrix(&read_instances(include_str!("../moons-test.txt")));
+        check_against_brute_force(IndexType::BallTree, &Euclidean, &train, &test);
+    }
+
+    #[test]
+    fn ball_tree_high_dimensional_test() {
+        let train = random_matrix(500, 64, 42);
+        let test = random_matrix(50, 64, 7);
+        check_against_brute_force(IndexType::BallTree, &Euclidean, &train, &test);
+    }
+
+    #[test]
+    fn ball_tree_metrics_test() {
+        let train = random_matrix(500, 16, 42);
+        let test = random_matrix(50, 16, 7);
+        check_against_brute_force(IndexType::BallTree, &Manhattan, &train, &test);
+        check_against_brute_force(IndexType::BallTree, &Chebyshev, &train, &test);
+        check_against_brute_force(IndexType::BallTree, &Minkowski::new(1.5), &train, &test);
+    }
+
+    #[test]
+    fn read_overflow_test() {
+        let mut data = Vec::new();
+        {
+            let mut writer = ModelWriter::new(&mut data).unwrap();
+            writer.write_usizes(&[]).unwrap();
+            writer.write_usize(1).unwrap();
+            writer.flush().unwrap();
+            writer.finish().unwrap();
+        }
+
+        // The size of a node with this many features overflows.
+        let mut reader = ModelReader::new(&data).unwrap();
+        assert!(BallTree::read(&mut reader, 0, usize::MAX / 2).is_err());
+    }
+}
@@ -0,0 +1,193 @@
+use ndarray::linalg::general_mat_mul;
+use ndarray::prelude::*;
+
+use index::{NearestNeighbors, Neighbor};
+use {Euclidean, Metric};
+
+/// Number of training instances per block of the distance computation.
+const TRAIN_BLOCK_SIZE: usize = 1024;
+
+/// Compute the squared L2 norm of every row of a matrix.
+pub(crate) fn squared_row_norms(features: ArrayView2<f32>) -> Array1<f32> {
+    features
+        .outer_iter()
+        .map(|row| row.iter().map(|&v| v * v).sum())
+        .collect()
+}
+
+/// Find the k nearest neighbors of a batch of queries by brute force
+/// Euclidean search.
+///
+/// Squared distances are computed as ||x||² - 2 x·q + ||q||², so that the
+/// bulk of the work is done by matrix-matrix products over blocks of
+/// training instances. This avoids materializing the differences between
+/// the query and every training instance.
+///
+/// The expansion suffers from cancellation, so its distances are only used
+/// to select candidates. All instances that could be among the k nearest
+/// neighbors given the floating point error of the expansion are re-ranked
+/// with the exact distance. The result is therefore identical to regular
+/// brute force search.
+pub(crate) fn euclidean_k_nearest_batch(
+    features: ArrayView2<f32>,
+    squared_norms: ArrayView1<f32>,
+    queries: ArrayView2<f32>,
+    k: usize,
+) -> Vec<Vec<Neighbor>> {
+    assert_eq!(features.cols(), queries.cols());
+
+    let max_squared_norm = squared_norms.iter().cloned().fold(0., f32::max);
+    let query_squared_norms = squared_row_norms(queries);
+
+    let mut candidates: Vec<_> = query_squared_norms
+        .iter()
+        .map(|&query_norm| {
+            Candidates::new(
+                k,
+                error_bound(features.cols(), max_squared_norm + query_norm),
+            )
+        })
+        .collect();
+
+    let block_size = TRAIN_BLOCK_SIZE.min(features.rows()).max(1);
+    let mut dots = Array2::zeros((block_size, queries.rows()));
+    for (block_idx, block) in features.axis_chunks_iter(Axis(0), block_size).enumerate() {
+        let offset = block_idx * block_size;
+        let mut dots = dots.slice_mut(s![..block.rows(), ..]);
+
+        // -2 x·q for every instance-query pair in the block.
+        general_mat_mul(-2., &block, &queries.t(), 0., &mut dots);
+
+        for (row_idx, row) in dots.outer_iter().enumerate() {
+            let idx = offset + row_idx;
+            let instance_norm = squared_norms[idx];
+            for ((query_candidates, &query_norm), &dot) in candidates
+                .iter_mut()
+                .zip(query_squared_norms.iter())
+                .zip(row.iter())
+            {
+                // Cancellation can result in small negative values.
+                let squared_distance = (instance_norm + dot + query_norm).max(0.);
+                query_candidates.push(idx, squared_distance);
+            }
+        }
+    }
+
+    candidates
+        .into_iter()
+        .zip(queries.outer_iter())
+        .map(|(query_candidates, query)| query_candidates.rerank(features, query))
+        .collect()
+}
+
+/// Bound on the absolute error of squared distances that are computed
+/// using the expansion, relative to the exact distance.
+///
+/// `squared_norms` is an upper bound on ||x||² + ||q||².
+fn error_bound(n_features: usize, squared_norms: f32) -> f32 {
+    4. * (n_features + 2) as f32 * f32::EPSILON * squared_norms
+}
+
+/// Candidate neighbors of a query.
+struct Candidates {
+    nearest: NearestNeighbors,
+    candidates: Vec<(usize, f32)>,
+    prune_len: usize,
+    tolerance: f32,
+}
+
+impl Candidates {
+    fn new(k: usize, error_bound: f32) -> Self {
+        Candidates {
+            nearest: NearestNeighbors::new(k),
+            candidates: Vec::new(),
+            prune_len: 4 * k,
+
+            // Both the k-th nearest distance and the distance of a
+            // candidate can be off by the error bound.
+            tolerance: 2. * error_bound,
+        }
+    }
+
+    /// Maximum squared distance of a candidate that could be among the
+    /// k nearest neighbors.
+    fn threshold(&self) -> f32 {
+        self.nearest
+            .max_distance()
+            .map_or(f32::INFINITY, |max| max + self.tolerance)
+    }
+
+    fn push(&mut self, idx: usize, squared_distance: f32) {
+        self.nearest.push(idx, squared_distance);
+
+        let threshold = self.threshold();
+        if squared_distance <= threshold {
+            self.candidates.push((idx, squared_distance));
+
+            // The threshold can only decrease, so prune candidates that
+            // can no longer be among the nearest neighbors once in a while.
+            if self.candidates.len() > self.prune_len {
+                self.candidates.retain(|&(_, d)| d <= threshold);
+                self.prune_len = self.prune_len.max(2 * self.candidates.len());
+            }
+        }
+    }
+
+    /// Select the k nearest neighbors using exact distances.
+    fn rerank(self, features: ArrayView2<f32>, query: ArrayView1<f32>) -> Vec<Neighbor> {
+        let threshold = self.threshold();
+
+        let mut nearest_neighbors = NearestNeighbors::new(self.nearest.k());
+        for (idx, squared_distance) in self.candidates {
+            if squared_distance <= threshold {
+                nearest_neighbors.push(idx, Euclidean.distance(features.row(idx), query));
+            }
+        }
+
+        nearest_neighbors.into_sorted_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::prelude::*;
+
+    use super::{euclidean_k_nearest_batch, squared_row_norms};
+    use index::tests::random_matrix;
+    use index::{brute_force_k_nearest, Neighbor};
+    use Euclidean;
+
+    fn check_against_brute_force(train: ArrayView2<f32>, test: ArrayView2<f32>, k: usize) {
+        let norms = squared_row_norms(train);
+        let batch = euclidean_k_nearest_batch(train, norms.view(), test, k);
+
+        let brute_force: Vec<Vec<Neighbor>> = test
+            .outer_iter()
+            .map(|query| brute_force_k_nearest(train, &Euclidean, query, k))
+            .collect();
+
+        assert_eq!(batch, brute_force);
+    }
+
+    #[test]
+    fn batch_test() {
+        let train = random_matrix(3000, 16, 11);
+        let test = random_matrix(50, 16, 12);
+        check_against_brute_force(train.view(), test.view(), 5);
+    }
+
+    #[test]
+    fn batch_cancellation_test() {
+        // Instances far from the origin and close to each other.
+        let train = random_matrix(500, 8, 11).mapv(|v| 1000. + v * 1e-2);
+        let test = random_matrix(20, 8, 12).mapv(|v| 1000. + v * 1e-2);
+        check_against_brute_force(train.view(), test.view(), 3);
+    }
+
+    #[test]
+    fn batch_duplicates_test() {
+        let train = Array2::from_elem((40, 2), 1.);
+        let test = arr2(&[[1., 1.], [0., 0.]]);
+        check_against_brute_force(train.view(), test.view(), 4);
+    }
+}
@@ -0,0 +1,171 @@
+use std::collections::BTreeMap;
+
+use rand::Rng;
+
+use index::seeded_rng;
+
+/// Split data points into folds for k-fold cross-validation.
+///
+/// The data points are shuffled using the given seed and divided over
+/// `n_folds` folds, whose sizes differ by at most one. Every fold contains
+/// the indices of its data points in increasing order.
+pub fn k_fold(n_instances: usize, n_folds: usize, seed: u64) -> Vec<Vec<usize>> {
+    check_n_folds(n_instances, n_folds);
+
+    let mut indices: Vec<_> = (0..n_instances).collect();
+    seeded_rng(seed).shuffle(&mut indices);
+
+    deal(indices, n_folds, 0)
+}
+
+/// Split data points into folds for stratified k-fold cross-validation.
+///
+/// Like `k_fold`, but the data points of every class are divided evenly
+/// over the folds, so that every fold has roughly the same class
+/// distribution as the data.
+pub fn stratified_k_fold(labels: &[usize], n_folds: usize, seed: u64) -> Vec<Vec<usize>> {
+    check_n_folds(labels.len(), n_folds);
+
+    let mut classes = BTreeMap::new();
+    for (idx, &label) in labels.iter().enumerate() {
+        classes.entry(label).or_insert_with(Vec::new).push(idx);
+    }
+
+    let mut rng = seeded_rng(seed);
+    let mut folds = vec![Vec::new(); n_folds];
+
+    // Continue dealing where the previous class stopped, so that the
+    // sizes of the folds differ by at most one.
+    let mut offset = 0;
+    for (_, mut indices) in classes {
+        rng.shuffle(&mut indices);
+        let n_indices = indices.len();
+        for (fold, class_fold) in folds.iter_mut().zip(deal(indices, n_folds, offset)) {
+            fold.extend(class_fold);
+        }
+        offset = (offset + n_indices) % n_folds;
+    }
+
+    for fold in &mut folds {
+        fold.sort_unstable();
+    }
+
+    folds
+}
+
+/// Randomly split data points into training and test data.
+///
+/// The given fraction of the data points, rounded to the nearest integer,
+/// is used as test data. Returns the indices of the training and test data
+/// points in increasing order.
+pub fn train_test_split(
+    n_instances: usize,
+    test_fraction: f32,
+    seed: u64,
+) -> (Vec<usize>, Vec<usize>) {
+    assert!(
+        test_fraction > 0. && test_fraction < 1.,
+        "The test fraction should be in (0, 1), was: {}",
+        test_fraction
+    );
+
+    let mut indices: Vec<_> = (0..n_instances).collect();
+    seeded_rng(seed).shuffle(&mut indices);
+
+    let n_test = (n_instances as f32 * test_fraction).round() as usize;
+    let mut test = indices.split_off(n_instances - n_test);
+    indices.sort_unstable();
+    test.sort_unstable();
+
+    (indices, test)
+}
+
+fn check_n_folds(n_instances: usize, n_folds: usize) {
+    assert!(
+        n_folds >= 2,
+        "At least 2 folds are required, was: {}",
+        n_folds
+    );
+    assert!(
+        n_folds <= n_instances,
+        "Cannot split {} data points into {} folds",
+        n_instances,
+        n_folds
+    );
+}
+
+/// Deal indices over folds round-robin, starting at the given fold.
+fn deal(indices: Vec<usize>, n_folds: usize, offset: usize) -> Vec<Vec<usize>> {
+    let mut folds = vec![Vec::new(); n_folds];
+    for (i, idx) in indices.into_iter().enumerate() {
+        folds[(offset + i) % n_folds].push(idx);
+    }
+
+    for fold in &mut folds {
+        fold.sort_unstable();
+    }
+
+    folds
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{k_fold, stratified_k_fold, train_test_split};
+
+    fn check_partition(folds: &[Vec<usize>], n_instances: usize) {
+        let mut all: Vec<_> = folds.iter().flat_map(|fold| fold.iter().cloned()).collect();
+        all.sort_unstable();
+        assert_eq!(all, (0..n_instances).collect::<Vec<_>>());
+
+        let min = folds.iter().map(Vec::len).min().unwrap();
+        let max = folds.iter().map(Vec::len).max().unwrap();
+        assert!(max - min <= 1);
+    }
+
+    #[test]
+    fn k_fold_test() {
+        let folds = k_fold(23, 5, 42);
+        assert_eq!(folds.len(), 5);
+        check_partition(&folds, 23);
+
+        assert_eq!(k_fold(23, 5, 42), folds);
+        assert_ne!(k_fold(23, 5, 43), folds);
+    }
+
+    #[test]
+    fn stratified_k_fold_test() {
+        // 15 instances of class 0, 6 of class 1, and 2 of class 2.
+        let labels: Vec<_> = (0..23)
+            .map(|i| match i {
+                0..=14 => 0,
+                15..=20 => 1,
+                _ => 2,
+            })
+            .collect();
+        let folds = stratified_k_fold(&labels, 3, 42);
+        check_partition(&folds, 23);
+
+        for fold in &folds {
+            let count = |class| fold.iter().filter(|&&idx| labels[idx] == class).count();
+            assert_eq!(count(0), 5);
+            assert_eq!(count(1), 2);
+        }
+    }
+
+    #[test]
+    fn train_test_split_test() {
+        let (train, test) = train_test_split(23, 0.2, 42);
+        assert_eq!(test.len(), 5);
+
+        let mut all = train;
+        all.extend(test);
+        all.sort_unstable();
+        assert_eq!(all, (0..23).collect::<Vec<_>>());
+    }
+
+    #[test]
+    #[should_panic]
+    fn too_many_folds_test() {
+        k_fold(3, 4, 42);
+    }
+}
@@ -0,0 +1,282 @@
+use std::io::Read;
+
+use csv::{Reader, ReaderBuilder, StringRecord, StringRecordsIntoIter, Trim};
+use failure::{err_msg, Error};
+
+use Instance;
+
+/// A column of a CSV file.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Column {
+    /// The column with the given (zero-based) index.
+    Index(usize),
+
+    /// The column with the given name in the header.
+    Name(String),
+}
+
+/// Convert a string to a column: numbers are column indices and other
+/// strings column names.
+impl<'a> From<&'a str> for Column {
+    fn from(column: &'a str) -> Self {
+        match column.parse() {
+            Ok(idx) => Column::Index(idx),
+            Err(_) => Column::Name(column.to_owned()),
+        }
+    }
+}
+
+/// Options for reading data points from CSV files.
+#[derive(Clone, Debug)]
+pub struct CsvOptions {
+    /// The field delimiter.
+    pub delimiter: u8,
+
+    /// Whether the first row is a header with column names.
+    pub has_header: bool,
+
+    /// The column with the class labels or targets.
+    pub label_column: Column,
+
+    /// The columns with the features, in the order in which they are used.
+    ///
+    /// When absent, all columns except for the label column are used.
+    pub feature_columns: Option<Vec<Column>>,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        CsvOptions {
+            delimiter: b',',
+            has_header: true,
+            label_column: Column::Index(0),
+            feature_columns: None,
+        }
+    }
+}
+
+/// An iterator over data points in a CSV file.
+///
+/// Labels are returned as strings, so that class names can be mapped to
+/// labels with a `LabelDictionary` and real-valued targets can be parsed.
+/// Errors report the line number.
+pub struct CsvIter<R> {
+    records: StringRecordsIntoIter<R>,
+    header: Option<StringRecord>,
+    label_column: usize,
+    feature_columns: Option<Vec<usize>>,
+}
+
+impl<R> CsvIter<R>
+where
+    R: Read,
+{
+    /// Construct a new iterator over data points.
+    ///
+    /// Fails when a column is selected by name and the file does not have
+    /// a header or the header does not contain the name.
+    pub fn new(read: R, options: &CsvOptions) -> Result<Self, Error> {
+        let mut reader = ReaderBuilder::new()
+            .delimiter(options.delimiter)
+            .has_headers(options.has_header)
+            .trim(Trim::All)
+            .from_reader(read);
+
+        let header = if options.has_header {
+            Some(reader.headers()?.clone())
+        } else {
+            None
+        };
+
+        let label_column = column_index(&options.label_column, header.as_ref())?;
+        let feature_columns = match options.feature_columns {
+            Some(ref columns) => Some(
+                columns
+                    .iter()
+                    .map(|column| column_index(column, header.as_ref()))
+                    .collect::<Result<_, _>>()?,
+            ),
+            None => None,
+        };
+
+        Ok(CsvIter {
+            records: Reader::into_records(reader),
+            header,
+            label_column,
+            feature_columns,
+        })
+    }
+
+    fn process_record(&self, record: &StringRecord) -> Result<Instance<String>, String> {
+        let label = self.field(record, self.label_column)?.to_owned();
+
+        let features = match self.feature_columns {
+            Some(ref columns) => columns
+                .iter()
+                .map(|&idx| self.parse_field(record, idx))
+                .collect::<Result<_, _>>()?,
+            None => (0..record.len())
+                .filter(|&idx| idx != self.label_column)
+                .map(|idx| self.parse_field(record, idx))
+                .collect::<Result<_, _>>()?,
+        };
+
+        Ok(Instance { label, features })
+    }
+
+    fn field<'b>(&self, record: &'b StringRecord, idx: usize) -> Result<&'b str, String> {
+        record.get(idx).ok_or_else(|| {
+            format!(
+                "{} does not exist, the record has {} fields",
+                self.column_name(idx),
+                record.len()
+            )
+        })
+    }
+
+    fn parse_field(&self, record: &StringRecord, idx: usize) -> Result<f32, String> {
+        let field = self.field(record, idx)?;
+        field.parse().map_err(|err| {
+            format!(
+                "invalid value '{}' in {}: {}",
+                field,
+                self.column_name(idx),
+                err
+            )
+        })
+    }
+
+    fn column_name(&self, idx: usize) -> String {
+        match self.header.as_ref().and_then(|header| header.get(idx)) {
+            Some(name) => format!("column '{}'", name),
+            None => format!("column {}", idx),
+        }
+    }
+}
+
+impl<R> Iterator for CsvIter<R>
+where
+    R: Read,
+{
+    type Item = Result<Instance<String>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let record = match self.records.next()? {
+            Ok(record) => record,
+            Err(err) => return Some(Err(err.into())),
+        };
+
+        let line = record.position().map(|pos| pos.line()).unwrap_or(0);
+        Some(
+            self.process_record(&record)
+                .map_err(|err| err_msg(format!("line {}: {}", line, err))),
+        )
+    }
+}
+
+fn column_index(column: &Column, header: Option<&StringRecord>) -> Result<usize, Error> {
+    match *column {
+        Column::Index(idx) => Ok(idx),
+        Column::Name(ref name) => {
+            let header = header.ok_or_else(|| {
+                err_msg(format!(
+                    "Cannot select column '{}' by name in a file without a header",
+                    name
+                ))
+            })?;
+            header
+                .iter()
+                .position(|field| field == name)
+                .ok_or_else(|| err_msg(format!("Unknown column: {}", name)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::{Column, CsvIter, CsvOptions};
+    use Instance;
+
+    static DATA: &str = "length, width, class\n1.5, 2, spam\n-1, 0.5, ham\n";
+
+    fn instance(label: &str, features: &[f32]) -> Instance<String> {
+        Instance {
+            label: label.to_owned(),
+            features: features.to_owned(),
+        }
+    }
+
+    #[test]
+    fn csv_iter_test() {
+        let options = CsvOptions {
+            label_column: Column::Name("class".to_owned()),
+            ..CsvOptions::default()
+        };
+        let instances = CsvIter::new(Cursor::new(DATA), &options)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            instances,
+            vec![instance("spam", &[1.5, 2.]), instance("ham", &[-1., 0.5])]
+        );
+
+        // Select and reorder columns, without a header.
+        let options = CsvOptions {
+            delimiter: b';',
+            has_header: false,
+            label_column: Column::Index(2),
+            feature_columns: Some(vec![Column::Index(1), Column::Index(0)]),
+        };
+        let instances = CsvIter::new(Cursor::new("1;2;spam\n3;4;ham"), &options)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            instances,
+            vec![instance("spam", &[2., 1.]), instance("ham", &[4., 3.])]
+        );
+    }
+
+    #[test]
+    fn csv_invalid_test() {
+        // The class column is used as a feature.
+        let options = CsvOptions::default();
+        let err = CsvIter::new(Cursor::new(DATA), &options)
+            .unwrap()
+            .find_map(|inst| inst.err())
+            .unwrap();
+        assert!(err
+            .to_string()
+            .starts_with("line 2: invalid value 'spam' in column 'class'"));
+
+        let options = CsvOptions {
+            label_column: Column::Name("color".to_owned()),
+            ..CsvOptions::default()
+        };
+        assert!(CsvIter::new(Cursor::new(DATA), &options).is_err());
+
+        let options = CsvOptions {
+            has_header: false,
+            label_column: Column::Name("class".to_owned()),
+            ..CsvOptions::default()
+        };
+        assert!(CsvIter::new(Cursor::new(DATA), &options).is_err());
+
+        let options = CsvOptions {
+            label_column: Column::Index(5),
+            ..CsvOptions::default()
+        };
+        let err = CsvIter::new(Cursor::new(DATA), &options)
+            .unwrap()
+            .next()
+            .unwrap()
+            .err()
+            .unwrap();
+        assert!(err
+            .to_string()
+            .starts_with("line 2: column 5 does not exist"));
+    }
+}
@@ -1,16 +1,346 @@
+use std::fmt;
+use std::io::Write;
 use std::iter::Sum;
+use std::sync::Arc;
 
+use byteorder::{LittleEndian, WriteBytesExt};
+use failure::{err_msg, Error};
 use ndarray::prelude::*;
 use ndarray::Data;
 use num_traits::Float;
 use ordered_float::OrderedFloat;
 
+use serialization::{invalid_model, ModelReader, ModelWriter};
+use Mahalanobis;
+
+/// Distance metrics between feature vectors.
+///
+/// User-defined metrics can be used by implementing this trait and
+/// wrapping the metric with `Metrics::custom`.
+pub trait Metric {
+    /// Compute the distance between two feature vectors.
+    fn distance(&self, v1: ArrayView1<f32>, v2: ArrayView1<f32>) -> f32;
+
+    /// Check whether the distance is a true metric: it is non-negative,
+    /// symmetric, zero only for identical vectors, and satisfies the
+    /// triangle inequality.
+    ///
+    /// Search structures that prune using the triangle inequality, such
+    /// as the ball tree, require a true metric. The default implementation
+    /// conservatively returns `false`.
+    fn is_true_metric(&self) -> bool {
+        false
+    }
+
+    /// Get a lower bound on the distance between two vectors that differ
+    /// by `diff` in one coordinate.
+    ///
+    /// The KD-tree uses this bound to prune subtrees, so it can only be
+    /// used with metrics that provide a bound. The default implementation
+    /// returns `None`.
+    fn coordinate_lower_bound(&self, _diff: f32) -> Option<f32> {
+        None
+    }
+}
+
+/// Euclidean distance metric.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Euclidean;
+
+impl Metric for Euclidean {
+    fn distance(&self, v1: ArrayView1<f32>, v2: ArrayView1<f32>) -> f32 {
+        v1.euclidean_distance(&v2)
+    }
+
+    fn is_true_metric(&self) -> bool {
+        true
+    }
+
+    fn coordinate_lower_bound(&self, diff: f32) -> Option<f32> {
+        Some(diff.abs())
+    }
+}
+
+/// Squared Euclidean distance.
+///
+/// The squared Euclidean distance gives the same nearest neighbors as the
+/// Euclidean distance, but it is not a true metric, since it does not
+/// satisfy the triangle inequality.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SquaredEuclidean;
+
+impl Metric for SquaredEuclidean {
+    fn distance(&self, v1: ArrayView1<f32>, v2: ArrayView1<f32>) -> f32 {
+        v1.iter()
+            .zip(v2.iter())
+            .map(|(&a, &b)| (a - b) * (a - b))
+            .sum()
+    }
+
+    fn coordinate_lower_bound(&self, diff: f32) -> Option<f32> {
+        Some(diff * diff)
+    }
+}
+
+/// Manhattan (L1) distance metric.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Manhattan;
+
+impl Metric for Manhattan {
+    fn distance(&self, v1: ArrayView1<f32>, v2: ArrayView1<f32>) -> f32 {
+        v1.iter().zip(v2.iter()).map(|(&a, &b)| (a - b).abs()).sum()
+    }
+
+    fn is_true_metric(&self) -> bool {
+        true
+    }
+
+    fn coordinate_lower_bound(&self, diff: f32) -> Option<f32> {
+        Some(diff.abs())
+    }
+}
+
+/// Chebyshev (L-infinity) distance metric.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Chebyshev;
+
+impl Metric for Chebyshev {
+    fn distance(&self, v1: ArrayView1<f32>, v2: ArrayView1<f32>) -> f32 {
+        v1.iter()
+            .zip(v2.iter())
+            .map(|(&a, &b)| (a - b).abs())
+            .fold(0., f32::max)
+    }
+
+    fn is_true_metric(&self) -> bool {
+        true
+    }
+
+    fn coordinate_lower_bound(&self, diff: f32) -> Option<f32> {
+        Some(diff.abs())
+    }
+}
+
+/// Minkowski distance of order p.
+///
+/// The Minkowski distance is a true metric for p >= 1. With p = 1 it is
+/// the Manhattan distance and with p = 2 the Euclidean distance.
+#[derive(Clone, Copy, Debug)]
+pub struct Minkowski {
+    p: f32,
+}
+
+impl Minkowski {
+    /// Construct the Minkowski distance of order `p`, which must be
+    /// positive.
+    pub fn new(p: f32) -> Self {
+        assert!(p > 0., "p should be positive, was: {}", p);
+        Minkowski { p }
+    }
+
+    /// Get the order of the distance.
+    pub fn p(&self) -> f32 {
+        self.p
+    }
+}
+
+impl Metric for Minkowski {
+    fn distance(&self, v1: ArrayView1<f32>, v2: ArrayView1<f32>) -> f32 {
+        v1.iter()
+            .zip(v2.iter())
+            .map(|(&a, &b)| (a - b).abs().powf(self.p))
+            .sum::<f32>()
+            .powf(1. / self.p)
+    }
+
+    fn is_true_metric(&self) -> bool {
+        self.p >= 1.
+    }
+
+    fn coordinate_lower_bound(&self, diff: f32) -> Option<f32> {
+        Some(diff.abs())
+    }
+}
+
+/// Cosine distance, one minus the cosine similarity.
+///
+/// The cosine distance is not a true metric, since it does not satisfy
+/// the triangle inequality. The distance between a zero vector and any
+/// other vector is 1.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Cosine;
+
+impl Metric for Cosine {
+    fn distance(&self, v1: ArrayView1<f32>, v2: ArrayView1<f32>) -> f32 {
+        let norms = v1.norm(Norms::L2) * v2.norm(Norms::L2);
+        if norms == 0. {
+            1.
+        } else {
+            1. - v1.dot(&v2) / norms
+        }
+    }
+}
+
+/// The distance metrics that can be used by KNN models.
+#[derive(Clone, Default)]
+pub enum Metrics {
+    #[default]
+    Euclidean,
+    SquaredEuclidean,
+    Manhattan,
+    Chebyshev,
+    Minkowski(Minkowski),
+
+    /// The Mahalanobis distance, fitted on training data.
+    Mahalanobis(Mahalanobis),
+    Cosine,
+
+    /// A user-defined metric.
+    Custom(Arc<dyn Metric + Send + Sync>),
+}
+
+impl Metrics {
+    /// Use a user-defined metric.
+    pub fn custom<M>(metric: M) -> Self
+    where
+        M: Metric + Send + Sync + 'static,
+    {
+        Metrics::Custom(Arc::new(metric))
+    }
+
+    /// Check whether the metric can be computed directly on sparse
+    /// vectors.
+    ///
+    /// All metrics support sparse vectors, except for the Mahalanobis
+    /// distance and custom metrics.
+    pub fn supports_sparse(&self) -> bool {
+        !matches!(*self, Metrics::Mahalanobis(_) | Metrics::Custom(_))
+    }
+
+    pub(crate) fn write<W>(&self, writer: &mut ModelWriter<W>) -> Result<(), Error>
+    where
+        W: Write,
+    {
+        match *self {
+            Metrics::Euclidean => writer.write_u8(0)?,
+            Metrics::SquaredEuclidean => writer.write_u8(1)?,
+            Metrics::Manhattan => writer.write_u8(2)?,
+            Metrics::Chebyshev => writer.write_u8(3)?,
+            Metrics::Minkowski(minkowski) => {
+                writer.write_u8(4)?;
+                writer.write_f32::<LittleEndian>(minkowski.p())?;
+            }
+            Metrics::Cosine => writer.write_u8(5)?,
+            Metrics::Mahalanobis(ref mahalanobis) => {
+                writer.write_u8(6)?;
+                writer.write_f32s(mahalanobis.whitening())?;
+            }
+            Metrics::Custom(_) => return Err(err_msg("Cannot save a model with a custom metric")),
+        }
+
+        Ok(())
+    }
+
+    /// Read a metric for feature vectors of the given length.
+    pub(crate) fn read(reader: &mut ModelReader, n_features: usize) -> Result<Self, Error> {
+        let metric = match reader.read_u8()? {
+            0 => Metrics::Euclidean,
+            1 => Metrics::SquaredEuclidean,
+            2 => Metrics::Manhattan,
+            3 => Metrics::Chebyshev,
+            4 => {
+                let p = reader.read_f32()?;
+                if p.is_nan() || p <= 0. {
+                    return Err(invalid_model("invalid order of the Minkowski distance"));
+                }
+                Metrics::Minkowski(Minkowski::new(p))
+            }
+            5 => Metrics::Cosine,
+            6 => {
+                let n_values = n_features
+                    .checked_mul(n_features)
+                    .ok_or_else(|| invalid_model("file is truncated"))?;
+                let whitening =
+                    Array2::from_shape_vec((n_features, n_features), reader.read_f32s(n_values)?)
+                        .expect("Number of elements does not correspond to the shape");
+                Metrics::Mahalanobis(Mahalanobis::from_whitening(whitening))
+            }
+            _ => return Err(invalid_model("unknown metric")),
+        };
+
+        Ok(metric)
+    }
+
+    fn as_metric(&self) -> &dyn Metric {
+        match *self {
+            Metrics::Euclidean => &Euclidean,
+            Metrics::SquaredEuclidean => &SquaredEuclidean,
+            Metrics::Manhattan => &Manhattan,
+            Metrics::Chebyshev => &Chebyshev,
+            Metrics::Minkowski(ref minkowski) => minkowski,
+            Metrics::Mahalanobis(ref mahalanobis) => mahalanobis,
+            Metrics::Cosine => &Cosine,
+            Metrics::Custom(ref metric) => metric.as_ref(),
+        }
+    }
+}
+
+impl fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Metrics::Euclidean => write!(f, "Euclidean"),
+            Metrics::SquaredEuclidean => write!(f, "SquaredEuclidean"),
+            Metrics::Manhattan => write!(f, "Manhattan"),
+            Metrics::Chebyshev => write!(f, "Chebyshev"),
+            Metrics::Minkowski(minkowski) => write!(f, "Minkowski({})", minkowski.p()),
+            Metrics::Mahalanobis(_) => write!(f, "Mahalanobis"),
+            Metrics::Cosine => write!(f, "Cosine"),
+            Metrics::Custom(_) => write!(f, "Custom"),
+        }
+    }
+}
+
+impl Metric for Metrics {
+    fn distance(&self, v1: ArrayView1<f32>, v2: ArrayView1<f32>) -> f32 {
+        self.as_metric().distance(v1, v2)
+    }
+
+    fn is_true_metric(&self) -> bool {
+        self.as_metric().is_true_metric()
+    }
+
+    fn coordinate_lower_bound(&self, diff: f32) -> Option<f32> {
+        self.as_metric().coordinate_lower_bound(diff)
+    }
+}
+
 pub trait EuclideanDistance<T> {
     type Output;
 
     fn euclidean_distance(&self, other: &T) -> Self::Output;
 }
 
+impl<A, S, S2> EuclideanDistance<ArrayBase<S2, Ix1>> for ArrayBase<S, Ix1>
+where
+    A: Float + Sum,
+    S: Data<Elem = A>,
+    S2: Data<Elem = A>,
+{
+    type Output = A;
+
+    fn euclidean_distance(&self, other: &ArrayBase<S2, Ix1>) -> Self::Output {
+        assert_eq!(self.shape(), other.shape());
+
+        // Computed without allocating a difference vector, in the same
+        // order as the matrix-vector distance to get identical results.
+        self.iter()
+            .zip(other.iter())
+            .map(|(&v1, &v2)| (v1 - v2) * (v1 - v2))
+            .sum::<A>()
+            .sqrt()
+    }
+}
+
 impl<A, S, S2> EuclideanDistance<ArrayBase<S2, Ix1>> for ArrayBase<S, Ix2>
 where
     A: Float + Sum,
@@ -22,8 +352,9 @@ where
     fn euclidean_distance(&self, other: &ArrayBase<S2, Ix1>) -> Self::Output {
         assert_eq!(self.shape()[1], other.shape()[0]);
 
-        let diff = self - other;
-        diff.outer_iter().map(|v| v.norm(Norms::L2)).collect()
+        self.outer_iter()
+            .map(|v| v.euclidean_distance(other))
+            .collect()
     }
 }
 
@@ -54,7 +385,8 @@ where
         match norm {
             L1 => self.iter().map(|v| v.abs()).sum(),
             L2 => self.iter().map(|&v| v * v).sum::<A>().sqrt(),
-            Infinity => self.iter()
+            Infinity => self
+                .iter()
                 .map(|v| v.abs())
                 .max_by(|&v1, &v2| OrderedFloat(v1).cmp(&OrderedFloat(v2)))
                 .unwrap(),
@@ -64,9 +396,12 @@ where
 
 #[cfg(test)]
 mod tests {
-    use ndarray::{arr1, arr2};
+    use ndarray::{arr1, arr2, ArrayView1};
 
-    use super::{EuclideanDistance, Norm, Norms};
+    use super::{
+        Chebyshev, Cosine, Euclidean, EuclideanDistance, Manhattan, Metric, Metrics, Minkowski,
+        Norm, Norms, SquaredEuclidean,
+    };
 
     #[test]
     fn l1_norm_test() {
@@ -87,6 +422,79 @@ mod tests {
         assert_eq!(arr1(&[1.0, -4.0, 3.0, 1.5]).norm(Norms::Infinity), 4.0);
     }
 
+    #[test]
+    fn metrics_test() {
+        let v1 = arr1(&[1.0, 0.0, 2.0, -1.0]);
+        let v2 = arr1(&[-2.0, 3.0, 1.5, 6.0]);
+        let (v1, v2) = (v1.view(), v2.view());
+
+        assert_abs_diff_eq!(Euclidean.distance(v1, v2), 8.20061, epsilon = 1e-5);
+        assert_abs_diff_eq!(SquaredEuclidean.distance(v1, v2), 67.25, epsilon = 1e-6);
+        assert_abs_diff_eq!(Manhattan.distance(v1, v2), 13.5, epsilon = 1e-6);
+        assert_abs_diff_eq!(Chebyshev.distance(v1, v2), 7.0, epsilon = 1e-6);
+        assert_abs_diff_eq!(
+            Minkowski::new(1.).distance(v1, v2),
+            Manhattan.distance(v1, v2),
+            epsilon = 1e-5
+        );
+        assert_abs_diff_eq!(
+            Minkowski::new(2.).distance(v1, v2),
+            Euclidean.distance(v1, v2),
+            epsilon = 1e-5
+        );
+        assert_abs_diff_eq!(
+            Minkowski::new(3.).distance(v1, v2),
+            7.350368,
+            epsilon = 1e-5
+        );
+    }
+
+    #[test]
+    fn metrics_enum_test() {
+        struct Constant;
+
+        impl Metric for Constant {
+            fn distance(&self, _v1: ArrayView1<f32>, _v2: ArrayView1<f32>) -> f32 {
+                42.
+            }
+        }
+
+        let v1 = arr1(&[1.0, 0.0]);
+        let v2 = arr1(&[0.0, 1.0]);
+
+        assert_abs_diff_eq!(
+            Metrics::Manhattan.distance(v1.view(), v2.view()),
+            2.0,
+            epsilon = 1e-6
+        );
+        assert!(Metrics::Manhattan.is_true_metric());
+        assert!(!Metrics::Cosine.is_true_metric());
+        assert!(!Metrics::Minkowski(Minkowski::new(0.5)).is_true_metric());
+        assert_eq!(Metrics::Cosine.coordinate_lower_bound(1.), None);
+
+        let custom = Metrics::custom(Constant);
+        assert_eq!(custom.distance(v1.view(), v2.view()), 42.);
+        assert!(!custom.is_true_metric());
+    }
+
+    #[test]
+    fn cosine_distance_test() {
+        let v1 = arr1(&[1.0, 0.0, 1.0]);
+        let v2 = arr1(&[2.0, 0.0, 2.0]);
+        let v3 = arr1(&[0.0, 3.0, 0.0]);
+        let v4 = arr1(&[-1.0, 0.0, -1.0]);
+        assert_abs_diff_eq!(Cosine.distance(v1.view(), v2.view()), 0.0, epsilon = 1e-6);
+        assert_abs_diff_eq!(Cosine.distance(v1.view(), v3.view()), 1.0, epsilon = 1e-6);
+        assert_abs_diff_eq!(Cosine.distance(v1.view(), v4.view()), 2.0, epsilon = 1e-6);
+        assert_eq!(Cosine.distance(v1.view(), arr1(&[0.0; 3]).view()), 1.0);
+    }
+
+    #[test]
+    fn euclidean_distance_vector_test() {
+        let dist = arr1(&[-2.0, 3.0, 1.5, 6.0]).euclidean_distance(&arr1(&[1.0, 1.0, 1.0, -3.0]));
+        assert_abs_diff_eq!(dist, 9.708244, epsilon = 1e-6);
+    }
+
     #[test]
     fn euclidean_distance_test() {
         let dists = arr2(&[[1.0, 0.0, 2.0, -1.0], [-2.0, 3.0, 1.5, 6.0]])
@@ -1,15 +1,21 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Write};
+
+use ndarray::Array2;
+
+use LabelDictionary;
+
+/// Evaluation of classification predictions.
+///
+/// The evaluator builds a confusion matrix, from which accuracy,
+/// per-class precision, recall and F1, averaged F1 scores, and Cohen's
+/// kappa are computed.
+#[derive(Default)]
 pub struct Evaluator {
     n_instances: usize,
     n_correct: usize,
-}
-
-impl Default for Evaluator {
-    fn default() -> Self {
-        Evaluator {
-            n_instances: 0,
-            n_correct: 0,
-        }
-    }
+    classes: BTreeSet<usize>,
+    confusion: BTreeMap<(usize, usize), usize>,
 }
 
 impl Evaluator {
@@ -19,9 +25,372 @@ impl Evaluator {
         if predicted == correct {
             self.n_correct += 1;
         }
+
+        self.classes.insert(correct);
+        self.classes.insert(predicted);
+        *self.confusion.entry((correct, predicted)).or_insert(0) += 1;
+    }
+
+    pub fn n_instances(&self) -> usize {
+        self.n_instances
     }
 
     pub fn accuracy(&self) -> f32 {
         self.n_correct as f32 / self.n_instances as f32
     }
+
+    /// Get the classes that occur as correct or predicted labels, in
+    /// increasing order.
+    pub fn classes(&self) -> Vec<usize> {
+        self.classes.iter().cloned().collect()
+    }
+
+    /// Get the confusion matrix.
+    ///
+    /// Rows correspond to the correct classes and columns to the predicted
+    /// classes, both in the order of `classes`.
+    pub fn confusion_matrix(&self) -> Array2<usize> {
+        let classes = self.classes();
+        Array2::from_shape_fn((classes.len(), classes.len()), |(i, j)| {
+            self.n_confused(classes[i], classes[j])
+        })
+    }
+
+    /// Get the number of instances of class `correct` that were predicted
+    /// as `predicted`.
+    pub fn n_confused(&self, correct: usize, predicted: usize) -> usize {
+        self.confusion
+            .get(&(correct, predicted))
+            .cloned()
+            .unwrap_or(0)
+    }
+
+    /// Get the number of instances of a class.
+    pub fn support(&self, class: usize) -> usize {
+        self.confusion
+            .iter()
+            .filter(|&(&(correct, _), _)| correct == class)
+            .map(|(_, &count)| count)
+            .sum()
+    }
+
+    /// Get the number of instances that were predicted as a class.
+    fn n_predicted(&self, class: usize) -> usize {
+        self.confusion
+            .iter()
+            .filter(|&(&(_, predicted), _)| predicted == class)
+            .map(|(_, &count)| count)
+            .sum()
+    }
+
+    /// Precision of a class, 0 when the class was never predicted.
+    pub fn precision(&self, class: usize) -> f32 {
+        ratio(self.n_confused(class, class), self.n_predicted(class))
+    }
+
+    /// Recall of a class, 0 when the class does not occur.
+    pub fn recall(&self, class: usize) -> f32 {
+        ratio(self.n_confused(class, class), self.support(class))
+    }
+
+    /// F1 score of a class, the harmonic mean of its precision and recall.
+    pub fn f1(&self, class: usize) -> f32 {
+        f1(self.precision(class), self.recall(class))
+    }
+
+    /// Unweighted mean of the F1 scores of the classes.
+    pub fn macro_f1(&self) -> f32 {
+        let classes = self.classes();
+        classes.iter().map(|&class| self.f1(class)).sum::<f32>() / classes.len() as f32
+    }
+
+    /// F1 score computed from the counts over all classes.
+    ///
+    /// Since every instance has exactly one correct and one predicted
+    /// class, this is equal to the accuracy.
+    pub fn micro_f1(&self) -> f32 {
+        self.accuracy()
+    }
+
+    /// Mean of the F1 scores of the classes, weighted by their support.
+    pub fn weighted_f1(&self) -> f32 {
+        self.classes()
+            .iter()
+            .map(|&class| self.support(class) as f32 * self.f1(class))
+            .sum::<f32>()
+            / self.n_instances as f32
+    }
+
+    /// Cohen's kappa, the agreement between the correct and predicted
+    /// classes corrected for agreement by chance.
+    pub fn kappa(&self) -> f32 {
+        let n = self.n_instances as f64;
+        let observed = self.n_correct as f64 / n;
+        let expected = self
+            .classes()
+            .iter()
+            .map(|&class| self.support(class) as f64 * self.n_predicted(class) as f64)
+            .sum::<f64>()
+            / (n * n);
+
+        if expected == 1. {
+            // All correct and predicted labels are the same class.
+            1.
+        } else {
+            ((observed - expected) / (1. - expected)) as f32
+        }
+    }
+
+    /// Write a table with the per-class metrics, the averaged metrics,
+    /// and the confusion matrix.
+    ///
+    /// Classes are shown by their names when a label dictionary is given.
+    pub fn write_table<W>(&self, mut write: W, names: Option<&LabelDictionary>) -> io::Result<()>
+    where
+        W: Write,
+    {
+        let classes = self.classes();
+        let class_names: Vec<_> = classes
+            .iter()
+            .map(|&class| match names.and_then(|names| names.name(class)) {
+                Some(name) => name.to_owned(),
+                None => class.to_string(),
+            })
+            .collect();
+        let width = class_names
+            .iter()
+            .map(|name| name.chars().count())
+            .chain(Some(12))
+            .max()
+            .unwrap();
+
+        writeln!(
+            write,
+            "{:<width$} {:>9} {:>9} {:>9} {:>9}",
+            "Class",
+            "Precision",
+            "Recall",
+            "F1",
+            "Support",
+            width = width
+        )?;
+        for (&class, name) in classes.iter().zip(&class_names) {
+            writeln!(
+                write,
+                "{:<width$} {:>9.3} {:>9.3} {:>9.3} {:>9}",
+                name,
+                self.precision(class),
+                self.recall(class),
+                self.f1(class),
+                self.support(class),
+                width = width
+            )?;
+        }
+        writeln!(write)?;
+
+        for &(name, value) in &[
+            ("Macro F1", self.macro_f1()),
+            ("Micro F1", self.micro_f1()),
+            ("Weighted F1", self.weighted_f1()),
+            ("Kappa", self.kappa()),
+        ] {
+            writeln!(write, "{:<width$} {:>9.3}", name, value, width = width)?;
+        }
+        writeln!(write)?;
+
+        writeln!(
+            write,
+            "Confusion matrix (rows: correct, columns: predicted)"
+        )?;
+        let cell_width = class_names
+            .iter()
+            .map(|name| name.chars().count())
+            .chain(Some(self.n_instances.to_string().len()))
+            .max()
+            .unwrap();
+        write!(write, "{:<width$}", "", width = width)?;
+        for name in &class_names {
+            write!(write, " {:>width$}", name, width = cell_width)?;
+        }
+        writeln!(write)?;
+        for (row, name) in self.confusion_matrix().outer_iter().zip(&class_names) {
+            write!(write, "{:<width$}", name, width = width)?;
+            for count in row {
+                write!(write, " {:>width$}", count, width = cell_width)?;
+            }
+            writeln!(write)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn ratio(numerator: usize, denominator: usize) -> f32 {
+    if denominator == 0 {
+        0.
+    } else {
+        numerator as f32 / denominator as f32
+    }
+}
+
+fn f1(precision: f32, recall: f32) -> f32 {
+    if precision + recall == 0. {
+        0.
+    } else {
+        2. * precision * recall / (precision + recall)
+    }
+}
+
+/// Evaluation of regression predictions.
+#[derive(Default)]
+pub struct RegressionEvaluator {
+    n_instances: usize,
+    abs_error_sum: f64,
+    squared_error_sum: f64,
+    target_sum: f64,
+    squared_target_sum: f64,
+}
+
+impl RegressionEvaluator {
+    pub fn count(&mut self, target: f32, predicted: f32) {
+        let target = target as f64;
+        let error = predicted as f64 - target;
+
+        self.n_instances += 1;
+        self.abs_error_sum += error.abs();
+        self.squared_error_sum += error * error;
+        self.target_sum += target;
+        self.squared_target_sum += target * target;
+    }
+
+    /// Mean absolute error.
+    pub fn mae(&self) -> f32 {
+        (self.abs_error_sum / self.n_instances as f64) as f32
+    }
+
+    /// Root mean squared error.
+    pub fn rmse(&self) -> f32 {
+        (self.squared_error_sum / self.n_instances as f64).sqrt() as f32
+    }
+
+    /// Coefficient of determination, the proportion of the variance of
+    /// the targets that is explained by the predictions.
+    pub fn r_squared(&self) -> f32 {
+        let n = self.n_instances as f64;
+        let total_sum_of_squares = self.squared_target_sum - self.target_sum * self.target_sum / n;
+        (1. - self.squared_error_sum / total_sum_of_squares) as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::arr2;
+
+    use super::{Evaluator, RegressionEvaluator};
+    use LabelDictionary;
+
+    fn evaluator() -> Evaluator {
+        let mut eval = Evaluator::default();
+        for &(correct, predicted, count) in &[
+            (0, 0, 5),
+            (0, 1, 2),
+            (0, 2, 1),
+            (1, 0, 1),
+            (1, 1, 3),
+            (2, 2, 4),
+            (2, 1, 2),
+        ] {
+            for _ in 0..count {
+                eval.count(correct, predicted);
+            }
+        }
+        eval
+    }
+
+    #[test]
+    fn evaluator_test() {
+        let eval = evaluator();
+
+        assert_eq!(eval.classes(), vec![0, 1, 2]);
+        assert_eq!(
+            eval.confusion_matrix(),
+            arr2(&[[5, 2, 1], [1, 3, 0], [0, 2, 4]])
+        );
+        assert_eq!(eval.support(0), 8);
+        assert_abs_diff_eq!(eval.accuracy(), 12. / 18.);
+
+        assert_abs_diff_eq!(eval.precision(0), 5. / 6.);
+        assert_abs_diff_eq!(eval.recall(0), 5. / 8.);
+        assert_abs_diff_eq!(eval.f1(0), 10. / 14., epsilon = 1e-6);
+        assert_abs_diff_eq!(eval.f1(1), 6. / 11., epsilon = 1e-6);
+        assert_abs_diff_eq!(eval.f1(2), 8. / 11., epsilon = 1e-6);
+
+        assert_abs_diff_eq!(
+            eval.macro_f1(),
+            (10. / 14. + 6. / 11. + 8. / 11.) / 3.,
+            epsilon = 1e-6
+        );
+        assert_abs_diff_eq!(eval.micro_f1(), eval.accuracy());
+        assert_abs_diff_eq!(
+            eval.weighted_f1(),
+            (8. * 10. / 14. + 4. * 6. / 11. + 6. * 8. / 11.) / 18.,
+            epsilon = 1e-6
+        );
+
+        // Chance agreement: (8 * 6 + 4 * 7 + 6 * 5) / 18².
+        let expected = 106. / 324.;
+        assert_abs_diff_eq!(
+            eval.kappa(),
+            (12. / 18. - expected) / (1. - expected),
+            epsilon = 1e-6
+        );
+    }
+
+    #[test]
+    fn unpredicted_class_test() {
+        let mut eval = Evaluator::default();
+        eval.count(0, 0);
+        eval.count(1, 0);
+
+        assert_eq!(eval.precision(1), 0.);
+        assert_eq!(eval.recall(1), 0.);
+        assert_eq!(eval.f1(1), 0.);
+        assert_eq!(eval.kappa(), 0.);
+
+        let mut eval = Evaluator::default();
+        eval.count(1, 1);
+        assert_eq!(eval.kappa(), 1.);
+    }
+
+    #[test]
+    fn table_test() {
+        let mut names = LabelDictionary::default();
+        for name in &["spam", "ham", "eggs"] {
+            names.encode(name);
+        }
+
+        let mut table = Vec::new();
+        evaluator().write_table(&mut table, Some(&names)).unwrap();
+        let table = String::from_utf8(table).unwrap();
+        let lines: Vec<_> = table.lines().collect();
+        assert_eq!(
+            lines[1],
+            "spam             0.833     0.625     0.714         8"
+        );
+        assert_eq!(lines[5], "Macro F1         0.662");
+        assert_eq!(lines[12], "spam            5    2    1");
+    }
+
+    #[test]
+    fn regression_evaluator_test() {
+        let mut eval = RegressionEvaluator::default();
+        for &(target, predicted) in &[(1., 2.), (2., 2.), (3., 1.), (6., 7.)] {
+            eval.count(target, predicted);
+        }
+
+        assert_abs_diff_eq!(eval.mae(), 1.);
+        assert_abs_diff_eq!(eval.rmse(), 1.5f32.sqrt(), epsilon = 1e-6);
+        // Mean target is 3, total sum of squares is 14.
+        assert_abs_diff_eq!(eval.r_squared(), 1. - 6. / 14., epsilon = 1e-6);
+    }
 }
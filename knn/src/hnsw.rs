@@ -0,0 +1,453 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashSet};
+use std::io::{self, Write};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use failure::Error;
+use ndarray::prelude::*;
+use ordered_float::OrderedFloat;
+use rand::Rng;
+
+use index::{seeded_rng, Neighbor};
+use serialization::{invalid_model, ModelReader, ModelWriter};
+use Metric;
+
+/// Hyperparameters of a Hierarchical Navigable Small World graph.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct HnswParams {
+    /// The number of links of an instance in the upper layers (M). In the
+    /// bottom layer, instances have up to 2M links.
+    pub m: usize,
+
+    /// The number of candidates to consider when linking an instance
+    /// during construction.
+    pub ef_construction: usize,
+
+    /// The number of candidates to consider during search. At least k
+    /// candidates are considered when searching the k nearest neighbors.
+    pub ef_search: usize,
+
+    /// The seed for the random layer assignment of instances.
+    pub seed: u64,
+}
+
+impl Default for HnswParams {
+    fn default() -> Self {
+        HnswParams {
+            m: 16,
+            ef_construction: 200,
+            ef_search: 64,
+            seed: 42,
+        }
+    }
+}
+
+/// A Hierarchical Navigable Small World graph for approximate nearest
+/// neighbor search (Malkov & Yashunin, 2016).
+///
+/// Every instance is a vertex in the bottom layer of the graph. The upper
+/// layers contain exponentially fewer instances and are used to quickly
+/// find a good entry point for search in the layer below.
+pub(crate) struct Hnsw {
+    params: HnswParams,
+
+    /// `links[idx][layer]` are the neighbors of instance `idx` in `layer`.
+    links: Vec<Vec<Vec<usize>>>,
+
+    entry_point: Option<usize>,
+}
+
+impl Hnsw {
+    /// Get the parameters that the graph was constructed with.
+    pub fn params(&self) -> HnswParams {
+        self.params
+    }
+
+    /// Construct an HNSW graph for the rows of a feature matrix.
+    pub fn new<M>(features: ArrayView2<f32>, metric: &M, params: HnswParams) -> Self
+    where
+        M: Metric,
+    {
+        assert!(params.m > 1, "M should at least be 2");
+        assert!(
+            params.ef_construction > 0,
+            "ef_construction should at least be 1"
+        );
+
+        let mut hnsw = Hnsw {
+            params,
+            links: Vec::with_capacity(features.rows()),
+            entry_point: None,
+        };
+
+        let mut rng = seeded_rng(params.seed);
+        let level_mult = 1. / (params.m as f64).ln();
+        for idx in 0..features.rows() {
+            // Sample the layer from an exponentially decaying distribution.
+            let level = (-(1. - rng.gen::<f64>()).ln() * level_mult) as usize;
+            hnsw.insert(features, metric, idx, level);
+        }
+
+        hnsw
+    }
+
+    fn insert<M>(&mut self, features: ArrayView2<f32>, metric: &M, idx: usize, level: usize)
+    where
+        M: Metric,
+    {
+        self.links.push(vec![Vec::new(); level + 1]);
+
+        let entry_point = match self.entry_point {
+            Some(entry_point) => entry_point,
+            None => {
+                self.entry_point = Some(idx);
+                return;
+            }
+        };
+
+        let query = features.row(idx);
+        let top_layer = self.links[entry_point].len() - 1;
+
+        // Find the closest instance in the layers above the level of the
+        // new instance.
+        let mut entry_points = vec![neighbor(features, metric, query, entry_point)];
+        for layer in (level + 1..=top_layer).rev() {
+            entry_points = self.search_layer(features, metric, query, &entry_points, 1, layer);
+        }
+
+        // Link the instance in its own layers.
+        for layer in (0..=level.min(top_layer)).rev() {
+            let candidates = self.search_layer(
+                features,
+                metric,
+                query,
+                &entry_points,
+                self.params.ef_construction,
+                layer,
+            );
+
+            let neighbors = select_neighbors(features, metric, &candidates, self.params.m);
+            for &neighbor_idx in &neighbors {
+                self.link(features, metric, neighbor_idx, idx, layer);
+            }
+            self.links[idx][layer] = neighbors;
+
+            entry_points = candidates;
+        }
+
+        if level > top_layer {
+            self.entry_point = Some(idx);
+        }
+    }
+
+    /// Add a link from `from` to `to`, pruning the links of `from` when
+    /// it has too many links.
+    fn link<M>(
+        &mut self,
+        features: ArrayView2<f32>,
+        metric: &M,
+        from: usize,
+        to: usize,
+        layer: usize,
+    ) where
+        M: Metric,
+    {
+        let max_links = if layer == 0 {
+            2 * self.params.m
+        } else {
+            self.params.m
+        };
+
+        let links = &mut self.links[from][layer];
+        links.push(to);
+
+        if links.len() > max_links {
+            let base = features.row(from);
+            let mut candidates: Vec<_> = links
+                .iter()
+                .map(|&idx| neighbor(features, metric, base, idx))
+                .collect();
+            candidates.sort();
+            *links = select_neighbors(features, metric, &candidates, max_links);
+        }
+    }
+
+    /// Search the `ef` nearest neighbors of the query in a layer, starting
+    /// from the given entry points. The neighbors are sorted by distance.
+    fn search_layer<M>(
+        &self,
+        features: ArrayView2<f32>,
+        metric: &M,
+        query: ArrayView1<f32>,
+        entry_points: &[Neighbor],
+        ef: usize,
+        layer: usize,
+    ) -> Vec<Neighbor>
+    where
+        M: Metric,
+    {
+        let mut visited: HashSet<usize> = entry_points.iter().map(|n| n.idx).collect();
+        let mut candidates: BinaryHeap<_> = entry_points.iter().cloned().map(Reverse).collect();
+        let mut results: BinaryHeap<_> = entry_points.iter().cloned().collect();
+        while results.len() > ef {
+            results.pop();
+        }
+
+        while let Some(Reverse(candidate)) = candidates.pop() {
+            // Stop when the closest candidate is farther away than all
+            // the current results.
+            if results.len() >= ef && results.peek().is_some_and(|&worst| candidate > worst) {
+                break;
+            }
+
+            for &idx in &self.links[candidate.idx][layer] {
+                if !visited.insert(idx) {
+                    continue;
+                }
+
+                let neighbor = neighbor(features, metric, query, idx);
+                if results.len() < ef || results.peek().is_some_and(|&worst| neighbor < worst) {
+                    candidates.push(Reverse(neighbor));
+                    results.push(neighbor);
+                    if results.len() > ef {
+                        results.pop();
+                    }
+                }
+            }
+        }
+
+        results.into_sorted_vec()
+    }
+
+    pub fn write<W>(&self, writer: &mut ModelWriter<W>) -> io::Result<()>
+    where
+        W: Write,
+    {
+        writer.write_usize(self.params.m)?;
+        writer.write_usize(self.params.ef_construction)?;
+        writer.write_usize(self.params.ef_search)?;
+        writer.write_u64::<LittleEndian>(self.params.seed)?;
+
+        // The entry point is present iff there are instances.
+        writer.write_usize(self.entry_point.unwrap_or(0))?;
+
+        writer.write_usize(self.links.len())?;
+        for layers in &self.links {
+            writer.write_usize(layers.len())?;
+            for links in layers {
+                writer.write_usizes(links)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Read an HNSW graph for a feature matrix with the given number of
+    /// instances.
+    pub fn read(reader: &mut ModelReader, n_instances: usize) -> Result<Self, Error> {
+        let params = HnswParams {
+            m: reader.read_usize()?,
+            ef_construction: reader.read_usize()?,
+            ef_search: reader.read_usize()?,
+            seed: reader.read_u64()?,
+        };
+
+        let entry_point = reader.read_usize()?;
+
+        if reader.read_usize()? != n_instances {
+            return Err(invalid_model(
+                "HNSW graph has an incorrect number of instances",
+            ));
+        }
+
+        let mut links = Vec::with_capacity(n_instances);
+        for _ in 0..n_instances {
+            let n_layers = reader.read_len(8)?;
+            if n_layers == 0 {
+                return Err(invalid_model("HNSW instance without layers"));
+            }
+            let layers = (0..n_layers)
+                .map(|_| reader.read_indices(n_instances))
+                .collect::<Result<Vec<_>, _>>()?;
+            links.push(layers);
+        }
+
+        // Search follows links within a layer, so linked instances must
+        // be in that layer.
+        for layers in &links {
+            for (layer, layer_links) in layers.iter().enumerate() {
+                if layer_links.iter().any(|&idx| links[idx].len() <= layer) {
+                    return Err(invalid_model("HNSW link to an instance outside its layer"));
+                }
+            }
+        }
+
+        let entry_point = if n_instances == 0 {
+            None
+        } else if entry_point < n_instances {
+            Some(entry_point)
+        } else {
+            return Err(invalid_model("HNSW entry point out of bounds"));
+        };
+
+        Ok(Hnsw {
+            params,
+            links,
+            entry_point,
+        })
+    }
+
+    /// Find the approximate k nearest neighbors of a query point.
+    pub fn k_nearest<M>(
+        &self,
+        features: ArrayView2<f32>,
+        metric: &M,
+        query: ArrayView1<f32>,
+        k: usize,
+    ) -> Vec<Neighbor>
+    where
+        M: Metric,
+    {
+        assert!(k > 0, "k should at least be 1");
+
+        let entry_point = match self.entry_point {
+            Some(entry_point) => entry_point,
+            None => return Vec::new(),
+        };
+
+        let mut entry_points = vec![neighbor(features, metric, query, entry_point)];
+        for layer in (1..self.links[entry_point].len()).rev() {
+            entry_points = self.search_layer(features, metric, query, &entry_points, 1, layer);
+        }
+
+        let ef = self.params.ef_search.max(k);
+        let mut nearest_neighbors =
+            self.search_layer(features, metric, query, &entry_points, ef, 0);
+        nearest_neighbors.truncate(k);
+        nearest_neighbors
+    }
+}
+
+fn neighbor<M>(
+    features: ArrayView2<f32>,
+    metric: &M,
+    query: ArrayView1<f32>,
+    idx: usize,
+) -> Neighbor
+where
+    M: Metric,
+{
+    Neighbor {
+        distance: OrderedFloat(metric.distance(features.row(idx), query)),
+        idx,
+    }
+}
+
+/// Select at most `m` neighbors from candidates that are sorted by their
+/// distance to the base instance.
+///
+/// A candidate is preferred when it is closer to the base instance than
+/// to any of the neighbors that were already selected. This spreads the
+/// links over different directions. Remaining slots are filled with the
+/// closest candidates that were passed over.
+fn select_neighbors<M>(
+    features: ArrayView2<f32>,
+    metric: &M,
+    candidates: &[Neighbor],
+    m: usize,
+) -> Vec<usize>
+where
+    M: Metric,
+{
+    let mut selected: Vec<usize> = Vec::with_capacity(m);
+    let mut discarded = Vec::new();
+
+    for candidate in candidates {
+        if selected.len() == m {
+            break;
+        }
+
+        let row = features.row(candidate.idx);
+        if selected
+            .iter()
+            .all(|&idx| metric.distance(row, features.row(idx)) > candidate.distance.into_inner())
+        {
+            selected.push(candidate.idx);
+        } else {
+            discarded.push(candidate.idx);
+        }
+    }
+
+    let n_remaining = m - selected.len();
+    selected.extend(discarded.into_iter().take(n_remaining));
+
+    selected
+}
+
+#[cfg(test)]
+mod tests {
+    use index::tests::{feature_matrix, random_matrix, read_instances};
+    use index::{Index, IndexType};
+    use {Euclidean, HnswParams};
+
+    fn recall(index_type: IndexType, train_seed: u64, dims: usize) -> f32 {
+        let train = random_matrix(1000, dims, train_seed);
+        let test = random_matrix(100, dims, train_seed + 1);
+
+        let brute_force = Index::new(IndexType::BruteForce, train.view(), &Euclidean);
+        let hnsw = Index::new(index_type, train.view(), &Euclidean);
+
+        let mut found = 0;
+        for query in test.outer_iter() {
+            let exact = brute_force.k_nearest(train.view(), &Euclidean, query, 10);
+            let approx = hnsw.k_nearest(train.view(), &Euclidean, query, 10);
+            assert_eq!(approx.len(), 10);
+            found += approx.iter().filter(|n| exact.contains(n)).count();
+        }
+
+        found as f32 / (test.rows() * 10) as f32
+    }
+
+    #[test]
+    fn hnsw_recall_test() {
+        let params = HnswParams::default();
+        assert!(recall(IndexType::Hnsw(params), 13, 8) > 0.95);
+    }
+
+    #[test]
+    fn hnsw_ef_search_test() {
+        // A larger candidate list should not decrease recall.
+        let narrow = HnswParams {
+            m: 4,
+            ef_construction: 16,
+            ef_search: 10,
+            ..HnswParams::default()
+        };
+        let wide = HnswParams {
+            ef_search: 100,
+            ..narrow
+        };
+
+        let narrow_recall = recall(IndexType::Hnsw(narrow), 21, 16);
+        let wide_recall = recall(IndexType::Hnsw(wide), 21, 16);
+        assert!(wide_recall >= narrow_recall);
+        assert!(wide_recall > 0.9);
+    }
+
+    #[test]
+    fn hnsw_deterministic_test() {
+        let train = feature_matrix(&read_instances(include_str!("../moons-train.txt")));
+        let test = feature_matrix(&read_instances(include_str!("../moons-test.txt")));
+
+        let params = HnswParams::default();
+        let hnsw1 = Index::new(IndexType::Hnsw(params), train.view(), &Euclidean);
+        let hnsw2 = Index::new(IndexType::Hnsw(params), train.view(), &Euclidean);
+
+        for query in test.outer_iter() {
+            assert_eq!(
+                hnsw1.k_nearest(train.view(), &Euclidean, query, 3),
+                hnsw2.k_nearest(train.view(), &Euclidean, query, 3)
+            );
+        }
+    }
+}
@@ -0,0 +1,489 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::io::{self, Write};
+
+use byteorder::WriteBytesExt;
+use failure::Error;
+use ndarray::prelude::*;
+use ordered_float::OrderedFloat;
+use rand::{SeedableRng, XorShiftRng};
+
+use balltree::BallTree;
+use hnsw::Hnsw;
+use kdtree::KdTree;
+use lsh::Lsh;
+use serialization::{invalid_model, ModelReader, ModelWriter};
+use {Euclidean, HnswParams, LshParams, Metric, Metrics};
+
+/// Nearest neighbor search strategies.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum IndexType {
+    /// Compute the distance to every training instance.
+    BruteForce,
+
+    /// Search a KD-tree over the training instances.
+    #[default]
+    KdTree,
+
+    /// Search a ball tree over the training instances. Ball trees are
+    /// more effective than KD-trees for data with many features.
+    BallTree,
+
+    /// Search a Hierarchical Navigable Small World graph. This strategy
+    /// is approximate: it is fast, but does not always find the nearest
+    /// neighbors.
+    Hnsw(HnswParams),
+
+    /// Search a random hyperplane locality-sensitive hash index. This
+    /// strategy is approximate and finds instances with a small angle to
+    /// the query, so it is best suited to the cosine distance.
+    Lsh(LshParams),
+}
+
+impl IndexType {
+    /// Check whether the search strategy can be used with a metric.
+    ///
+    /// The KD-tree requires a metric that provides a lower bound on the
+    /// distance given the difference in one coordinate. The ball tree
+    /// requires a true metric. The other strategies support all metrics.
+    /// Since search with the Mahalanobis distance is Euclidean search over
+    /// whitened data, it is supported by all strategies.
+    pub fn supports_metric(&self, metric: &Metrics) -> bool {
+        match *metric {
+            Metrics::Mahalanobis(_) => self.supports(&Euclidean),
+            ref metric => self.supports(metric),
+        }
+    }
+
+    pub(crate) fn supports<M>(&self, metric: &M) -> bool
+    where
+        M: Metric,
+    {
+        match *self {
+            IndexType::KdTree => metric.coordinate_lower_bound(0.).is_some(),
+            IndexType::BallTree => metric.is_true_metric(),
+            _ => true,
+        }
+    }
+}
+
+/// A neighbor of a query point.
+///
+/// Neighbors are ordered by distance. Neighbors at the same distance are
+/// ordered by their instance index, so that all exact search strategies
+/// find the same neighbors.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub(crate) struct Neighbor {
+    pub distance: OrderedFloat<f32>,
+    pub idx: usize,
+}
+
+/// Collector of the k nearest neighbors of a query point.
+pub(crate) struct NearestNeighbors {
+    k: usize,
+    heap: BinaryHeap<Neighbor>,
+}
+
+impl NearestNeighbors {
+    pub fn new(k: usize) -> Self {
+        assert!(k > 0, "k should at least be 1");
+
+        NearestNeighbors {
+            k,
+            heap: BinaryHeap::with_capacity(k),
+        }
+    }
+
+    /// The number of neighbors to collect.
+    pub fn k(&self) -> usize {
+        self.k
+    }
+
+    /// Offer a candidate neighbor.
+    pub fn push(&mut self, idx: usize, distance: f32) {
+        let neighbor = Neighbor {
+            distance: OrderedFloat(distance),
+            idx,
+        };
+
+        if self.heap.len() < self.k {
+            self.heap.push(neighbor);
+        } else {
+            let mut root = self
+                .heap
+                .peek_mut()
+                .expect("k > 0, so there should be a neighbor");
+            if neighbor < *root {
+                *root = neighbor;
+            }
+        }
+    }
+
+    /// The distance that a candidate has to beat.
+    ///
+    /// Returns `None` when fewer than k neighbors were collected, since
+    /// any candidate is accepted in that case.
+    pub fn max_distance(&self) -> Option<f32> {
+        if self.heap.len() < self.k {
+            None
+        } else {
+            self.heap.peek().map(|n| n.distance.into_inner())
+        }
+    }
+
+    /// Check whether a region at the given minimum distance can contain
+    /// a neighbor that would be accepted.
+    pub fn may_contain(&self, min_distance: f32) -> bool {
+        // Candidates at the same distance can still displace a neighbor
+        // with a higher index, so only strictly farther regions are pruned.
+        self.max_distance().map_or(true, |max| min_distance <= max)
+    }
+
+    /// Get the neighbors, sorted from nearest to farthest.
+    pub fn into_sorted_vec(self) -> Vec<Neighbor> {
+        self.heap.into_sorted_vec()
+    }
+}
+
+/// A nearest neighbor search structure over the rows of a feature matrix.
+pub(crate) enum Index {
+    BruteForce,
+    KdTree(KdTree),
+    BallTree(BallTree),
+    Hnsw(Hnsw),
+    Lsh(Lsh),
+}
+
+impl Index {
+    pub fn new<M>(index_type: IndexType, features: ArrayView2<f32>, metric: &M) -> Self
+    where
+        M: Metric,
+    {
+        assert!(
+            index_type.supports(metric),
+            "The search strategy does not support the metric"
+        );
+
+        match index_type {
+            IndexType::BruteForce => Index::BruteForce,
+            IndexType::KdTree => Index::KdTree(KdTree::new(features)),
+            IndexType::BallTree => Index::BallTree(BallTree::new(features, metric)),
+            IndexType::Hnsw(params) => Index::Hnsw(Hnsw::new(features, metric, params)),
+            IndexType::Lsh(params) => Index::Lsh(Lsh::new(features, params)),
+        }
+    }
+
+    /// Get the search strategy of the index.
+    pub fn index_type(&self) -> IndexType {
+        match *self {
+            Index::BruteForce => IndexType::BruteForce,
+            Index::KdTree(_) => IndexType::KdTree,
+            Index::BallTree(_) => IndexType::BallTree,
+            Index::Hnsw(ref hnsw) => IndexType::Hnsw(hnsw.params()),
+            Index::Lsh(ref lsh) => IndexType::Lsh(lsh.params()),
+        }
+    }
+
+    pub fn write<W>(&self, writer: &mut ModelWriter<W>) -> io::Result<()>
+    where
+        W: Write,
+    {
+        match *self {
+            Index::BruteForce => writer.write_u8(0),
+            Index::KdTree(ref tree) => {
+                writer.write_u8(1)?;
+                tree.write(writer)
+            }
+            Index::BallTree(ref tree) => {
+                writer.write_u8(2)?;
+                tree.write(writer)
+            }
+            Index::Hnsw(ref hnsw) => {
+                writer.write_u8(3)?;
+                hnsw.write(writer)
+            }
+            Index::Lsh(ref lsh) => {
+                writer.write_u8(4)?;
+                lsh.write(writer)
+            }
+        }
+    }
+
+    /// Read an index for a feature matrix with the given shape.
+    pub fn read(
+        reader: &mut ModelReader,
+        n_instances: usize,
+        n_features: usize,
+    ) -> Result<Self, Error> {
+        match reader.read_u8()? {
+            0 => Ok(Index::BruteForce),
+            1 => Ok(Index::KdTree(KdTree::read(
+                reader,
+                n_instances,
+                n_features,
+            )?)),
+            2 => Ok(Index::BallTree(BallTree::read(
+                reader,
+                n_instances,
+                n_features,
+            )?)),
+            3 => Ok(Index::Hnsw(Hnsw::read(reader, n_instances)?)),
+            4 => Ok(Index::Lsh(Lsh::read(reader, n_instances, n_features)?)),
+            _ => Err(invalid_model("unknown index type")),
+        }
+    }
+
+    /// Find the k nearest neighbors of a query point.
+    pub fn k_nearest<M>(
+        &self,
+        features: ArrayView2<f32>,
+        metric: &M,
+        query: ArrayView1<f32>,
+        k: usize,
+    ) -> Vec<Neighbor>
+    where
+        M: Metric,
+    {
+        match *self {
+            Index::BruteForce => brute_force_k_nearest(features, metric, query, k),
+            Index::KdTree(ref tree) => tree.k_nearest(features, metric, query, k),
+            Index::BallTree(ref tree) => tree.k_nearest(features, metric, query, k),
+            Index::Hnsw(ref hnsw) => hnsw.k_nearest(features, metric, query, k),
+            Index::Lsh(ref lsh) => lsh.k_nearest(features, metric, query, k),
+        }
+    }
+
+    /// Find the neighbors of a query point within the given radius,
+    /// sorted from nearest to farthest.
+    ///
+    /// The approximate strategies search an increasing number of nearest
+    /// neighbors, until the farthest of them is outside the radius. They
+    /// may miss neighbors within the radius.
+    pub fn within_radius<M>(
+        &self,
+        features: ArrayView2<f32>,
+        metric: &M,
+        query: ArrayView1<f32>,
+        radius: f32,
+    ) -> Vec<Neighbor>
+    where
+        M: Metric,
+    {
+        match *self {
+            Index::BruteForce => brute_force_within_radius(features, metric, query, radius),
+            Index::KdTree(ref tree) => tree.within_radius(features, metric, query, radius),
+            Index::BallTree(ref tree) => tree.within_radius(features, metric, query, radius),
+            Index::Hnsw(_) | Index::Lsh(_) => {
+                let mut k = 16;
+                loop {
+                    let mut neighbors = self.k_nearest(features, metric, query, k);
+                    let complete = neighbors.len() < k || k >= features.rows();
+                    if complete
+                        || neighbors
+                            .last()
+                            .is_some_and(|neighbor| neighbor.distance.into_inner() > radius)
+                    {
+                        neighbors.retain(|neighbor| neighbor.distance.into_inner() <= radius);
+                        return neighbors;
+                    }
+                    k *= 2;
+                }
+            }
+        }
+    }
+}
+
+pub(crate) fn brute_force_k_nearest<M>(
+    features: ArrayView2<f32>,
+    metric: &M,
+    query: ArrayView1<f32>,
+    k: usize,
+) -> Vec<Neighbor>
+where
+    M: Metric,
+{
+    let mut nearest_neighbors = NearestNeighbors::new(k);
+    for (idx, instance) in features.outer_iter().enumerate() {
+        nearest_neighbors.push(idx, metric.distance(instance, query));
+    }
+
+    nearest_neighbors.into_sorted_vec()
+}
+
+fn brute_force_within_radius<M>(
+    features: ArrayView2<f32>,
+    metric: &M,
+    query: ArrayView1<f32>,
+    radius: f32,
+) -> Vec<Neighbor>
+where
+    M: Metric,
+{
+    let mut neighbors = Vec::new();
+    for (idx, instance) in features.outer_iter().enumerate() {
+        push_within_radius(
+            &mut neighbors,
+            idx,
+            metric.distance(instance, query),
+            radius,
+        );
+    }
+
+    neighbors.sort_unstable();
+    neighbors
+}
+
+/// Add a candidate neighbor if it is within the radius.
+pub(crate) fn push_within_radius(
+    neighbors: &mut Vec<Neighbor>,
+    idx: usize,
+    distance: f32,
+    radius: f32,
+) {
+    if distance <= radius {
+        neighbors.push(Neighbor {
+            distance: OrderedFloat(distance),
+            idx,
+        });
+    }
+}
+
+/// Construct a random number generator with a fixed seed.
+pub(crate) fn seeded_rng(seed: u64) -> XorShiftRng {
+    // The xorshift generator must not be seeded with all zeros.
+    XorShiftRng::from_seed([seed as u32, (seed >> 32) as u32, 0x9e37_79b9, 0x7f4a_7c15])
+}
+
+/// Find the feature with the largest spread among the given instances.
+///
+/// Returns the feature and its spread. The spread is zero when all
+/// instances are identical.
+pub(crate) fn widest_feature(features: ArrayView2<f32>, indices: &[usize]) -> (usize, f32) {
+    let (feature, spread) = (0..features.cols())
+        .map(|feature| {
+            let (min, max) =
+                indices
+                    .iter()
+                    .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), &idx| {
+                        let v = features[(idx, feature)];
+                        (min.min(v), max.max(v))
+                    });
+            (feature, max - min)
+        })
+        .max_by(|&(_, s1), &(_, s2)| s1.partial_cmp(&s2).unwrap_or(Ordering::Equal))
+        .unwrap_or((0, 0.));
+
+    if spread.is_nan() {
+        (feature, 0.)
+    } else {
+        (feature, spread)
+    }
+}
+
+/// Partition instances around the median of a feature.
+///
+/// Returns the position of the median in `indices`. Instances before
+/// the median have a value that is smaller than or equal to the median,
+/// instances after the median a value that is larger or equal.
+pub(crate) fn partition_median(
+    features: ArrayView2<f32>,
+    indices: &mut [usize],
+    feature: usize,
+) -> usize {
+    let mid = indices.len() / 2;
+    indices.select_nth_unstable_by(mid, |&i1, &i2| {
+        features[(i1, feature)]
+            .partial_cmp(&features[(i2, feature)])
+            .unwrap_or(Ordering::Equal)
+    });
+    mid
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::Cursor;
+
+    use ndarray::prelude::*;
+
+    use super::{Index, IndexType, NearestNeighbors};
+    use {Instance, InstanceIter, Metric};
+
+    pub fn read_instances(data: &str) -> Vec<Instance> {
+        InstanceIter::new(Cursor::new(data))
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    pub fn feature_matrix(instances: &[Instance]) -> Array2<f32> {
+        let n_features = instances[0].features.len();
+        let data = instances
+            .iter()
+            .flat_map(|inst| inst.features.iter().cloned())
+            .collect();
+        Array2::from_shape_vec((instances.len(), n_features), data).unwrap()
+    }
+
+    /// Generate a matrix with values in [-1, 1) using a linear
+    /// congruential generator.
+    pub fn random_matrix(rows: usize, cols: usize, seed: u64) -> Array2<f32> {
+        let mut state = seed;
+        Array2::from_shape_fn((rows, cols), |_| {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (state >> 40) as f32 / (1u64 << 23) as f32 - 1.
+        })
+    }
+
+    /// Check that an index finds the same neighbors as brute force search.
+    pub fn check_against_brute_force<M>(
+        index_type: IndexType,
+        metric: &M,
+        train: &Array2<f32>,
+        test: &Array2<f32>,
+    ) where
+        M: Metric,
+    {
+        let brute_force = Index::new(IndexType::BruteForce, train.view(), metric);
+        let index = Index::new(index_type, train.view(), metric);
+
+        for query in test.outer_iter() {
+            for k in 1..8 {
+                let nearest_neighbors = brute_force.k_nearest(train.view(), metric, query, k);
+                assert_eq!(
+                    index.k_nearest(train.view(), metric, query, k),
+                    nearest_neighbors
+                );
+
+                // Search within the distance of the k-th nearest neighbor.
+                let radius = nearest_neighbors.last().unwrap().distance.into_inner();
+                assert_eq!(
+                    index.within_radius(train.view(), metric, query, radius),
+                    brute_force.within_radius(train.view(), metric, query, radius)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn nearest_neighbors_test() {
+        let mut nearest = NearestNeighbors::new(2);
+        assert!(nearest.may_contain(100.0));
+
+        nearest.push(0, 3.0);
+        nearest.push(1, 1.0);
+        assert_eq!(nearest.max_distance(), Some(3.0));
+
+        nearest.push(2, 2.0);
+        nearest.push(3, 3.0);
+        assert_eq!(nearest.max_distance(), Some(2.0));
+        assert!(nearest.may_contain(2.0));
+        assert!(!nearest.may_contain(2.5));
+
+        // Ties are broken in favor of the lowest index.
+        nearest.push(4, 1.0);
+        nearest.push(5, 1.0);
+        let idx: Vec<_> = nearest.into_sorted_vec().iter().map(|n| n.idx).collect();
+        assert_eq!(idx, vec![1, 4]);
+    }
+}
@@ -1,53 +1,82 @@
+use std::error::Error as StdError;
 use std::io::{self, BufRead, Lines};
+use std::marker::PhantomData;
+use std::str::FromStr;
 
 use failure::{err_msg, Error};
 
+use SparseVector;
+
 /// A data instance.
-#[derive(Debug, PartialEq, PartialOrd)]
-pub struct Instance {
+///
+/// The label is a class (`usize`) for classification and a target
+/// value (`f32`) for regression. The features are dense (`Vec<f32>`)
+/// or sparse (`SparseVector`).
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
+pub struct Instance<L = usize, F = Vec<f32>> {
     /// The data point label.
-    pub label: usize,
+    pub label: L,
 
     /// The features of the data point.
-    pub features: Vec<f32>,
+    pub features: F,
 }
 
-/// An iterator over data points.
-pub struct InstanceIter<R> {
+/// A data instance with a real-valued target for regression.
+pub type RegressionInstance = Instance<f32>;
+
+/// A data instance with sparse features.
+pub type SparseInstance<L = usize> = Instance<L, SparseVector>;
+
+/// An iterator over data points with class labels.
+pub type InstanceIter<R> = LabeledInstanceIter<R, usize>;
+
+/// An iterator over data points with real-valued targets for regression.
+pub type RegressionInstanceIter<R> = LabeledInstanceIter<R, f32>;
+
+/// An iterator over data points with labels of type `L`.
+pub struct LabeledInstanceIter<R, L> {
     lines: Lines<R>,
+    label_type: PhantomData<L>,
 }
 
-impl<R> InstanceIter<R>
+impl<R, L> LabeledInstanceIter<R, L>
 where
     R: BufRead,
 {
     /// Construct a new iterator over data points.
     pub fn new(buf_read: R) -> Self {
-        InstanceIter {
+        LabeledInstanceIter {
             lines: buf_read.lines(),
+            label_type: PhantomData,
         }
     }
 }
 
-impl<R> Iterator for InstanceIter<R>
+impl<R, L> Iterator for LabeledInstanceIter<R, L>
 where
     R: BufRead,
+    L: FromStr,
+    L::Err: StdError + Send + Sync + 'static,
 {
-    type Item = Result<Instance, Error>;
+    type Item = Result<Instance<L>, Error>;
 
     fn next(&mut self) -> Option<Self::Item> {
         Some(process_line(self.lines.next()?))
     }
 }
 
-fn process_line(line: Result<String, io::Error>) -> Result<Instance, Error> {
+fn process_line<L>(line: Result<String, io::Error>) -> Result<Instance<L>, Error>
+where
+    L: FromStr,
+    L::Err: StdError + Send + Sync + 'static,
+{
     let line = line?;
 
     let mut iter = line.split_whitespace();
 
     // Get and parse label.
     let label_str = iter.next().ok_or(err_msg("Line is missing label"))?;
-    let label = label_str.parse::<usize>()?;
+    let label = label_str.parse::<L>()?;
 
     // Parse the remaining columns as features.
     let features = iter.map(|v| v.parse::<f32>()).collect::<Result<_, _>>()?;
@@ -55,11 +84,127 @@ fn process_line(line: Result<String, io::Error>) -> Result<Instance, Error> {
     Ok(Instance { label, features })
 }
 
+/// An iterator over data points in the svmlight/libsvm format.
+///
+/// Every line consists of a label, followed by `index:value` pairs of
+/// the non-zero features in increasing index order, for example:
+///
+/// ```text
+/// 1 3:0.5 17:1 42:-2.25
+/// ```
+///
+/// Query identifiers (`qid:n`) are ignored, as are comments starting
+/// with `#` and empty lines. Errors report the line number.
+pub struct SvmLightIter<R, L = usize> {
+    lines: Lines<R>,
+    line_no: usize,
+    label_type: PhantomData<L>,
+}
+
+impl<R, L> SvmLightIter<R, L>
+where
+    R: BufRead,
+{
+    /// Construct a new iterator over data points.
+    pub fn new(buf_read: R) -> Self {
+        SvmLightIter {
+            lines: buf_read.lines(),
+            line_no: 0,
+            label_type: PhantomData,
+        }
+    }
+}
+
+impl<R, L> Iterator for SvmLightIter<R, L>
+where
+    R: BufRead,
+    L: FromStr,
+    L::Err: StdError + Send + Sync + 'static,
+{
+    type Item = Result<SparseInstance<L>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(err) => return Some(Err(err.into())),
+            };
+            self.line_no += 1;
+
+            // Strip comments and skip empty lines.
+            let data = line.split('#').next().unwrap().trim();
+            if data.is_empty() {
+                continue;
+            }
+
+            return Some(
+                process_svmlight_line(data)
+                    .map_err(|err| err_msg(format!("line {}: {}", self.line_no, err))),
+            );
+        }
+    }
+}
+
+fn process_svmlight_line<L>(line: &str) -> Result<SparseInstance<L>, String>
+where
+    L: FromStr,
+    L::Err: StdError,
+{
+    let mut iter = line.split_whitespace();
+
+    let label_str = iter.next().expect("Line should not be empty");
+    let label = label_str
+        .parse::<L>()
+        .map_err(|err| format!("invalid label '{}': {}", label_str, err))?;
+
+    let mut indices = Vec::new();
+    let mut values = Vec::new();
+    let mut last_index = None;
+    for token in iter {
+        let mut parts = token.splitn(2, ':');
+        let (index_str, value_str) = match (parts.next(), parts.next()) {
+            (Some(index), Some(value)) => (index, value),
+            _ => return Err(format!("expected index:value, got '{}'", token)),
+        };
+
+        if index_str == "qid" {
+            continue;
+        }
+
+        let index = index_str
+            .parse::<usize>()
+            .map_err(|err| format!("invalid feature index in '{}': {}", token, err))?;
+        let value = value_str
+            .parse::<f32>()
+            .map_err(|err| format!("invalid feature value in '{}': {}", token, err))?;
+
+        if let Some(last_index) = last_index.filter(|&last_index| index <= last_index) {
+            return Err(format!(
+                "feature indices should be increasing, got {} after {}",
+                index, last_index
+            ));
+        }
+        last_index = Some(index);
+
+        // Explicit zeros are not stored.
+        if value != 0. {
+            indices.push(index);
+            values.push(value);
+        }
+    }
+
+    Ok(Instance {
+        label,
+        features: SparseVector::new(indices, values),
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use std::io::Cursor;
 
-    use super::{Instance, InstanceIter};
+    use super::{Instance, InstanceIter, RegressionInstance, RegressionInstanceIter, SvmLightIter};
+    use {SparseInstance, SparseVector};
 
     #[test]
     fn iter_test() {
@@ -82,4 +227,82 @@ mod tests {
         );
         assert!(iter.next().is_none());
     }
+
+    #[test]
+    fn regression_iter_test() {
+        let lines = "-0.5 1.0 -1.0\n2 -1.0 1.0";
+        let mut iter = RegressionInstanceIter::new(Cursor::new(lines));
+
+        assert_eq!(
+            iter.next().unwrap().unwrap(),
+            RegressionInstance {
+                label: -0.5,
+                features: vec![1.0, -1.0],
+            }
+        );
+        assert_eq!(
+            iter.next().unwrap().unwrap(),
+            RegressionInstance {
+                label: 2.0,
+                features: vec![-1.0, 1.0],
+            }
+        );
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn invalid_class_test() {
+        let mut iter = InstanceIter::new(Cursor::new("0.5 1.0 -1.0"));
+        assert!(iter.next().unwrap().is_err());
+    }
+
+    #[test]
+    fn svmlight_iter_test() {
+        let lines = "1 1:0.5 3:-1 # comment\n\n0 qid:2 2:1.5 4:0\n2\n";
+        let mut iter: SvmLightIter<_> = SvmLightIter::new(Cursor::new(lines));
+
+        assert_eq!(
+            iter.next().unwrap().unwrap(),
+            SparseInstance {
+                label: 1,
+                features: SparseVector::new(vec![1, 3], vec![0.5, -1.]),
+            }
+        );
+        assert_eq!(
+            iter.next().unwrap().unwrap(),
+            SparseInstance {
+                label: 0,
+                features: SparseVector::new(vec![2], vec![1.5]),
+            }
+        );
+        assert_eq!(
+            iter.next().unwrap().unwrap(),
+            SparseInstance {
+                label: 2,
+                features: SparseVector::default(),
+            }
+        );
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn svmlight_invalid_test() {
+        for (lines, message) in &[
+            ("1 1:0.5\n0 2:x", "line 2: invalid feature value in '2:x'"),
+            ("1 1:0.5\n\n0 2", "line 3: expected index:value, got '2'"),
+            ("a 1:0.5", "line 1: invalid label 'a'"),
+            ("1 -1:0.5", "line 1: invalid feature index in '-1:0.5'"),
+            ("1 3:1 2:1", "line 1: feature indices should be increasing"),
+            ("1 3:0 2:1", "line 1: feature indices should be increasing"),
+        ] {
+            let mut iter: SvmLightIter<_> = SvmLightIter::new(Cursor::new(*lines));
+            let err = iter.find_map(|inst| inst.err()).unwrap();
+            assert!(
+                err.to_string().starts_with(message),
+                "'{}' does not start with '{}'",
+                err,
+                message
+            );
+        }
+    }
 }
@@ -0,0 +1,319 @@
+use std::io::{self, Write};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use failure::Error;
+use ndarray::prelude::*;
+
+use index::{partition_median, push_within_radius, widest_feature, NearestNeighbors, Neighbor};
+use serialization::{invalid_model, ModelReader, ModelWriter};
+use Metric;
+
+/// Maximum number of instances in a leaf node.
+const LEAF_SIZE: usize = 16;
+
+enum Node {
+    /// A leaf, containing the instances `indices[start..end]`.
+    Leaf { start: usize, end: usize },
+
+    /// An inner node that splits instances on a feature. Instances in
+    /// the left subtree have a value `<= value` for the feature, instances
+    /// in the right subtree `>= value`.
+    Split {
+        feature: usize,
+        value: f32,
+        left: usize,
+        right: usize,
+    },
+}
+
+/// A KD-tree for exact nearest neighbor search.
+///
+/// The tree does not own the feature matrix, it stores the indices of
+/// the instances in the feature matrix that the tree was built from.
+pub(crate) struct KdTree {
+    indices: Vec<usize>,
+    nodes: Vec<Node>,
+}
+
+impl KdTree {
+    /// Construct a KD-tree for the rows of a feature matrix.
+    pub fn new(features: ArrayView2<f32>) -> Self {
+        let mut tree = KdTree {
+            indices: (0..features.rows()).collect(),
+            nodes: Vec::new(),
+        };
+
+        if !tree.indices.is_empty() {
+            tree.build(features, 0, features.rows());
+        }
+
+        tree
+    }
+
+    /// Build the subtree for `indices[start..end]`, returning the
+    /// index of its root node.
+    fn build(&mut self, features: ArrayView2<f32>, start: usize, end: usize) -> usize {
+        if end - start <= LEAF_SIZE {
+            return self.add_node(Node::Leaf { start, end });
+        }
+
+        // Split on the feature with the largest spread.
+        let (feature, spread) = widest_feature(features, &self.indices[start..end]);
+
+        // All remaining instances are identical.
+        if spread <= 0. {
+            return self.add_node(Node::Leaf { start, end });
+        }
+
+        let mid = start + partition_median(features, &mut self.indices[start..end], feature);
+        let value = features[(self.indices[mid], feature)];
+
+        let left = self.build(features, start, mid);
+        let right = self.build(features, mid, end);
+
+        self.add_node(Node::Split {
+            feature,
+            value,
+            left,
+            right,
+        })
+    }
+
+    fn add_node(&mut self, node: Node) -> usize {
+        self.nodes.push(node);
+        self.nodes.len() - 1
+    }
+
+    pub fn write<W>(&self, writer: &mut ModelWriter<W>) -> io::Result<()>
+    where
+        W: Write,
+    {
+        writer.write_usizes(&self.indices)?;
+        writer.write_usize(self.nodes.len())?;
+        for node in &self.nodes {
+            match *node {
+                Node::Leaf { start, end } => {
+                    writer.write_u8(0)?;
+                    writer.write_usize(start)?;
+                    writer.write_usize(end)?;
+                }
+                Node::Split {
+                    feature,
+                    value,
+                    left,
+                    right,
+                } => {
+                    writer.write_u8(1)?;
+                    writer.write_usize(feature)?;
+                    writer.write_f32::<LittleEndian>(value)?;
+                    writer.write_usize(left)?;
+                    writer.write_usize(right)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Read a KD-tree for a feature matrix with the given shape.
+    ///
+    /// Children must precede their parents, which guarantees that the
+    /// tree does not contain cycles.
+    pub fn read(
+        reader: &mut ModelReader,
+        n_instances: usize,
+        n_features: usize,
+    ) -> Result<Self, Error> {
+        let indices = reader.read_indices(n_instances)?;
+        let n_nodes = reader.read_len(17)?;
+
+        let mut nodes = Vec::with_capacity(n_nodes);
+        for node_idx in 0..n_nodes {
+            let node = match reader.read_u8()? {
+                0 => {
+                    let start = reader.read_usize()?;
+                    let end = reader.read_usize()?;
+                    if start > end || end > indices.len() {
+                        return Err(invalid_model("KD-tree leaf out of bounds"));
+                    }
+                    Node::Leaf { start, end }
+                }
+                1 => Node::Split {
+                    feature: reader.read_index(n_features)?,
+                    value: reader.read_f32()?,
+                    left: reader.read_index(node_idx)?,
+                    right: reader.read_index(node_idx)?,
+                },
+                _ => return Err(invalid_model("unknown KD-tree node type")),
+            };
+            nodes.push(node);
+        }
+
+        Ok(KdTree { indices, nodes })
+    }
+
+    /// Find the k nearest neighbors of a query point.
+    ///
+    /// The metric must provide coordinate lower bounds.
+    pub fn k_nearest<M>(
+        &self,
+        features: ArrayView2<f32>,
+        metric: &M,
+        query: ArrayView1<f32>,
+        k: usize,
+    ) -> Vec<Neighbor>
+    where
+        M: Metric,
+    {
+        let mut nearest_neighbors = NearestNeighbors::new(k);
+
+        if let Some(root) = self.nodes.len().checked_sub(1) {
+            self.search(features, metric, query, root, &mut nearest_neighbors);
+        }
+
+        nearest_neighbors.into_sorted_vec()
+    }
+
+    fn search<M>(
+        &self,
+        features: ArrayView2<f32>,
+        metric: &M,
+        query: ArrayView1<f32>,
+        node: usize,
+        nearest_neighbors: &mut NearestNeighbors,
+    ) where
+        M: Metric,
+    {
+        match self.nodes[node] {
+            Node::Leaf { start, end } => {
+                for &idx in &self.indices[start..end] {
+                    let distance = metric.distance(features.row(idx), query);
+                    nearest_neighbors.push(idx, distance);
+                }
+            }
+            Node::Split {
+                feature,
+                value,
+                left,
+                right,
+            } => {
+                let diff = query[feature] - value;
+                let (near, far) = if diff < 0. {
+                    (left, right)
+                } else {
+                    (right, left)
+                };
+
+                self.search(features, metric, query, near, nearest_neighbors);
+
+                // The distance along the split feature gives a lower bound
+                // on the distance to any instance on the other side.
+                let min_distance = metric
+                    .coordinate_lower_bound(diff)
+                    .expect("Metric does not provide coordinate bounds");
+                if nearest_neighbors.may_contain(min_distance) {
+                    self.search(features, metric, query, far, nearest_neighbors);
+                }
+            }
+        }
+    }
+
+    /// Find the neighbors of a query point within the given radius,
+    /// sorted from nearest to farthest.
+    ///
+    /// The metric must provide coordinate lower bounds.
+    pub fn within_radius<M>(
+        &self,
+        features: ArrayView2<f32>,
+        metric: &M,
+        query: ArrayView1<f32>,
+        radius: f32,
+    ) -> Vec<Neighbor>
+    where
+        M: Metric,
+    {
+        let mut neighbors = Vec::new();
+
+        if let Some(root) = self.nodes.len().checked_sub(1) {
+            self.search_radius(features, metric, query, radius, root, &mut neighbors);
+        }
+
+        neighbors.sort_unstable();
+        neighbors
+    }
+
+    fn search_radius<M>(
+        &self,
+        features: ArrayView2<f32>,
+        metric: &M,
+        query: ArrayView1<f32>,
+        radius: f32,
+        node: usize,
+        neighbors: &mut Vec<Neighbor>,
+    ) where
+        M: Metric,
+    {
+        match self.nodes[node] {
+            Node::Leaf { start, end } => {
+                for &idx in &self.indices[start..end] {
+                    let distance = metric.distance(features.row(idx), query);
+                    push_within_radius(neighbors, idx, distance, radius);
+                }
+            }
+            Node::Split {
+                feature,
+                value,
+                left,
+                right,
+            } => {
+                let diff = query[feature] - value;
+                let (near, far) = if diff < 0. {
+                    (left, right)
+                } else {
+                    (right, left)
+                };
+
+                self.search_radius(features, metric, query, radius, near, neighbors);
+
+                let min_distance = metric
+                    .coordinate_lower_bound(diff)
+                    .expect("Metric does not provide coordinate bounds");
+                if min_distance <= radius {
+                    self.search_radius(features, metric, query, radius, far, neighbors);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use index::tests::{check_against_brute_force, feature_matrix, random_matrix, read_instances};
+    use index::IndexType;
+    use {Chebyshev, Euclidean, Manhattan, Minkowski, SquaredEuclidean};
+
+    #[test]
+    fn kd_tree_moons_test() {
+        let train = feature_matrix(&read_instances(include_str!("../moons-train.txt")));
+        let test = feature_matrix(&read_instances(include_str!("../moons-test.txt")));
+        check_against_brute_force(IndexType::KdTree, &Euclidean, &train, &test);
+    }
+
+    #[test]
+    fn kd_tree_duplicates_test() {
+        // Many identical instances, which result in distance ties.
+        let train = feature_matrix(&read_instances(&"0 1.0 1.0\n1 1.0 2.0\n".repeat(40)));
+        let test = feature_matrix(&read_instances("0 1.0 1.0\n0 1.0 1.5\n1 0.0 3.0"));
+        check_against_brute_force(IndexType::KdTree, &Euclidean, &train, &test);
+    }
+
+    #[test]
+    fn kd_tree_metrics_test() {
+        let train = random_matrix(500, 4, 5);
+        let test = random_matrix(50, 4, 6);
+        check_against_brute_force(IndexType::KdTree, &SquaredEuclidean, &train, &test);
+        check_against_brute_force(IndexType::KdTree, &Manhattan, &train, &test);
+        check_against_brute_force(IndexType::KdTree, &Chebyshev, &train, &test);
+        check_against_brute_force(IndexType::KdTree, &Minkowski::new(3.), &train, &test);
+    }
+}
@@ -1,32 +1,143 @@
-use std::collections::{BTreeMap, BinaryHeap};
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
 
+use byteorder::WriteBytesExt;
+use failure::{err_msg, Error};
 use ndarray::prelude::*;
+use ndarray::{Data, OwnedRepr, ViewRepr};
 use ordered_float::OrderedFloat;
+use rand::Rng;
 
-use {EuclideanDistance, Instance};
+use anomaly::Densities;
+use index::{seeded_rng, Neighbor};
+use search::Search;
+use serialization::{invalid_model, ModelReader, ModelWriter};
+use {
+    AnomalyScore, Covariance, IndexType, Instance, LabelDictionary, Mahalanobis, Metrics,
+    Reduction, Scaler, Scaling, TieBreaking, Weights,
+};
 
 /// `KNNBuilder` collects data points for KNN classification.
-pub struct KNNBuilder {
-    labels: Vec<usize>,
+///
+/// The builder collects data points with class labels (`usize`) for
+/// classification or real-valued targets (`f32`) for regression.
+#[derive(Clone)]
+pub struct KNNBuilder<L = usize> {
+    labels: Vec<L>,
     features: Vec<f32>,
     n_instances: usize,
+    index_type: Option<IndexType>,
+    metric: Metrics,
+    scaling: Option<Scaling>,
+    scaler: Option<Scaler>,
 }
 
-impl Default for KNNBuilder {
+impl<L> Default for KNNBuilder<L> {
     fn default() -> Self {
         KNNBuilder {
-            features: Vec::new(),
             labels: Vec::new(),
+            features: Vec::new(),
             n_instances: 0,
+            index_type: None,
+            metric: Metrics::default(),
+            scaling: None,
+            scaler: None,
         }
     }
 }
 
-impl KNNBuilder {
+impl<L> KNNBuilder<L> {
+    /// Set the nearest neighbor search strategy.
+    ///
+    /// The default strategy is a KD-tree, or brute force search when
+    /// the KD-tree does not support the metric. Brute force search will
+    /// give the same predictions.
+    pub fn set_index_type(&mut self, index_type: IndexType) {
+        self.index_type = Some(index_type);
+    }
+
+    /// Set the distance metric.
+    ///
+    /// The default metric is the Euclidean distance. The metric must be
+    /// supported by the search strategy when one is set, see
+    /// `IndexType::supports_metric`.
+    pub fn set_metric(&mut self, metric: Metrics) {
+        self.metric = metric;
+    }
+
+    /// Set the feature scaling.
+    ///
+    /// The scaler is fitted on the data points in the builder when the
+    /// model is constructed. Queries are scaled in the same way before
+    /// search. By default, features are not scaled.
+    pub fn set_scaling(&mut self, scaling: Scaling) {
+        self.scaling = Some(scaling);
+        self.scaler = None;
+    }
+
+    /// Fit the Mahalanobis distance on the data points in the builder.
+    ///
+    /// The fitted distance can be used as the metric of the model with
+    /// `set_metric(Metrics::Mahalanobis(..))`. When feature scaling is
+    /// used, the distance is fitted on the scaled data points, so the
+    /// scaling should be set first.
+    pub fn fit_mahalanobis(&self, covariance: Covariance) -> Result<Mahalanobis, Error> {
+        let features = self.features_view();
+        match self.fit_scaler() {
+            Some(scaler) => Mahalanobis::fit(scaler.transform_rows(features).view(), covariance),
+            None => Mahalanobis::fit(features, covariance),
+        }
+    }
+
+    fn index_type(&self) -> IndexType {
+        self.index_type.unwrap_or_else(|| {
+            if IndexType::KdTree.supports_metric(&self.metric) {
+                IndexType::KdTree
+            } else {
+                IndexType::BruteForce
+            }
+        })
+    }
+
+    fn features_view(&self) -> ArrayView2<'_, f32> {
+        let features_len = self
+            .features
+            .len()
+            .checked_div(self.n_instances)
+            .unwrap_or(0);
+        ArrayView2::from_shape((self.n_instances, features_len), &self.features)
+            .expect("Number of elements does not correspond to the shape")
+    }
+
+    /// Get the feature scaler, fitting it when it was not kept from a
+    /// reduction.
+    fn fit_scaler(&self) -> Option<Scaler> {
+        if self.scaler.is_some() {
+            return self.scaler.clone();
+        }
+
+        match self.scaling {
+            Some(scaling) if self.n_instances > 0 => {
+                Some(Scaler::fit(self.features_view(), scaling))
+            }
+            _ => None,
+        }
+    }
+
+    /// Get the number of data points in the builder.
+    pub fn len(&self) -> usize {
+        self.n_instances
+    }
+
+    /// Check whether the builder is empty.
+    pub fn is_empty(&self) -> bool {
+        self.n_instances == 0
+    }
+
     /// Push a new data point into the builder.
-    pub fn push(&mut self, inst: Instance) {
-        if self.n_instances != 0 {
-            let features_len = self.features.len() / self.n_instances;
+    pub fn push(&mut self, inst: Instance<L>) {
+        if let Some(features_len) = self.features.len().checked_div(self.n_instances) {
             assert_eq!(
                 features_len,
                 inst.features.len(),
@@ -40,72 +151,1379 @@ impl KNNBuilder {
         self.features.extend(inst.features);
         self.labels.push(inst.label);
     }
+
+    /// Get the labels and the nearest neighbor search structure.
+    pub(crate) fn build(self) -> (Vec<L>, Search) {
+        let scaler = self.fit_scaler();
+        let index_type = self.index_type();
+        let features_len = self.features.len() / self.n_instances;
+        let features = Array2::from_shape_vec((self.n_instances, features_len), self.features)
+            .expect("Number of elements does not correspond to the shape");
+
+        (
+            self.labels,
+            Search::new(features, index_type, self.metric, scaler),
+        )
+    }
+}
+
+impl KNNBuilder {
+    /// Remove data points using a prototype selection algorithm.
+    ///
+    /// The data points are selected using the metric, search strategy,
+    /// and feature scaling of the builder, so these should be set first.
+    /// The feature scaler is fitted on the data before reduction and kept
+    /// for the model. Reductions can be applied one after another, for
+    /// instance editing to remove noise followed by condensing.
+    pub fn reduce(&mut self, reduction: Reduction) {
+        assert!(self.n_instances > 0, "Cannot reduce a builder without data");
+
+        let scaler = self.fit_scaler();
+        let search = Search::new(
+            self.features_view().to_owned(),
+            self.index_type(),
+            self.metric.clone(),
+            scaler.clone(),
+        );
+        let keep = reduction.select(&self.labels, &search);
+        self.scaler = scaler;
+
+        let features_len = self.features.len() / self.n_instances;
+        let mut labels = Vec::new();
+        let mut features = Vec::new();
+        for ((&label, instance), keep) in self
+            .labels
+            .iter()
+            .zip(self.features.chunks(features_len.max(1)))
+            .zip(keep)
+        {
+            if keep {
+                labels.push(label);
+                features.extend_from_slice(instance);
+            }
+        }
+
+        self.n_instances = labels.len();
+        self.labels = labels;
+        self.features = features;
+    }
+}
+
+/// A nearest neighbor of a data point among the training instances.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TrainingNeighbor {
+    /// The ID of the training instance.
+    pub id: usize,
+
+    /// The label of the training instance.
+    pub label: usize,
+
+    /// The distance between the data point and the training instance.
+    pub distance: f32,
 }
 
 /// A K Nearest Neighbor classifier.
-pub struct KNN {
+///
+/// The training feature matrix is owned by the classifier or borrowed,
+/// for instance from a memory-mapped model file (see `MmapModel`).
+///
+/// Every training instance has a stable ID. The instances of the builder
+/// are numbered from zero in the order in which they were pushed, and
+/// inserted instances get the next unused ID. IDs are not reused after
+/// removal, and are preserved by compaction and saving.
+#[allow(clippy::upper_case_acronyms)]
+pub struct KNN<S = OwnedRepr<f32>>
+where
+    S: Data<Elem = f32>,
+{
     labels: Vec<usize>,
-    features: Array2<f32>,
+    ids: Vec<usize>,
+    next_id: usize,
+    label_names: Option<LabelDictionary>,
+    n_classes: usize,
+    search: Search<S>,
+    weights: Weights,
+    tie_breaking: TieBreaking,
+    outlier_label: Option<usize>,
+    densities: Mutex<Option<(usize, Arc<Densities>)>>,
 }
 
-#[derive(Debug, Eq, Ord, PartialEq, PartialOrd)]
-struct Neighbor {
-    distance: OrderedFloat<f32>,
-    label: usize,
-}
+/// A K Nearest Neighbor classifier that borrows its feature matrix.
+#[allow(clippy::upper_case_acronyms)]
+pub type KNNView<'a> = KNN<ViewRepr<&'a f32>>;
 
 impl From<KNNBuilder> for KNN {
     fn from(builder: KNNBuilder) -> Self {
-        let features_len = builder.features.len() / builder.n_instances;
-
+        let (labels, search) = builder.build();
         KNN {
-            labels: builder.labels,
-            features: Array2::from_shape_vec((builder.n_instances, features_len), builder.features)
-                .expect("Number of elements does not correspond to the shape"),
+            n_classes: n_classes(&labels),
+            ids: (0..labels.len()).collect(),
+            next_id: labels.len(),
+            labels,
+            label_names: None,
+            search,
+            weights: Weights::default(),
+            tie_breaking: TieBreaking::default(),
+            outlier_label: None,
+            densities: Mutex::new(None),
         }
     }
 }
 
 impl KNN {
+    /// Read a model that was written with `save`.
+    ///
+    /// Returns an error when the data is not a model, when the model was
+    /// written with an incompatible version of the model format, or when
+    /// the model is corrupted.
+    pub fn load<R>(mut read: R) -> Result<Self, Error>
+    where
+        R: Read,
+    {
+        let mut data = Vec::new();
+        read.read_to_end(&mut data)?;
+        Self::read_with(&data, Search::read)
+    }
+
+    /// Insert a training instance, returning its ID.
+    ///
+    /// The instance is scaled like the training data and is searched by
+    /// brute force until the model is compacted. When the model has class
+    /// names, the label must have a class name.
+    pub fn insert(&mut self, instance: Instance) -> usize {
+        if self.label_names.is_some() {
+            assert!(
+                instance.label < self.n_classes,
+                "Label {} does not have a class name",
+                instance.label
+            );
+        }
+
+        let features = ArrayView1::from_shape([instance.features.len()], &instance.features)
+            .expect("Number of elements does not correspond to the shape");
+        self.search.insert(features);
+        self.densities = Mutex::new(None);
+        self.labels.push(instance.label);
+        self.n_classes = self.n_classes.max(instance.label + 1);
+
+        let id = self.next_id;
+        self.ids.push(id);
+        self.next_id += 1;
+
+        id
+    }
+
+    /// Remove the training instance with the given ID.
+    ///
+    /// Returns `false` when there is no instance with the ID. Removed
+    /// instances are skipped during search until the model is compacted.
+    pub fn remove(&mut self, id: usize) -> bool {
+        match self.ids.binary_search(&id) {
+            Ok(idx) => {
+                self.densities = Mutex::new(None);
+                self.search.remove(idx)
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Compact the model.
+    ///
+    /// The feature matrix and the nearest neighbor index are rebuilt from
+    /// the training instances, so that inserted instances are indexed and
+    /// removed instances no longer take memory or search time. Models
+    /// with inserted or removed instances must be compacted before they
+    /// can be saved. Panics when all instances were removed.
+    pub fn compact(&mut self) {
+        if self.search.is_compact() {
+            return;
+        }
+
+        let keep = self.search.compact();
+        self.densities = Mutex::new(None);
+        let (labels, ids) = self
+            .labels
+            .iter()
+            .zip(&self.ids)
+            .zip(keep)
+            .filter(|&(_, keep)| keep)
+            .map(|(label_id, _)| label_id)
+            .unzip();
+        self.labels = labels;
+        self.ids = ids;
+    }
+}
+
+impl<'a> KNNView<'a> {
+    /// Load a model that was written with `save` without copying its
+    /// feature matrix.
+    ///
+    /// The feature matrix is borrowed from `data`, which must be aligned
+    /// for `f32`. This is the case for memory-mapped files, but not
+    /// necessarily for other buffers. Loading without copying is only
+    /// supported on little-endian platforms.
+    pub fn load_view(data: &'a [u8]) -> Result<Self, Error> {
+        Self::read_with(data, Search::read_view)
+    }
+}
+
+impl<S> KNN<S>
+where
+    S: Data<Elem = f32>,
+{
+    fn read_with<'a, F>(data: &'a [u8], read_search: F) -> Result<Self, Error>
+    where
+        F: FnOnce(&mut ModelReader<'a>) -> Result<Search<S>, Error>,
+    {
+        let mut reader = ModelReader::new(data)?;
+
+        let n_labels = reader.read_len(8)?;
+        let labels = (0..n_labels)
+            .map(|_| reader.read_usize())
+            .collect::<Result<Vec<_>, _>>()?;
+        let next_id = reader.read_usize()?;
+        let ids = reader.read_indices(next_id)?;
+        let label_names = match reader.read_u8()? {
+            0 => None,
+            1 => Some(LabelDictionary::read(&mut reader)?),
+            _ => return Err(invalid_model("invalid label dictionary")),
+        };
+        let weights = Weights::read(&mut reader)?;
+        let tie_breaking = TieBreaking::read(&mut reader)?;
+        let outlier_label = match reader.read_u8()? {
+            0 => None,
+            1 => Some(reader.read_usize()?),
+            _ => return Err(invalid_model("invalid outlier label")),
+        };
+        let search = read_search(&mut reader)?;
+        reader.finish()?;
+
+        if labels.len() != search.n_instances() {
+            return Err(invalid_model("number of labels and instances differ"));
+        }
+
+        if ids.len() != labels.len() || ids.windows(2).any(|w| w[0] >= w[1]) {
+            return Err(invalid_model("invalid instance IDs"));
+        }
+
+        let n_classes = match label_names {
+            Some(ref names) => {
+                if labels
+                    .iter()
+                    .chain(&outlier_label)
+                    .any(|&label| label >= names.len())
+                {
+                    return Err(invalid_model("label without a class name"));
+                }
+                names.len()
+            }
+            None => n_classes(&labels),
+        };
+
+        Ok(KNN {
+            labels,
+            ids,
+            next_id,
+            n_classes,
+            label_names,
+            search,
+            weights,
+            tie_breaking,
+            outlier_label,
+            densities: Mutex::new(None),
+        })
+    }
+
+    /// Write the model in a versioned binary format.
+    ///
+    /// The model contains the labels and their names, the instance IDs,
+    /// the feature matrix, the fitted feature scaler, the metric
+    /// (including the fitted whitening of the Mahalanobis distance), the
+    /// neighbor weights, the tie breaking, the outlier label, and the
+    /// nearest neighbor index. Models with a custom metric cannot be
+    /// saved. Models with inserted or removed instances must be compacted
+    /// first.
+    pub fn save<W>(&self, write: W) -> Result<(), Error>
+    where
+        W: Write,
+    {
+        if !self.search.is_compact() {
+            return Err(err_msg(
+                "Cannot save a model with inserted or removed instances, compact it first",
+            ));
+        }
+
+        let mut writer = ModelWriter::new(write)?;
+        writer.write_usizes(&self.labels)?;
+        writer.write_usize(self.next_id)?;
+        writer.write_usizes(&self.ids)?;
+        match self.label_names {
+            Some(ref names) => {
+                writer.write_u8(1)?;
+                names.write(&mut writer)?;
+            }
+            None => writer.write_u8(0)?,
+        }
+        self.weights.write(&mut writer)?;
+        self.tie_breaking.write(&mut writer)?;
+        match self.outlier_label {
+            Some(label) => {
+                writer.write_u8(1)?;
+                writer.write_usize(label)?;
+            }
+            None => writer.write_u8(0)?,
+        }
+        self.search.write(&mut writer)?;
+        writer.finish()?;
+
+        Ok(())
+    }
+
+    /// Get the number of features of data points.
+    pub fn n_features(&self) -> usize {
+        self.search.n_features()
+    }
+
+    /// Get the number of training instances, excluding removed instances.
+    pub fn len(&self) -> usize {
+        self.labels.len() - self.search.n_removed()
+    }
+
+    /// Check whether all training instances were removed.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Check whether the model has a training instance with the given ID.
+    pub fn contains(&self, id: usize) -> bool {
+        match self.ids.binary_search(&id) {
+            Ok(idx) => !self.search.is_removed(idx),
+            Err(_) => false,
+        }
+    }
+
+    /// Get the number of classes.
+    ///
+    /// The classes are numbered from zero. When the model has class names,
+    /// every class name is counted, otherwise the number of classes is
+    /// determined by the largest label in the training data.
+    pub fn n_classes(&self) -> usize {
+        self.n_classes
+    }
+
+    /// Get the class names of the labels, if the model has them.
+    pub fn label_names(&self) -> Option<&LabelDictionary> {
+        self.label_names.as_ref()
+    }
+
+    /// Set the class names of the labels.
+    ///
+    /// The names are stored with the model, so that predictions can be
+    /// reported using the class names. The dictionary must have a name
+    /// for every label of the training data and for the outlier label.
+    pub fn set_label_names(&mut self, names: LabelDictionary) {
+        assert!(
+            self.labels
+                .iter()
+                .chain(&self.outlier_label)
+                .all(|&label| label < names.len()),
+            "Every label should have a class name"
+        );
+        self.n_classes = names.len();
+        self.label_names = Some(names);
+    }
+
+    /// Set the weighting of neighbors in voting.
+    ///
+    /// By default, every neighbor has one vote.
+    /// Panics when the Gaussian bandwidth is not positive and finite.
+    pub fn set_weights(&mut self, weights: Weights) {
+        assert!(
+            weights.is_valid(),
+            "The Gaussian bandwidth should be positive and finite"
+        );
+        self.weights = weights;
+    }
+
+    /// Get the breaking of ties between classes.
+    pub fn tie_breaking(&self) -> TieBreaking {
+        self.tie_breaking
+    }
+
+    /// Set the breaking of ties between classes.
+    ///
+    /// By default, the smallest of the tied labels is predicted.
+    pub fn set_tie_breaking(&mut self, tie_breaking: TieBreaking) {
+        self.tie_breaking = tie_breaking;
+    }
+
+    /// Get the label that is predicted for data points without training
+    /// instances within the radius in `classify_radius`.
+    pub fn outlier_label(&self) -> Option<usize> {
+        self.outlier_label
+    }
+
+    /// Set the label that is predicted for data points without training
+    /// instances within the radius in `classify_radius`.
+    ///
+    /// By default, there is no outlier label and classification of such
+    /// data points fails. When the model has class names, the outlier
+    /// label must have a class name.
+    pub fn set_outlier_label(&mut self, label: Option<usize>) {
+        if let (Some(label), Some(names)) = (label, self.label_names.as_ref()) {
+            assert!(
+                label < names.len(),
+                "The outlier label should have a class name"
+            );
+        }
+        self.outlier_label = label;
+    }
+
     /// Classify a data point.
     ///
     /// The feature vector and the number of nearest neighbors to used are
     /// specified as arguments. The predicted class is returned.
     pub fn classify(&self, features: &[f32], k: usize) -> usize {
-        assert!(k > 0, "k should at least be 1");
-
-        // Compute all distances.
-        let distances = self.features
-            .euclidean_distance(&ArrayView1::from_shape([features.len()], features).unwrap());
-
-        // Get the nearest neighbors.
-        let mut nearest_neighbors = BinaryHeap::with_capacity(k);
-        for (idx, &dist) in distances.iter().enumerate() {
-            let neighbor = Neighbor {
-                label: self.labels[idx],
-                distance: OrderedFloat(dist),
-            };
-            if nearest_neighbors.len() < k {
-                nearest_neighbors.push(neighbor);
-            } else {
-                let mut root = nearest_neighbors
-                    .peek_mut()
-                    .expect("k > 0, so there should be a neighbor");
-                if neighbor.distance < root.distance {
-                    *root = neighbor;
-                }
+        let features = ArrayView1::from_shape([features.len()], features).unwrap();
+        self.vote(&self.search.k_nearest(features, k))
+    }
+
+    /// Classify a batch of data points.
+    ///
+    /// Every row of the feature matrix is a data point. The data points
+    /// are classified in parallel, using the global rayon thread pool. The
+    /// predicted classes are returned in the order of the rows.
+    ///
+    /// With brute force search, the distances for blocks of data points
+    /// are computed using matrix multiplication. The predictions are the
+    /// same as those of `classify`.
+    pub fn classify_batch(&self, features: &ArrayView2<f32>, k: usize) -> Vec<usize>
+    where
+        S: Sync,
+    {
+        self.search
+            .k_nearest_batch(features, k, |nearest_neighbors| {
+                self.vote(nearest_neighbors)
+            })
+    }
+
+    /// Classify a data point by the training instances within a radius.
+    ///
+    /// All training instances within distance `radius` of the data point
+    /// vote, using the neighbor weights and tie breaking of the model.
+    /// Distances are computed with the metric of the model, after feature
+    /// scaling. When there are no training instances within the radius,
+    /// the outlier label is predicted. Fails if the model does not have
+    /// an outlier label in that case.
+    pub fn classify_radius(&self, features: &[f32], radius: f32) -> Result<usize, Error> {
+        let features = ArrayView1::from_shape([features.len()], features).unwrap();
+        self.vote_radius(&self.search.within_radius(features, radius), radius)
+    }
+
+    /// Classify a batch of data points by the training instances within
+    /// a radius.
+    ///
+    /// Every row of the feature matrix is a data point. The data points
+    /// are classified in parallel, using the global rayon thread pool. The
+    /// predicted classes are returned in the order of the rows. Fails when
+    /// `classify_radius` fails for a data point.
+    pub fn classify_radius_batch(
+        &self,
+        features: &ArrayView2<f32>,
+        radius: f32,
+    ) -> Result<Vec<usize>, Error>
+    where
+        S: Sync,
+    {
+        self.search
+            .within_radius_batch(features, radius, |neighbors| {
+                self.vote_radius(neighbors, radius)
+            })
+            .into_iter()
+            .collect()
+    }
+
+    fn vote_radius(&self, neighbors: &[Neighbor], radius: f32) -> Result<usize, Error> {
+        if neighbors.is_empty() {
+            self.outlier_label.ok_or_else(|| {
+                err_msg(format!(
+                    "No training instances within radius {} and no outlier label",
+                    radius
+                ))
+            })
+        } else {
+            Ok(self.vote(neighbors))
+        }
+    }
+
+    /// Classify a batch of data points for several numbers of nearest
+    /// neighbors and neighbor weightings.
+    ///
+    /// The nearest neighbors of every data point are searched once, for
+    /// the largest k. The predictions for smaller k use the nearest of
+    /// these neighbors. The predictions are indexed by weighting, k, and
+    /// data point, and are the same as those of `classify_batch` with the
+    /// corresponding weighting and k.
+    pub fn classify_batch_grid(
+        &self,
+        features: &ArrayView2<f32>,
+        ks: &[usize],
+        weights: &[Weights],
+    ) -> Vec<Vec<Vec<usize>>>
+    where
+        S: Sync,
+    {
+        let max_k = *ks.iter().max().expect("At least one k should be given");
+        assert!(ks.iter().all(|&k| k > 0), "k should at least be 1");
+
+        let predictions = self
+            .search
+            .k_nearest_batch(features, max_k, |nearest_neighbors| {
+                weights
+                    .iter()
+                    .flat_map(|&weights| {
+                        ks.iter().map(move |&k| {
+                            let k = k.min(nearest_neighbors.len());
+                            vote(
+                                &self.labels,
+                                Some(&self.ids),
+                                weights,
+                                self.tie_breaking,
+                                &nearest_neighbors[..k],
+                            )
+                        })
+                    })
+                    .collect::<Vec<_>>()
+            });
+
+        (0..weights.len())
+            .map(|weights_idx| {
+                (0..ks.len())
+                    .map(|k_idx| {
+                        predictions
+                            .iter()
+                            .map(|instance| instance[weights_idx * ks.len() + k_idx])
+                            .collect()
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Find the k nearest training instances of a data point.
+    ///
+    /// The neighbors are sorted by distance, ties are ordered by ID.
+    /// Distances are computed with the metric of the model, after feature
+    /// scaling. Fewer than k neighbors are returned when the model has
+    /// fewer than k instances.
+    pub fn kneighbors(&self, features: &[f32], k: usize) -> Vec<TrainingNeighbor> {
+        let features = ArrayView1::from_shape([features.len()], features).unwrap();
+        self.training_neighbors(&self.search.k_nearest(features, k))
+    }
+
+    /// Find the k nearest training instances of a batch of data points.
+    ///
+    /// Every row of the feature matrix is a data point. The neighbors of
+    /// every data point, as found by `kneighbors`, are returned in the
+    /// order of the rows.
+    pub fn kneighbors_batch(
+        &self,
+        features: &ArrayView2<f32>,
+        k: usize,
+    ) -> Vec<Vec<TrainingNeighbor>>
+    where
+        S: Sync,
+    {
+        self.search
+            .k_nearest_batch(features, k, |nearest_neighbors| {
+                self.training_neighbors(nearest_neighbors)
+            })
+    }
+
+    fn training_neighbors(&self, nearest_neighbors: &[Neighbor]) -> Vec<TrainingNeighbor> {
+        nearest_neighbors
+            .iter()
+            .map(|neighbor| TrainingNeighbor {
+                id: self.ids[neighbor.idx],
+                label: self.labels[neighbor.idx],
+                distance: neighbor.distance.into_inner(),
+            })
+            .collect()
+    }
+
+    fn vote(&self, nearest_neighbors: &[Neighbor]) -> usize {
+        vote(
+            &self.labels,
+            Some(&self.ids),
+            self.weights,
+            self.tie_breaking,
+            nearest_neighbors,
+        )
+    }
+
+    /// Estimate the class probabilities of a data point.
+    ///
+    /// The probability of a class is its share of the (weighted) votes of
+    /// the k nearest neighbors, so distance-weighted probabilities are
+    /// obtained by setting the neighbor weights. With additive (Laplace)
+    /// smoothing `alpha`, the probability of class `c` is
+    /// `(w_c + alpha) / (w + alpha * n_classes)`, where `w_c` is the vote
+    /// of the class and `w` the sum of the votes. The probabilities are
+    /// indexed by label and sum to one.
+    pub fn predict_proba(&self, features: &[f32], k: usize, alpha: f32) -> Vec<f32> {
+        let features = ArrayView1::from_shape([features.len()], features).unwrap();
+        self.probabilities(&self.search.k_nearest(features, k), alpha)
+    }
+
+    /// Estimate the class probabilities of a batch of data points.
+    ///
+    /// Every row of the feature matrix is a data point. Returns a matrix
+    /// with the class probabilities of every data point, as computed by
+    /// `predict_proba`, in its rows.
+    pub fn predict_proba_batch(
+        &self,
+        features: &ArrayView2<f32>,
+        k: usize,
+        alpha: f32,
+    ) -> Array2<f32>
+    where
+        S: Sync,
+    {
+        let probabilities = self
+            .search
+            .k_nearest_batch(features, k, |nearest_neighbors| {
+                self.probabilities(nearest_neighbors, alpha)
+            });
+
+        Array2::from_shape_vec(
+            (features.rows(), self.n_classes),
+            probabilities.into_iter().flatten().collect(),
+        )
+        .expect("Number of elements does not correspond to the shape")
+    }
+
+    fn probabilities(&self, nearest_neighbors: &[Neighbor], alpha: f32) -> Vec<f32> {
+        assert!(
+            alpha >= 0.,
+            "The smoothing parameter should be non-negative, was: {}",
+            alpha
+        );
+
+        let mut probabilities = vec![alpha; self.n_classes];
+        for (label, weight) in label_weights(&self.labels, self.weights, nearest_neighbors) {
+            probabilities[label] += weight;
+        }
+
+        let total: f32 = probabilities.iter().sum();
+        for probability in &mut probabilities {
+            *probability /= total;
+        }
+
+        probabilities
+    }
+
+    /// Compute anomaly scores for a batch of data points.
+    ///
+    /// Every row of the feature matrix is a data point. The scores are
+    /// computed from the k nearest training instances, see `AnomalyScore`,
+    /// and are returned in the order of the rows. Higher scores are more
+    /// anomalous.
+    ///
+    /// The Local Outlier Factor requires the nearest neighbors of every
+    /// training instance. These are searched on the first call with a
+    /// given k and reused until instances are inserted or removed. It
+    /// also requires the model to have more than k instances.
+    pub fn anomaly_scores(
+        &self,
+        features: &ArrayView2<f32>,
+        k: usize,
+        score: AnomalyScore,
+    ) -> Vec<f32>
+    where
+        S: Sync,
+    {
+        let densities = if score == AnomalyScore::LocalOutlierFactor {
+            assert!(
+                k < self.len(),
+                "The Local Outlier Factor requires more than k training instances"
+            );
+            Some(self.densities(k))
+        } else {
+            None
+        };
+
+        self.search
+            .k_nearest_batch(features, k, |nearest_neighbors| {
+                score.score(nearest_neighbors, densities.as_deref())
+            })
+    }
+
+    /// Get the densities of the training instances for k neighbors.
+    ///
+    /// The densities of the last k are cached.
+    fn densities(&self, k: usize) -> Arc<Densities>
+    where
+        S: Sync,
+    {
+        let mut cached = self.densities.lock().expect("Densities lock is poisoned");
+        if let Some((cached_k, ref densities)) = *cached {
+            if cached_k == k {
+                return densities.clone();
+            }
+        }
+
+        let densities = Arc::new(Densities::new(&self.search.k_nearest_others(k)));
+        *cached = Some((k, densities.clone()));
+        densities
+    }
+
+    /// Compute the recall of the nearest neighbor search for a data point.
+    ///
+    /// Returns the fraction of the k nearest neighbors found by brute
+    /// force search that are also found by the index. The recall is
+    /// always 1 for exact search strategies.
+    pub fn recall(&self, features: &[f32], k: usize) -> f32 {
+        let features = ArrayView1::from_shape([features.len()], features).unwrap();
+        self.search.recall(features, k)
+    }
+}
+
+/// Get the label with the highest summed weight among the nearest
+/// neighbors.
+///
+/// Ties between labels are broken using the given tie breaking. Random
+/// tie breaking is seeded with the IDs of the neighbors, or with their
+/// indices when the training instances do not have IDs.
+pub(crate) fn vote(
+    labels: &[usize],
+    ids: Option<&[usize]>,
+    weights: Weights,
+    tie_breaking: TieBreaking,
+    nearest_neighbors: &[Neighbor],
+) -> usize {
+    let label_weights = label_weights(labels, weights, nearest_neighbors);
+    let max_weight = label_weights
+        .values()
+        .cloned()
+        .fold(f32::NEG_INFINITY, f32::max);
+
+    // The tied labels, in increasing order.
+    let tied: Vec<_> = label_weights
+        .into_iter()
+        .filter(|&(_, weight)| weight == max_weight)
+        .map(|(label, _)| label)
+        .collect();
+    match tied.len() {
+        // All weights are NaN, predict the label of the nearest neighbor.
+        0 => {
+            return labels[nearest_neighbors
+                .first()
+                .expect("There should be at least one neighbor")
+                .idx]
+        }
+        1 => return tied[0],
+        _ => (),
+    }
+
+    match tie_breaking {
+        TieBreaking::SmallestLabel => tied[0],
+        TieBreaking::NearestNeighbor => nearest_neighbors
+            .iter()
+            .map(|neighbor| labels[neighbor.idx])
+            .find(|label| tied.contains(label))
+            .unwrap(),
+        TieBreaking::SmallestDistance => *tied
+            .iter()
+            .min_by_key(|&&label| {
+                OrderedFloat(
+                    nearest_neighbors
+                        .iter()
+                        .filter(|neighbor| labels[neighbor.idx] == label)
+                        .map(|neighbor| neighbor.distance.into_inner())
+                        .sum::<f32>(),
+                )
+            })
+            .unwrap(),
+        TieBreaking::Random { seed } => {
+            // Mix the neighbors into the seed, so that the choice does not
+            // depend on the order in which data points are classified.
+            let seed = nearest_neighbors.iter().fold(seed, |seed, neighbor| {
+                let id = ids.map_or(neighbor.idx, |ids| ids[neighbor.idx]);
+                (seed ^ id as u64).wrapping_mul(0x0100_0000_01b3)
+            });
+            tied[seeded_rng(seed).gen_range(0, tied.len())]
+        }
+        TieBreaking::DecreaseK => vote(
+            labels,
+            ids,
+            weights,
+            tie_breaking,
+            &nearest_neighbors[..nearest_neighbors.len() - 1],
+        ),
+    }
+}
+
+/// Sum the weights of the labels among the nearest neighbors.
+///
+/// A BTreeMap is used to ensure stable results.
+fn label_weights(
+    labels: &[usize],
+    weights: Weights,
+    nearest_neighbors: &[Neighbor],
+) -> BTreeMap<usize, f32> {
+    let weights = weights.neighbor_weights(nearest_neighbors);
+
+    let mut label_weights = BTreeMap::new();
+    for (neighbor, weight) in nearest_neighbors.iter().zip(weights) {
+        *label_weights.entry(labels[neighbor.idx]).or_insert(0.) += weight;
+    }
+
+    label_weights
+}
+
+/// Get the number of classes from the largest label.
+fn n_classes(labels: &[usize]) -> usize {
+    labels.iter().max().map_or(0, |&label| label + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::ArrayView1;
+
+    use index::tests::{feature_matrix, read_instances};
+    use {
+        Covariance, HnswParams, IndexType, Instance, KNNBuilder, LabelDictionary, LshParams,
+        Metric, Metrics, Scaler, Scaling, TieBreaking, TrainingNeighbor, Weights, KNN,
+    };
+
+    fn moons_model(index_type: IndexType, metric: Metrics) -> KNN {
+        let mut builder = KNNBuilder::default();
+        builder.set_index_type(index_type);
+        builder.set_metric(metric);
+        for instance in read_instances(include_str!("../moons-train.txt")) {
+            builder.push(instance);
+        }
+        builder.into()
+    }
+
+    #[test]
+    fn classify_batch_test() {
+        let test = read_instances(include_str!("../moons-test.txt"));
+        let test_features = feature_matrix(&test);
+
+        for &index_type in &[IndexType::BruteForce, IndexType::KdTree] {
+            let mut builder = KNNBuilder::default();
+            builder.set_index_type(index_type);
+            for instance in read_instances(include_str!("../moons-train.txt")) {
+                builder.push(instance);
+            }
+            let model: KNN = builder.into();
+
+            let sequential: Vec<_> = test
+                .iter()
+                .map(|instance| model.classify(&instance.features, 5))
+                .collect();
+            assert_eq!(model.classify_batch(&test_features.view(), 5), sequential);
+        }
+    }
+
+    #[test]
+    fn classify_batch_grid_test() {
+        let test = feature_matrix(&read_instances(include_str!("../moons-test.txt")));
+        let ks = [7, 1, 3];
+        let weights = [Weights::Uniform, Weights::InverseDistance];
+
+        let mut model = moons_model(IndexType::KdTree, Metrics::Euclidean);
+        let grid = model.classify_batch_grid(&test.view(), &ks, &weights);
+        for (&weights, predictions) in weights.iter().zip(grid) {
+            model.set_weights(weights);
+            for (&k, predictions) in ks.iter().zip(predictions) {
+                assert_eq!(predictions, model.classify_batch(&test.view(), k));
+            }
+        }
+    }
+
+    #[test]
+    fn weighted_vote_test() {
+        let mut builder = KNNBuilder::default();
+        for &(x, label) in &[(0., 0), (0.9, 1), (1.1, 1)] {
+            builder.push(Instance {
+                label,
+                features: vec![x],
+            });
+        }
+        let mut model: KNN = builder.into();
+
+        // Two votes for label 1 against one for label 0.
+        assert_eq!(model.classify(&[0.2], 3), 1);
+
+        // Label 0 is much closer: 1/0.2 = 5 against 1/0.7 + 1/0.9.
+        model.set_weights(Weights::InverseDistance);
+        assert_eq!(model.classify(&[0.2], 3), 0);
+
+        // An exact match determines the label.
+        model.set_weights(Weights::InverseSquaredDistance);
+        assert_eq!(model.classify(&[0.9], 3), 1);
+        assert_eq!(model.classify(&[0.], 3), 0);
+
+        // A narrow kernel favors the closest neighbor.
+        model.set_weights(Weights::Gaussian { bandwidth: 0.1 });
+        assert_eq!(model.classify(&[0.3], 3), 0);
+    }
+
+    #[test]
+    fn predict_proba_test() {
+        let mut builder = KNNBuilder::default();
+        for &(x, label) in &[(0., 0), (0.9, 1), (1.1, 1), (5., 2)] {
+            builder.push(Instance {
+                label,
+                features: vec![x],
+            });
+        }
+        let mut model: KNN = builder.into();
+        assert_eq!(model.n_classes(), 3);
+
+        assert_eq!(
+            model.predict_proba(&[0.2], 3, 0.),
+            vec![1. / 3., 2. / 3., 0.]
+        );
+
+        // Laplace smoothing gives unseen classes a probability.
+        assert_eq!(
+            model.predict_proba(&[0.2], 3, 1.),
+            vec![2. / 6., 3. / 6., 1. / 6.]
+        );
+
+        model.set_weights(Weights::InverseDistance);
+        let probabilities = model.predict_proba(&[0.2], 3, 0.);
+        let (w0, w1) = (1. / 0.2, 1. / 0.7 + 1. / 0.9);
+        assert_abs_diff_eq!(probabilities[0], w0 / (w0 + w1), epsilon = 1e-6);
+        assert_abs_diff_eq!(probabilities[1], w1 / (w0 + w1), epsilon = 1e-6);
+
+        // The class with the highest probability is the prediction.
+        let test = feature_matrix(&read_instances(include_str!("../moons-test.txt")));
+        let model = moons_model(IndexType::KdTree, Metrics::Euclidean);
+        let probabilities = model.predict_proba_batch(&test.view(), 5, 0.5);
+        assert_eq!(probabilities.shape(), &[test.rows(), 2]);
+        for ((row, probabilities), predicted) in test
+            .outer_iter()
+            .zip(probabilities.outer_iter())
+            .zip(model.classify_batch(&test.view(), 5))
+        {
+            assert_abs_diff_eq!(probabilities.scalar_sum(), 1., epsilon = 1e-6);
+            assert_eq!(
+                probabilities.to_vec(),
+                model.predict_proba(row.as_slice().unwrap(), 5, 0.5)
+            );
+            assert!(probabilities[predicted] >= probabilities[1 - predicted]);
+        }
+    }
+
+    #[test]
+    fn tie_breaking_test() {
+        let mut builder = KNNBuilder::default();
+        for &(x, label) in &[(-1., 2), (2., 1), (-3., 1), (4.5, 2), (10., 0)] {
+            builder.push(Instance {
+                label,
+                features: vec![x],
+            });
+        }
+        let mut model: KNN = builder.into();
+        assert_eq!(model.tie_breaking(), TieBreaking::SmallestLabel);
+
+        // Labels 1 and 2 both have two of the four nearest neighbors. The
+        // nearest neighbor has label 2, but label 1 has the smallest
+        // summed distance (2 + 3 against 1 + 4.5).
+        assert_eq!(model.classify(&[0.], 4), 1);
+        model.set_tie_breaking(TieBreaking::NearestNeighbor);
+        assert_eq!(model.classify(&[0.], 4), 2);
+        model.set_tie_breaking(TieBreaking::SmallestDistance);
+        assert_eq!(model.classify(&[0.], 4), 1);
+        model.set_tie_breaking(TieBreaking::DecreaseK);
+        assert_eq!(model.classify(&[0.], 4), 1);
+        assert_eq!(model.classify(&[0.], 2), 2);
+
+        model.set_tie_breaking(TieBreaking::Random { seed: 42 });
+        let label = model.classify(&[0.], 4);
+        assert!(label == 1 || label == 2);
+        assert_eq!(model.classify(&[0.], 4), label);
+
+        // Different seeds make different choices.
+        let labels: Vec<_> = (0..20)
+            .map(|seed| {
+                model.set_tie_breaking(TieBreaking::Random { seed });
+                model.classify(&[0.], 4)
+            })
+            .collect();
+        assert!(labels.contains(&1) && labels.contains(&2));
+
+        // Random choices do not change when instances are renumbered by
+        // compaction. Every query is between two instances with
+        // different labels.
+        let mut builder = KNNBuilder::default();
+        builder.push(Instance {
+            label: 0,
+            features: vec![-1000.],
+        });
+        for i in 0..20 {
+            for &(offset, label) in &[(-1., 0), (1., 1)] {
+                builder.push(Instance {
+                    label,
+                    features: vec![10. * i as f32 + offset],
+                });
+            }
+        }
+        let mut model: KNN = builder.into();
+        model.set_tie_breaking(TieBreaking::Random { seed: 42 });
+        let classify_all = |model: &KNN| -> Vec<_> {
+            (0..20)
+                .map(|i| model.classify(&[10. * i as f32], 2))
+                .collect()
+        };
+        let before = classify_all(&model);
+        model.remove(0);
+        model.compact();
+        assert_eq!(classify_all(&model), before);
+    }
+
+    #[test]
+    fn custom_metric_test() {
+        // Distance that only considers the first feature.
+        struct FirstFeature;
+
+        impl Metric for FirstFeature {
+            fn distance(&self, v1: ArrayView1<f32>, v2: ArrayView1<f32>) -> f32 {
+                (v1[0] - v2[0]).abs()
+            }
+        }
+
+        // The default KD-tree does not support the metric, so brute force
+        // search is used.
+        let mut builder = KNNBuilder::default();
+        for &(x, y, label) in &[(0., 0., 0), (1., 10., 1)] {
+            builder.push(Instance {
+                label,
+                features: vec![x, y],
+            });
+        }
+        builder.set_metric(Metrics::custom(FirstFeature));
+        let model: KNN = builder.into();
+
+        assert_eq!(model.classify(&[0.9, 0.], 1), 1);
+        assert_eq!(model.classify(&[0.1, 10.], 1), 0);
+    }
+
+    #[test]
+    fn scaling_test() {
+        let train = read_instances(include_str!("../moons-train.txt"));
+        let test = feature_matrix(&read_instances(include_str!("../moons-test.txt")));
+
+        // Stretch the second feature, so that scaling matters.
+        let stretch = |mut instance: Instance| {
+            instance.features[1] *= 100.;
+            instance
+        };
+        let mut stretched_test = test.clone();
+        stretched_test.column_mut(1).mapv_inplace(|v| v * 100.);
+
+        for &scaling in &[
+            Scaling::Standard,
+            Scaling::MinMax,
+            Scaling::Robust,
+            Scaling::L2,
+        ] {
+            let mut builder = KNNBuilder::default();
+            builder.set_scaling(scaling);
+            for instance in train.iter().cloned().map(stretch) {
+                builder.push(instance);
+            }
+            let model: KNN = builder.into();
+
+            // The model is equivalent to a model that is trained and
+            // applied on scaled data.
+            let train_features =
+                feature_matrix(&train.iter().cloned().map(stretch).collect::<Vec<_>>());
+            let scaler = Scaler::fit(train_features.view(), scaling);
+            let mut scaled_builder = KNNBuilder::default();
+            for (instance, features) in train
+                .iter()
+                .zip(scaler.transform_rows(train_features.view()).outer_iter())
+            {
+                scaled_builder.push(Instance {
+                    label: instance.label,
+                    features: features.to_vec(),
+                });
+            }
+            let scaled_model: KNN = scaled_builder.into();
+
+            let predictions = model.classify_batch(&stretched_test.view(), 5);
+            assert_eq!(
+                predictions,
+                scaled_model
+                    .classify_batch(&scaler.transform_rows(stretched_test.view()).view(), 5)
+            );
+
+            let mut data = Vec::new();
+            model.save(&mut data).unwrap();
+            let loaded = KNN::load(&data[..]).unwrap();
+            assert_eq!(
+                loaded.classify_batch(&stretched_test.view(), 5),
+                predictions
+            );
+        }
+    }
+
+    #[test]
+    fn save_load_test() {
+        let test = feature_matrix(&read_instances(include_str!("../moons-test.txt")));
+
+        let mahalanobis = {
+            let mut builder = KNNBuilder::default();
+            for instance in read_instances(include_str!("../moons-train.txt")) {
+                builder.push(instance);
+            }
+            builder.fit_mahalanobis(Covariance::LedoitWolf).unwrap()
+        };
+
+        let mut names = LabelDictionary::default();
+        names.encode("upper");
+        names.encode("lower");
+
+        for &(index_type, ref metric) in &[
+            (IndexType::BruteForce, Metrics::Cosine),
+            (IndexType::KdTree, Metrics::Manhattan),
+            (IndexType::BallTree, Metrics::Mahalanobis(mahalanobis)),
+            (IndexType::Hnsw(HnswParams::default()), Metrics::Euclidean),
+            (IndexType::Lsh(LshParams::default()), Metrics::Cosine),
+        ] {
+            let mut model = moons_model(index_type, metric.clone());
+            model.set_weights(Weights::Gaussian { bandwidth: 0.5 });
+            model.set_tie_breaking(TieBreaking::Random { seed: 7 });
+            model.set_outlier_label(Some(1));
+            model.set_label_names(names.clone());
+
+            let mut data = Vec::new();
+            model.save(&mut data).unwrap();
+            let loaded = KNN::load(&data[..]).unwrap();
+            assert_eq!(loaded.label_names(), Some(&names));
+            assert_eq!(loaded.tie_breaking(), TieBreaking::Random { seed: 7 });
+            assert_eq!(loaded.outlier_label(), Some(1));
+
+            assert_eq!(
+                loaded.classify_batch(&test.view(), 5),
+                model.classify_batch(&test.view(), 5)
+            );
+
+            // Saving the loaded model gives the same file.
+            let mut resaved = Vec::new();
+            loaded.save(&mut resaved).unwrap();
+            assert_eq!(resaved, data);
+        }
+    }
+
+    #[test]
+    fn insert_remove_test() {
+        let train = read_instances(include_str!("../moons-train.txt"));
+        let test = feature_matrix(&read_instances(include_str!("../moons-test.txt")));
+        let (initial, extra) = train.split_at(train.len() / 2);
+
+        for &index_type in &[
+            IndexType::BruteForce,
+            IndexType::KdTree,
+            IndexType::BallTree,
+        ] {
+            let mut builder = KNNBuilder::default();
+            builder.set_index_type(index_type);
+            for instance in initial.iter().cloned() {
+                builder.push(instance);
             }
+            let mut model: KNN = builder.into();
+
+            // Insert the remaining instances and remove every third instance.
+            let ids: Vec<_> = extra
+                .iter()
+                .cloned()
+                .map(|instance| model.insert(instance))
+                .collect();
+            assert_eq!(ids, (initial.len()..train.len()).collect::<Vec<_>>());
+            for id in (0..train.len()).step_by(3) {
+                assert!(model.remove(id));
+            }
+            assert!(!model.remove(0));
+            assert!(!model.remove(train.len()));
+            assert!(!model.contains(0));
+            assert!(model.contains(1));
+
+            // The model behaves like a model of the remaining instances.
+            let mut builder = KNNBuilder::default();
+            builder.set_index_type(index_type);
+            for (_, instance) in train
+                .iter()
+                .cloned()
+                .enumerate()
+                .filter(|&(id, _)| id % 3 != 0)
+            {
+                builder.push(instance);
+            }
+            let expected: KNN = builder.into();
+            assert_eq!(model.len(), expected.len());
+            assert_eq!(
+                model.classify_batch(&test.view(), 5),
+                expected.classify_batch(&test.view(), 5)
+            );
+
+            // Models must be compacted before saving.
+            assert!(model.save(Vec::new()).is_err());
+            model.compact();
+            assert_eq!(
+                model.classify_batch(&test.view(), 5),
+                expected.classify_batch(&test.view(), 5)
+            );
+
+            // IDs are preserved by saving.
+            let mut data = Vec::new();
+            model.save(&mut data).unwrap();
+            let mut loaded = KNN::load(&data[..]).unwrap();
+            assert!(!loaded.contains(3));
+            assert!(loaded.remove(4));
+            assert_eq!(loaded.len(), expected.len() - 1);
+            assert_eq!(loaded.insert(train[0].clone()), train.len());
+        }
+    }
+
+    #[test]
+    fn nan_weights_test() {
+        // The squared bandwidth underflows, so neighbors at distance 0
+        // have NaN weights.
+        let mut builder = KNNBuilder::default();
+        for &label in &[1, 0] {
+            builder.push(Instance {
+                label,
+                features: vec![0.],
+            });
+        }
+        let mut model: KNN = builder.into();
+        model.set_weights(Weights::Gaussian { bandwidth: 1e-30 });
+        assert_eq!(model.classify(&[0.], 2), 1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn invalid_bandwidth_test() {
+        moons_model(IndexType::BruteForce, Metrics::Euclidean)
+            .set_weights(Weights::Gaussian { bandwidth: 0. });
+    }
+
+    #[test]
+    fn kneighbors_test() {
+        let mut builder = KNNBuilder::default();
+        for &(x, label) in &[(0., 0), (1., 0), (3., 1), (4., 1)] {
+            builder.push(Instance {
+                label,
+                features: vec![x],
+            });
+        }
+        let mut model: KNN = builder.into();
+
+        let neighbor = |id, label, distance| TrainingNeighbor {
+            id,
+            label,
+            distance,
+        };
+        assert_eq!(
+            model.kneighbors(&[2.], 3),
+            vec![neighbor(1, 0, 1.), neighbor(2, 1, 1.), neighbor(0, 0, 2.)]
+        );
+        assert_eq!(model.kneighbors(&[2.], 10).len(), 4);
+
+        // Neighbors are reported by their stable IDs.
+        model.remove(1);
+        model.insert(Instance {
+            label: 1,
+            features: vec![2.5],
+        });
+        assert_eq!(
+            model.kneighbors(&[2.], 2),
+            vec![neighbor(4, 1, 0.5), neighbor(2, 1, 1.)]
+        );
+
+        let test = feature_matrix(&read_instances(include_str!("../moons-test.txt")));
+        let model = moons_model(IndexType::KdTree, Metrics::Euclidean);
+        let batch = model.kneighbors_batch(&test.view(), 5);
+        for (instance, neighbors) in test.outer_iter().zip(batch) {
+            assert_eq!(model.kneighbors(instance.as_slice().unwrap(), 5), neighbors);
+        }
+    }
+
+    #[test]
+    fn classify_radius_test() {
+        let mut builder = KNNBuilder::default();
+        for &(x, label) in &[(0., 0), (1., 0), (3., 1), (4., 1), (4.5, 1)] {
+            builder.push(Instance {
+                label,
+                features: vec![x],
+            });
         }
+        let mut model: KNN = builder.into();
 
-        // Count the labels among the nearest neighbors. A BTreeMap
-        // is used to ensure stable results.
-        let mut label_counts = BTreeMap::new();
-        for neighbor in nearest_neighbors {
-            let count = label_counts.entry(neighbor.label).or_insert(0);
-            *count += 1;
+        assert_eq!(model.classify_radius(&[0.5], 1.).unwrap(), 0);
+        assert_eq!(model.classify_radius(&[2.], 1.).unwrap(), 0);
+        assert_eq!(model.classify_radius(&[2.], 2.5).unwrap(), 1);
+        assert!(model.classify_radius(&[10.], 1.).is_err());
+
+        model.set_outlier_label(Some(2));
+        assert_eq!(model.classify_radius(&[10.], 1.).unwrap(), 2);
+
+        // Inserted and removed instances are taken into account.
+        model.remove(0);
+        model.remove(1);
+        assert_eq!(model.classify_radius(&[0.5], 1.).unwrap(), 2);
+        model.insert(Instance {
+            label: 0,
+            features: vec![0.],
+        });
+        assert_eq!(model.classify_radius(&[0.5], 1.).unwrap(), 0);
+
+        // The exact search strategies find the same neighbors.
+        let test = feature_matrix(&read_instances(include_str!("../moons-test.txt")));
+        let expected = moons_model(IndexType::BruteForce, Metrics::Euclidean)
+            .classify_radius_batch(&test.view(), 1.)
+            .unwrap();
+        for &index_type in &[IndexType::KdTree, IndexType::BallTree] {
+            let model = moons_model(index_type, Metrics::Euclidean);
+            let predicted = model.classify_radius_batch(&test.view(), 1.).unwrap();
+            assert_eq!(predicted, expected);
+            for (instance, &label) in test.outer_iter().zip(&predicted) {
+                assert_eq!(
+                    model
+                        .classify_radius(instance.as_slice().unwrap(), 1.)
+                        .unwrap(),
+                    label
+                );
+            }
         }
 
-        // Get the most frequent label.
-        *label_counts.iter().max_by_key(|kv| kv.1).unwrap().0
+        assert!(moons_model(IndexType::KdTree, Metrics::Euclidean)
+            .classify_radius_batch(&test.view(), 0.)
+            .is_err());
+    }
+
+    #[test]
+    fn load_invalid_test() {
+        let model = moons_model(IndexType::KdTree, Metrics::Euclidean);
+        let mut data = Vec::new();
+        model.save(&mut data).unwrap();
+
+        assert!(KNN::load(&data[..data.len() / 2]).is_err());
+
+        let mut corrupted = data.clone();
+        let mid = corrupted.len() / 2;
+        corrupted[mid] ^= 0x10;
+        assert!(KNN::load(&corrupted[..]).is_err());
+
+        assert!(KNN::load(&include_bytes!("../moons-train.txt")[..]).is_err());
+
+        let custom = moons_model(IndexType::BruteForce, Metrics::custom(Metrics::Manhattan));
+        assert!(custom.save(Vec::new()).is_err());
     }
 }
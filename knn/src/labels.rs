@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+use std::io::Write;
+
+use failure::Error;
+
+use serialization::{invalid_model, ModelReader, ModelWriter};
+
+/// A dictionary that maps class names to class labels.
+///
+/// Classes are numbered in the order in which they are first encoded.
+/// The dictionary can be stored in a model, so that predictions can be
+/// reported using the original class names.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LabelDictionary {
+    names: Vec<String>,
+    labels: HashMap<String, usize>,
+}
+
+impl LabelDictionary {
+    /// Get the label of a class name, adding the name if it is unknown.
+    pub fn encode(&mut self, name: &str) -> usize {
+        if let Some(&label) = self.labels.get(name) {
+            return label;
+        }
+
+        let label = self.names.len();
+        self.names.push(name.to_owned());
+        self.labels.insert(name.to_owned(), label);
+        label
+    }
+
+    /// Get the label of a class name.
+    pub fn label(&self, name: &str) -> Option<usize> {
+        self.labels.get(name).cloned()
+    }
+
+    /// Get the class name of a label.
+    pub fn name(&self, label: usize) -> Option<&str> {
+        self.names.get(label).map(String::as_str)
+    }
+
+    /// Get the number of classes.
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    /// Check whether the dictionary is empty.
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    pub(crate) fn write<W>(&self, writer: &mut ModelWriter<W>) -> Result<(), Error>
+    where
+        W: Write,
+    {
+        writer.write_usize(self.names.len())?;
+        for name in &self.names {
+            writer.write_str(name)?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn read(reader: &mut ModelReader) -> Result<Self, Error> {
+        let n_names = reader.read_len(8)?;
+
+        let mut dictionary = LabelDictionary::default();
+        for _ in 0..n_names {
+            let name = reader.read_str()?;
+            if dictionary.label(name).is_some() {
+                return Err(invalid_model("duplicate class name"));
+            }
+            dictionary.encode(name);
+        }
+
+        Ok(dictionary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serialization::{ModelReader, ModelWriter};
+    use LabelDictionary;
+
+    #[test]
+    fn label_dictionary_test() {
+        let mut dictionary = LabelDictionary::default();
+        assert!(dictionary.is_empty());
+        assert_eq!(dictionary.encode("spam"), 0);
+        assert_eq!(dictionary.encode("ham"), 1);
+        assert_eq!(dictionary.encode("spam"), 0);
+
+        assert_eq!(dictionary.len(), 2);
+        assert_eq!(dictionary.label("ham"), Some(1));
+        assert_eq!(dictionary.label("eggs"), None);
+        assert_eq!(dictionary.name(0), Some("spam"));
+        assert_eq!(dictionary.name(2), None);
+    }
+
+    #[test]
+    fn duplicate_names_test() {
+        for names in &[&["a", "b", "a"][..], &["a", "a"]] {
+            let mut data = Vec::new();
+            let mut writer = ModelWriter::new(&mut data).unwrap();
+            writer.write_usize(names.len()).unwrap();
+            for name in names.iter() {
+                writer.write_str(name).unwrap();
+            }
+            writer.finish().unwrap();
+
+            let mut reader = ModelReader::new(&data).unwrap();
+            assert!(LabelDictionary::read(&mut reader).is_err());
+        }
+    }
+}
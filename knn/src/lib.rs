@@ -0,0 +1,88 @@
+#[cfg(test)]
+#[macro_use]
+extern crate approx;
+extern crate byteorder;
+extern crate csv;
+extern crate failure;
+extern crate memmap;
+#[macro_use]
+extern crate ndarray;
+extern crate num_traits;
+extern crate ordered_float;
+extern crate rand;
+extern crate rayon;
+
+mod anomaly;
+pub use anomaly::{contamination_threshold, AnomalyScore};
+
+mod balltree;
+
+mod batch;
+
+mod cross_validation;
+pub use cross_validation::{k_fold, stratified_k_fold, train_test_split};
+
+mod csv_reader;
+pub use csv_reader::{Column, CsvIter, CsvOptions};
+
+pub mod distance;
+pub use distance::{
+    Chebyshev, Cosine, Euclidean, Manhattan, Metric, Metrics, Minkowski, SquaredEuclidean,
+};
+
+mod evaluation;
+pub use evaluation::{Evaluator, RegressionEvaluator};
+
+mod hnsw;
+pub use hnsw::HnswParams;
+
+mod index;
+pub use index::IndexType;
+
+mod instance;
+pub use instance::{
+    Instance, InstanceIter, LabeledInstanceIter, RegressionInstance, RegressionInstanceIter,
+    SparseInstance, SvmLightIter,
+};
+
+mod kdtree;
+
+mod lsh;
+pub use lsh::LshParams;
+
+mod labels;
+pub use labels::LabelDictionary;
+
+mod mahalanobis;
+pub use mahalanobis::{Covariance, Mahalanobis};
+
+mod knn;
+pub use knn::{KNNBuilder, KNNView, TrainingNeighbor, KNN};
+
+mod mmap;
+pub use mmap::MmapModel;
+
+mod regression;
+pub use regression::KNNRegressor;
+
+mod reduction;
+pub use reduction::Reduction;
+
+mod scaling;
+pub use scaling::{Scaler, Scaling};
+
+mod search;
+
+mod serialization;
+
+mod sparse;
+pub use sparse::{SparseMetric, SparseVector, SparseView};
+
+mod sparse_knn;
+pub use sparse_knn::{SparseKNN, SparseKNNBuilder};
+
+mod ties;
+pub use ties::TieBreaking;
+
+mod weights;
+pub use weights::Weights;
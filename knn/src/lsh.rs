@@ -0,0 +1,356 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::io::{self, Write};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use failure::Error;
+use ndarray::prelude::*;
+use ordered_float::OrderedFloat;
+use rand::distributions::{IndependentSample, Normal};
+
+use index::{brute_force_k_nearest, seeded_rng, NearestNeighbors, Neighbor};
+use serialization::{invalid_model, ModelReader, ModelWriter};
+use Metric;
+
+/// Hyperparameters of a random hyperplane locality-sensitive hash index.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct LshParams {
+    /// The number of hash tables.
+    pub n_tables: usize,
+
+    /// The number of hyperplanes, and thus signature bits, per table.
+    /// At most 64 bits are supported.
+    pub n_bits: usize,
+
+    /// The number of additional buckets to probe per table. Buckets are
+    /// probed in order of how likely they are to contain neighbors.
+    pub n_probes: usize,
+
+    /// The seed for the generation of hyperplanes.
+    pub seed: u64,
+}
+
+impl Default for LshParams {
+    fn default() -> Self {
+        LshParams {
+            n_tables: 8,
+            n_bits: 12,
+            n_probes: 4,
+            seed: 42,
+        }
+    }
+}
+
+/// A locality-sensitive hash index using random hyperplanes (Charikar, 2002).
+///
+/// Every bit of the signature of an instance records on which side of
+/// a random hyperplane through the origin the instance lies. Instances
+/// with a small angle between them are likely to share a signature, so
+/// the index approximates nearest neighbor search under the cosine
+/// distance. The instances in the probed buckets are re-ranked using the
+/// exact distance.
+pub(crate) struct Lsh {
+    params: LshParams,
+
+    /// Hyperplane normals, `n_bits` consecutive rows per table.
+    hyperplanes: Array2<f32>,
+
+    tables: Vec<HashMap<u64, Vec<usize>>>,
+}
+
+impl Lsh {
+    /// Get the parameters that the index was constructed with.
+    pub fn params(&self) -> LshParams {
+        self.params
+    }
+
+    /// Construct a hash index for the rows of a feature matrix.
+    pub fn new(features: ArrayView2<f32>, params: LshParams) -> Self {
+        assert!(
+            params.n_tables > 0,
+            "The number of tables should at least be 1"
+        );
+        assert!(
+            params.n_bits > 0 && params.n_bits <= 64,
+            "The number of bits should be between 1 and 64"
+        );
+
+        let mut rng = seeded_rng(params.seed);
+        let normal = Normal::new(0., 1.);
+        let hyperplanes =
+            Array2::from_shape_fn((params.n_tables * params.n_bits, features.cols()), |_| {
+                normal.ind_sample(&mut rng) as f32
+            });
+
+        let mut lsh = Lsh {
+            params,
+            hyperplanes,
+            tables: vec![HashMap::new(); params.n_tables],
+        };
+
+        for (idx, instance) in features.outer_iter().enumerate() {
+            let projections = lsh.hyperplanes.dot(&instance);
+            for (table, projections) in lsh
+                .tables
+                .iter_mut()
+                .zip(projections.exact_chunks(params.n_bits))
+            {
+                table
+                    .entry(signature(projections))
+                    .or_insert_with(Vec::new)
+                    .push(idx);
+            }
+        }
+
+        lsh
+    }
+
+    pub fn write<W>(&self, writer: &mut ModelWriter<W>) -> io::Result<()>
+    where
+        W: Write,
+    {
+        writer.write_usize(self.params.n_tables)?;
+        writer.write_usize(self.params.n_bits)?;
+        writer.write_usize(self.params.n_probes)?;
+        writer.write_u64::<LittleEndian>(self.params.seed)?;
+
+        writer.write_f32s(&self.hyperplanes)?;
+
+        for table in &self.tables {
+            // Sort the buckets, so that the same index is always written
+            // in the same way.
+            let mut buckets: Vec<_> = table.iter().collect();
+            buckets.sort_by_key(|&(&signature, _)| signature);
+
+            writer.write_usize(buckets.len())?;
+            for (&signature, bucket) in buckets {
+                writer.write_u64::<LittleEndian>(signature)?;
+                writer.write_usizes(bucket)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Read a hash index for a feature matrix with the given shape.
+    pub fn read(
+        reader: &mut ModelReader,
+        n_instances: usize,
+        n_features: usize,
+    ) -> Result<Self, Error> {
+        let params = LshParams {
+            n_tables: reader.read_usize()?,
+            n_bits: reader.read_usize()?,
+            n_probes: reader.read_usize()?,
+            seed: reader.read_u64()?,
+        };
+        if params.n_tables == 0 || params.n_bits == 0 || params.n_bits > 64 {
+            return Err(invalid_model("invalid LSH parameters"));
+        }
+
+        let n_hyperplanes = params
+            .n_tables
+            .checked_mul(params.n_bits)
+            .ok_or_else(|| invalid_model("invalid LSH parameters"))?;
+        let n_values = n_hyperplanes
+            .checked_mul(n_features)
+            .ok_or_else(|| invalid_model("invalid LSH parameters"))?;
+        let hyperplanes =
+            Array2::from_shape_vec((n_hyperplanes, n_features), reader.read_f32s(n_values)?)
+                .expect("Number of elements does not correspond to the shape");
+
+        let mut tables = Vec::new();
+        for _ in 0..params.n_tables {
+            let n_buckets = reader.read_len(16)?;
+            let mut table = HashMap::with_capacity(n_buckets);
+            for _ in 0..n_buckets {
+                let signature = reader.read_u64()?;
+                table.insert(signature, reader.read_indices(n_instances)?);
+            }
+            tables.push(table);
+        }
+
+        Ok(Lsh {
+            params,
+            hyperplanes,
+            tables,
+        })
+    }
+
+    /// Find the approximate k nearest neighbors of a query point.
+    ///
+    /// When the probed buckets contain fewer than k instances, brute force
+    /// search is used, so that k neighbors are always returned.
+    pub fn k_nearest<M>(
+        &self,
+        features: ArrayView2<f32>,
+        metric: &M,
+        query: ArrayView1<f32>,
+        k: usize,
+    ) -> Vec<Neighbor>
+    where
+        M: Metric,
+    {
+        let mut candidates = Vec::new();
+
+        let projections = self.hyperplanes.dot(&query);
+        for (table, projections) in self
+            .tables
+            .iter()
+            .zip(projections.exact_chunks(self.params.n_bits))
+        {
+            let signature = signature(projections);
+            for mask in probe_masks(projections, self.params.n_probes) {
+                if let Some(bucket) = table.get(&(signature ^ mask)) {
+                    candidates.extend_from_slice(bucket);
+                }
+            }
+        }
+
+        candidates.sort_unstable();
+        candidates.dedup();
+
+        if candidates.len() < k.min(features.rows()) {
+            return brute_force_k_nearest(features, metric, query, k);
+        }
+
+        let mut nearest_neighbors = NearestNeighbors::new(k);
+        for idx in candidates {
+            nearest_neighbors.push(idx, metric.distance(features.row(idx), query));
+        }
+
+        nearest_neighbors.into_sorted_vec()
+    }
+}
+
+/// Compute the signature for the projections onto the hyperplanes of a table.
+fn signature(projections: ArrayView1<f32>) -> u64 {
+    projections
+        .iter()
+        .enumerate()
+        .filter(|&(_, &p)| p >= 0.)
+        .fold(0, |sig, (bit, _)| sig | (1 << bit))
+}
+
+/// Generate the masks of the bits to flip for multi-probe lookup.
+///
+/// The first mask is empty, so that the bucket of the query itself is
+/// probed first. The following masks flip sets of bits, ordered by the
+/// sum of the distances of the query to the corresponding hyperplanes
+/// (Lv et al., 2007). Flipping a bit for which the query is close to the
+/// hyperplane gives a bucket that is likely to contain neighbors.
+fn probe_masks(projections: ArrayView1<f32>, n_probes: usize) -> Vec<u64> {
+    let mut bits: Vec<_> = (0..projections.len()).collect();
+    bits.sort_by_key(|&bit| OrderedFloat(projections[bit].abs()));
+    let scores: Vec<_> = bits.iter().map(|&bit| projections[bit].abs()).collect();
+
+    let mut masks = Vec::with_capacity(n_probes + 1);
+    masks.push(0);
+
+    // Perturbation sets are stored as increasing positions in `bits`.
+    let mut heap = BinaryHeap::new();
+    heap.push((Reverse(OrderedFloat(scores[0])), vec![0]));
+
+    while masks.len() <= n_probes {
+        let (Reverse(OrderedFloat(score)), set) = match heap.pop() {
+            Some(candidate) => candidate,
+            None => break,
+        };
+
+        masks.push(set.iter().fold(0, |mask, &pos| mask | (1 << bits[pos])));
+
+        let last = *set.last().expect("Perturbation sets are not empty");
+        if last + 1 < bits.len() {
+            // Shift: replace the last bit by the next bit.
+            let mut shifted = set.clone();
+            *shifted.last_mut().unwrap() = last + 1;
+            let shifted_score = score - scores[last] + scores[last + 1];
+            heap.push((Reverse(OrderedFloat(shifted_score)), shifted));
+
+            // Expand: add the next bit.
+            let mut expanded = set;
+            expanded.push(last + 1);
+            heap.push((Reverse(OrderedFloat(score + scores[last + 1])), expanded));
+        }
+    }
+
+    masks
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::prelude::*;
+
+    use super::probe_masks;
+    use index::tests::random_matrix;
+    use index::{Index, IndexType};
+    use {Cosine, LshParams};
+
+    #[test]
+    fn probe_masks_test() {
+        let projections = arr1(&[0.5, -0.1, 2.0, -0.3]);
+        assert_eq!(
+            probe_masks(projections.view(), 5),
+            vec![0, 0b0010, 0b1000, 0b1010, 0b0001, 0b0011]
+        );
+    }
+
+    #[test]
+    fn probe_masks_exhausted_test() {
+        let projections = arr1(&[0.5, -0.1]);
+        assert_eq!(probe_masks(projections.view(), 10).len(), 4);
+    }
+
+    fn recall(params: LshParams) -> f32 {
+        let train = random_matrix(1000, 16, 3);
+        let test = random_matrix(100, 16, 4);
+
+        let brute_force = Index::new(IndexType::BruteForce, train.view(), &Cosine);
+        let lsh = Index::new(IndexType::Lsh(params), train.view(), &Cosine);
+
+        let mut found = 0;
+        for query in test.outer_iter() {
+            let exact = brute_force.k_nearest(train.view(), &Cosine, query, 10);
+            let approx = lsh.k_nearest(train.view(), &Cosine, query, 10);
+            assert_eq!(approx.len(), 10);
+            found += approx.iter().filter(|n| exact.contains(n)).count();
+        }
+
+        found as f32 / (test.rows() * 10) as f32
+    }
+
+    #[test]
+    fn lsh_multi_probe_test() {
+        let params = LshParams {
+            n_tables: 8,
+            n_bits: 10,
+            n_probes: 0,
+            seed: 1,
+        };
+        let single_probe = recall(params);
+        let multi_probe = recall(LshParams {
+            n_probes: 16,
+            ..params
+        });
+
+        assert!(multi_probe > single_probe);
+        assert!(multi_probe > 0.8);
+    }
+
+    #[test]
+    fn lsh_seed_test() {
+        let train = random_matrix(200, 8, 3);
+        let test = random_matrix(20, 8, 4);
+
+        let params = LshParams::default();
+        let lsh1 = Index::new(IndexType::Lsh(params), train.view(), &Cosine);
+        let lsh2 = Index::new(IndexType::Lsh(params), train.view(), &Cosine);
+
+        for query in test.outer_iter() {
+            assert_eq!(
+                lsh1.k_nearest(train.view(), &Cosine, query, 5),
+                lsh2.k_nearest(train.view(), &Cosine, query, 5)
+            );
+        }
+    }
+}
@@ -0,0 +1,302 @@
+use failure::{err_msg, Error};
+use ndarray::prelude::*;
+
+use Metric;
+
+/// Estimator of the covariance matrix of the training data.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Covariance {
+    /// The empirical covariance matrix.
+    ///
+    /// The empirical covariance matrix is singular when there are fewer
+    /// instances than features or when features are linearly dependent.
+    Empirical,
+
+    /// The empirical covariance matrix, shrunk towards a scaled identity
+    /// matrix with the given intensity in [0, 1].
+    ///
+    /// The shrunk covariance matrix is `(1 - s) Σ + s μ I`, where `μ` is
+    /// the mean variance of the features.
+    Shrinkage(f32),
+
+    /// Shrinkage with the intensity estimated using the method of Ledoit
+    /// and Wolf (2004).
+    LedoitWolf,
+}
+
+/// Mahalanobis distance metric.
+///
+/// The Mahalanobis distance is `sqrt((x - y)ᵀ Σ⁻¹ (x - y))`, where `Σ` is
+/// the covariance matrix of the training data. Using the Cholesky
+/// decomposition `Σ = L Lᵀ`, it is equal to the Euclidean distance between
+/// the whitened vectors `L⁻¹ x` and `L⁻¹ y`. KNN models whiten the training
+/// data once and whiten queries before searching, so that search is as
+/// cheap as Euclidean search and supports all search strategies.
+#[derive(Clone, Debug)]
+pub struct Mahalanobis {
+    whitening: Array2<f32>,
+}
+
+impl Mahalanobis {
+    /// Fit the Mahalanobis distance on a feature matrix.
+    ///
+    /// Every row of the feature matrix is a data point. Fails when the
+    /// estimated covariance matrix is not positive definite.
+    pub fn fit(features: ArrayView2<f32>, covariance: Covariance) -> Result<Self, Error> {
+        if features.rows() == 0 {
+            return Err(err_msg("Cannot estimate covariance without data"));
+        }
+
+        let covariance = match covariance {
+            Covariance::Empirical => empirical_covariance(features),
+            Covariance::Shrinkage(shrinkage) => {
+                assert!(
+                    (0. ..=1.).contains(&shrinkage),
+                    "Shrinkage should be in [0, 1], was: {}",
+                    shrinkage
+                );
+                shrink(empirical_covariance(features), shrinkage as f64)
+            }
+            Covariance::LedoitWolf => {
+                let covariance = empirical_covariance(features);
+                let shrinkage = ledoit_wolf_shrinkage(features, covariance.view());
+                shrink(covariance, shrinkage)
+            }
+        };
+
+        let cholesky = cholesky(covariance.view()).ok_or_else(|| {
+            err_msg("Covariance matrix is not positive definite, consider using shrinkage")
+        })?;
+
+        Ok(Mahalanobis {
+            whitening: invert_lower_triangular(cholesky.view()).mapv(|v| v as f32),
+        })
+    }
+
+    /// Construct the Mahalanobis distance from a whitening matrix.
+    ///
+    /// The whitening matrix `W` should satisfy `Wᵀ W = Σ⁻¹`.
+    pub fn from_whitening(whitening: Array2<f32>) -> Self {
+        assert_eq!(
+            whitening.rows(),
+            whitening.cols(),
+            "Whitening matrix should be square"
+        );
+        Mahalanobis { whitening }
+    }
+
+    /// Get the whitening matrix.
+    pub fn whitening(&self) -> ArrayView2<'_, f32> {
+        self.whitening.view()
+    }
+
+    /// Whiten a feature vector.
+    pub fn whiten(&self, features: ArrayView1<f32>) -> Array1<f32> {
+        self.whitening.dot(&features)
+    }
+
+    /// Whiten every row of a feature matrix.
+    pub fn whiten_rows(&self, features: ArrayView2<f32>) -> Array2<f32> {
+        features.dot(&self.whitening.t())
+    }
+}
+
+impl Metric for Mahalanobis {
+    fn distance(&self, v1: ArrayView1<f32>, v2: ArrayView1<f32>) -> f32 {
+        let diff = &v1 - &v2;
+        self.whiten(diff.view())
+            .iter()
+            .map(|&v| v * v)
+            .sum::<f32>()
+            .sqrt()
+    }
+
+    fn is_true_metric(&self) -> bool {
+        true
+    }
+}
+
+/// Compute the (biased) empirical covariance matrix of a feature matrix.
+fn empirical_covariance(features: ArrayView2<f32>) -> Array2<f64> {
+    let centered = centered(features);
+    centered.t().dot(&centered) / features.rows() as f64
+}
+
+fn centered(features: ArrayView2<f32>) -> Array2<f64> {
+    let features = features.mapv(|v| v as f64);
+    let mean = features.mean_axis(Axis(0));
+    features - &mean
+}
+
+/// Shrink a covariance matrix towards a scaled identity matrix.
+fn shrink(mut covariance: Array2<f64>, shrinkage: f64) -> Array2<f64> {
+    let mu = covariance.diag().scalar_sum() / covariance.rows() as f64;
+    covariance *= 1. - shrinkage;
+    covariance.diag_mut().mapv_inplace(|v| v + shrinkage * mu);
+    covariance
+}
+
+/// Estimate the optimal shrinkage intensity (Ledoit and Wolf, 2004).
+fn ledoit_wolf_shrinkage(features: ArrayView2<f32>, covariance: ArrayView2<f64>) -> f64 {
+    let n_instances = features.rows() as f64;
+    let mu = covariance.diag().scalar_sum() / covariance.rows() as f64;
+
+    // Squared distance of the covariance matrix to the shrinkage target.
+    let delta: f64 = covariance
+        .indexed_iter()
+        .map(|((i, j), &v)| {
+            let target = if i == j { mu } else { 0. };
+            (v - target) * (v - target)
+        })
+        .sum();
+    if delta == 0. {
+        return 0.;
+    }
+
+    // Estimate of the variance of the empirical covariance matrix:
+    // sum_i ||x_i x_iᵀ - Σ||² / n², which simplifies to the expression
+    // below.
+    let squared_norm: f64 = covariance.iter().map(|&v| v * v).sum();
+    let fourth_powers: f64 = centered(features)
+        .outer_iter()
+        .map(|row| {
+            let norm: f64 = row.iter().map(|&v| v * v).sum();
+            norm * norm
+        })
+        .sum();
+    let beta = ((fourth_powers - n_instances * squared_norm) / (n_instances * n_instances)).max(0.);
+
+    beta.min(delta) / delta
+}
+
+/// Compute the Cholesky decomposition `L Lᵀ` of a symmetric matrix.
+///
+/// Returns `None` if the matrix is not (numerically) positive definite.
+fn cholesky(matrix: ArrayView2<f64>) -> Option<Array2<f64>> {
+    let n = matrix.rows();
+    let mut lower = Array2::zeros((n, n));
+
+    for i in 0..n {
+        for j in 0..=i {
+            let mut sum = matrix[(i, j)];
+            for k in 0..j {
+                sum -= lower[(i, k)] * lower[(j, k)];
+            }
+
+            if i == j {
+                if sum.is_nan() || sum <= 1e-12 * matrix[(i, i)] {
+                    return None;
+                }
+                lower[(i, i)] = sum.sqrt();
+            } else {
+                lower[(i, j)] = sum / lower[(j, j)];
+            }
+        }
+    }
+
+    Some(lower)
+}
+
+/// Invert a lower triangular matrix by forward substitution.
+fn invert_lower_triangular(lower: ArrayView2<f64>) -> Array2<f64> {
+    let n = lower.rows();
+    let mut inverse = Array2::zeros((n, n));
+
+    for col in 0..n {
+        for i in col..n {
+            let mut sum = if i == col { 1. } else { 0. };
+            for k in col..i {
+                sum -= lower[(i, k)] * inverse[(k, col)];
+            }
+            inverse[(i, col)] = sum / lower[(i, i)];
+        }
+    }
+
+    inverse
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::prelude::*;
+
+    use super::{cholesky, invert_lower_triangular};
+    use index::tests::random_matrix;
+    use index::{brute_force_k_nearest, IndexType};
+    use search::Search;
+    use {Covariance, Mahalanobis, Metric, Metrics};
+
+    #[test]
+    fn cholesky_test() {
+        let matrix = arr2(&[[4., 2., 0.4], [2., 2., 0.5], [0.4, 0.5, 3.]]);
+        let lower = cholesky(matrix.view()).unwrap();
+        assert!(lower.dot(&lower.t()).all_close(&matrix, 1e-12));
+
+        let inverse = invert_lower_triangular(lower.view());
+        assert!(inverse.dot(&lower).all_close(&Array2::eye(3), 1e-12));
+
+        assert!(cholesky(arr2(&[[1., 1.], [1., 1.]]).view()).is_none());
+    }
+
+    #[test]
+    fn mahalanobis_distance_test() {
+        // Feature 1 varies more than feature 0, and the features are
+        // correlated.
+        let features = arr2(&[[0., 0.], [1., 2.], [2., 2.], [3., 6.]]);
+        let mahalanobis = Mahalanobis::fit(features.view(), Covariance::Empirical).unwrap();
+
+        // The covariance is [[1.25, 2.25], [2.25, 4.75]], with inverse
+        // [[4.75, -2.25], [-2.25, 1.25]] / 0.875.
+        let v1 = arr1(&[1., 1.]);
+        let v2 = arr1(&[0., 3.]);
+        let expected = ((4.75 + 2. * 2.25 * 2. + 1.25 * 4.) / 0.875f32).sqrt();
+        assert_abs_diff_eq!(
+            mahalanobis.distance(v1.view(), v2.view()),
+            expected,
+            epsilon = 1e-5
+        );
+    }
+
+    #[test]
+    fn shrinkage_test() {
+        // Linearly dependent features.
+        let features = arr2(&[[0., 0.], [1., 2.], [2., 4.]]);
+        assert!(Mahalanobis::fit(features.view(), Covariance::Empirical).is_err());
+        assert!(Mahalanobis::fit(features.view(), Covariance::Shrinkage(0.1)).is_ok());
+        assert!(Mahalanobis::fit(features.view(), Covariance::LedoitWolf).is_ok());
+
+        // Without shrinkage, the whitened features are uncorrelated.
+        let features = random_matrix(200, 3, 1).mapv(|v| v * 3.) + &arr1(&[1., 2., 3.]);
+        let mahalanobis = Mahalanobis::fit(features.view(), Covariance::Shrinkage(0.)).unwrap();
+        let whitened = mahalanobis.whiten_rows(features.view());
+        let centered = &whitened - &whitened.mean_axis(Axis(0));
+        let covariance = centered.t().dot(&centered) / 200.;
+        assert!(covariance.all_close(&Array2::eye(3), 1e-4));
+    }
+
+    #[test]
+    fn mahalanobis_search_test() {
+        let train = random_matrix(500, 4, 3).mapv(|v| v * 10.);
+        let test = random_matrix(50, 4, 4);
+        let mahalanobis = Mahalanobis::fit(train.view(), Covariance::LedoitWolf).unwrap();
+
+        let search = Search::new(
+            train.clone(),
+            IndexType::KdTree,
+            Metrics::Mahalanobis(mahalanobis.clone()),
+            None,
+        );
+
+        for query in test.outer_iter() {
+            let found = search.k_nearest(query, 5);
+            let expected = brute_force_k_nearest(train.view(), &mahalanobis, query, 5);
+            for (found, expected) in found.iter().zip(&expected) {
+                assert_eq!(found.idx, expected.idx);
+                assert_abs_diff_eq!(
+                    found.distance.into_inner(),
+                    expected.distance.into_inner(),
+                    epsilon = 1e-4
+                );
+            }
+        }
+    }
+}
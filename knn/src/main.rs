@@ -1,62 +1,1092 @@
-#[cfg(test)]
-#[macro_use]
-extern crate approx;
 extern crate clap;
-extern crate failure;
+extern crate knn;
 extern crate ndarray;
-extern crate num_traits;
-extern crate ordered_float;
+extern crate rayon;
 extern crate stdinout;
 
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::process;
+use std::str::FromStr;
 
+use clap::ArgMatches;
+use knn::{
+    contamination_threshold, k_fold, stratified_k_fold, train_test_split, AnomalyScore, Column,
+    Covariance, CsvIter, CsvOptions, Evaluator, HnswParams, IndexType, Instance, KNNBuilder,
+    KNNRegressor, LabelDictionary, LabeledInstanceIter, LshParams, Metrics, Minkowski, MmapModel,
+    Reduction, RegressionEvaluator, Scaling, SparseKNN, SparseKNNBuilder, SvmLightIter,
+    TieBreaking, TrainingNeighbor, Weights, KNN,
+};
+use ndarray::{Array2, ArrayView2, Data};
+use rayon::ThreadPoolBuilder;
 use stdinout::OrExit;
 
 mod args;
 use args::parse_args;
 
-pub mod distance;
-use distance::EuclideanDistance;
+fn main() {
+    let matches = parse_args();
 
-mod evaluation;
-use evaluation::Evaluator;
+    match matches.subcommand() {
+        ("train", Some(matches)) => train(matches),
+        ("predict", Some(matches)) => predict(matches),
+        ("cv", Some(matches)) => cross_validate(matches),
+        ("select", Some(matches)) => select(matches),
+        ("score", Some(matches)) => score(matches),
+        _ => evaluate(&matches),
+    }
+}
 
-mod instance;
-use instance::{Instance, InstanceIter};
+/// Train a model and evaluate it on test data.
+fn evaluate(matches: &ArgMatches) {
+    let k = knearest(matches);
+    set_up_threads(matches);
 
-mod knn;
-use knn::{KNNBuilder, KNN};
+    let train_path = matches.value_of("TRAIN").unwrap();
+    let test_path = matches.value_of("TEST").unwrap();
 
-fn main() {
-    let matches = parse_args();
+    if matches.is_present("reduce")
+        && (matches.is_present("regression") || matches.value_of("format") == Some("svmlight"))
+    {
+        eprintln!("Prototype reduction is only supported for dense classification data");
+        process::exit(1);
+    }
+
+    if matches.is_present("radius")
+        && (matches.is_present("regression") || matches.value_of("format") == Some("svmlight"))
+    {
+        eprintln!("Radius classification is only supported for dense classification data");
+        process::exit(1);
+    }
+
+    if matches.value_of("format") == Some("svmlight") {
+        evaluate_sparse(matches, train_path, test_path, k);
+    } else if matches.is_present("regression") {
+        let train = regression_instances(matches, train_path);
+        let mut model: KNNRegressor = read_builder(matches, train).into();
+        model.set_weights(weights(matches));
+
+        let test = regression_instances(matches, test_path);
+        let (targets, features) = read_data(test, model.n_features());
+
+        let mut eval = RegressionEvaluator::default();
+        for (&target, predicted) in targets.iter().zip(model.predict_batch(&features.view(), k)) {
+            eval.count(target, predicted);
+        }
+
+        println!("MAE: {:.4}", eval.mae());
+        println!("RMSE: {:.4}", eval.rmse());
+        println!("R²: {:.4}", eval.r_squared());
+    } else {
+        // Test instances with unknown class names get new labels, so
+        // that they are counted as errors.
+        let mut names = LabelDictionary::default();
+        let train = class_instances(matches, train_path, &mut names);
+        let mut builder = read_builder(matches, train);
+
+        // Keep the model before reduction, to report its accuracy.
+        let unreduced = if matches.is_present("reduce") {
+            let unreduced: KNN = builder.clone().into();
+            reduce(matches, &mut builder);
+            Some(unreduced)
+        } else {
+            None
+        };
+
+        let outlier = outlier_label(matches, &mut names);
+        let mut model: KNN = builder.into();
+        model.set_weights(weights(matches));
+        model.set_tie_breaking(tie_breaking(matches));
+        model.set_outlier_label(outlier);
+
+        let test = class_instances(matches, test_path, &mut names);
+        let (labels, features) = read_data(test, model.n_features());
+
+        let mut eval = Evaluator::default();
+        for (&label, predicted) in labels
+            .iter()
+            .zip(classify(matches, &model, &features.view(), k))
+        {
+            eval.count(label, predicted);
+        }
+
+        if let Some(mut unreduced) = unreduced {
+            unreduced.set_weights(weights(matches));
+            unreduced.set_tie_breaking(tie_breaking(matches));
+            unreduced.set_outlier_label(outlier);
+            let mut unreduced_eval = Evaluator::default();
+            for (&label, predicted) in
+                labels
+                    .iter()
+                    .zip(classify(matches, &unreduced, &features.view(), k))
+            {
+                unreduced_eval.count(label, predicted);
+            }
+
+            println!(
+                "Accuracy before reduction: {:.1}",
+                unreduced_eval.accuracy() * 100.
+            );
+        }
+
+        println!("Tie breaking: {:?}", model.tie_breaking());
+        println!("Accuracy: {:.1}", eval.accuracy() * 100.);
+
+        if matches.is_present("recall") {
+            let recall_sum: f32 = features
+                .outer_iter()
+                .map(|instance| model.recall(instance.as_slice().unwrap(), k))
+                .sum();
+            println!("Recall: {:.1}", recall_sum / labels.len() as f32 * 100.);
+        }
+
+        let names = if is_csv(matches) { Some(&names) } else { None };
+        print_table(&eval, names);
+    }
+}
+
+/// Train a classifier on sparse data and evaluate it on test data.
+fn evaluate_sparse(matches: &ArgMatches, train_path: &str, test_path: &str, k: usize) {
+    if matches.is_present("regression") || matches.is_present("recall") {
+        eprintln!("Sparse data only supports classification with brute force search");
+        process::exit(1);
+    }
+
+    let mut builder = SparseKNNBuilder::default();
+    for instance in read_svmlight(train_path) {
+        builder.push(instance.or_exit("Cannot read training instance", 1));
+    }
+
+    // The Mahalanobis distance is the only metric that is fitted on the
+    // training data, so an empty dense builder suffices for the others.
+    if matches.value_of("metric") == Some("mahalanobis") {
+        eprintln!("The Mahalanobis distance cannot be used with sparse data");
+        process::exit(1);
+    }
+    if matches.is_present("scaling") {
+        eprintln!("Feature scaling cannot be used with sparse data");
+        process::exit(1);
+    }
+    let metric_name = matches.value_of("metric").unwrap_or("euclidean");
+    builder.set_metric(metric(
+        matches,
+        metric_name,
+        &KNNBuilder::<usize>::default(),
+    ));
+
+    let mut model: SparseKNN = builder.into();
+    model.set_weights(weights(matches));
+    model.set_tie_breaking(tie_breaking(matches));
+
+    let mut labels = Vec::new();
+    let mut features = Vec::new();
+    for instance in read_svmlight(test_path) {
+        let instance = instance.or_exit("Cannot read test instance", 1);
+        labels.push(instance.label);
+        features.push(instance.features);
+    }
+
+    let mut eval = Evaluator::default();
+    for (&label, predicted) in labels.iter().zip(model.classify_batch(&features, k)) {
+        eval.count(label, predicted);
+    }
+
+    println!("Tie breaking: {:?}", tie_breaking(matches));
+    println!("Accuracy: {:.1}", eval.accuracy() * 100.);
+    print_table(&eval, None);
+}
+
+/// Print the per-class metrics and the confusion matrix.
+fn print_table(eval: &Evaluator, names: Option<&LabelDictionary>) {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    writeln!(stdout)
+        .and_then(|_| eval.write_table(&mut stdout, names))
+        .or_exit("Cannot write evaluation", 1);
+}
+
+fn read_svmlight(path: &str) -> SvmLightIter<BufReader<File>> {
+    let file = File::open(path).or_exit(format!("Cannot open {}", path), 1);
+    SvmLightIter::new(BufReader::new(file))
+}
+
+/// Train a classifier and save it.
+///
+/// The class names of CSV data are saved with the model.
+fn train(matches: &ArgMatches) {
+    let train_path = matches.value_of("TRAIN").unwrap();
+    let model_path = matches.value_of("MODEL").unwrap();
+
+    let mut names = LabelDictionary::default();
+    let train = class_instances(matches, train_path, &mut names);
+    let mut builder = read_builder(matches, train);
+    reduce(matches, &mut builder);
+    let outlier = outlier_label(matches, &mut names);
+    let mut model: KNN = builder.into();
+    model.set_weights(weights(matches));
+    model.set_tie_breaking(tie_breaking(matches));
+    if is_csv(matches) {
+        model.set_label_names(names);
+    }
+    model.set_outlier_label(outlier);
+
+    let file = File::create(model_path).or_exit("Cannot create model file", 1);
+    model
+        .save(BufWriter::new(file))
+        .or_exit("Cannot save model", 1);
+}
+
+/// Classify data with a saved classifier.
+///
+/// Predictions are printed as class names when the model has them. The
+/// model is memory-mapped, so that its feature matrix is not copied. The
+/// neighbor weights, tie breaking, and outlier label of the model can be
+/// overridden. The tie breaking is reported on standard error. The class
+/// probabilities and the nearest neighbors can be written to separate
+/// files.
+fn predict(matches: &ArgMatches) {
+    let k = knearest(matches);
+    set_up_threads(matches);
+
+    let model_path = matches.value_of("MODEL").unwrap();
+    let test_path = matches.value_of("TEST").unwrap();
+
+    let mmap = MmapModel::open(model_path).or_exit("Cannot open model file", 1);
+    let mut model = mmap.model().or_exit("Cannot load model", 1);
+    if matches.is_present("weights") {
+        model.set_weights(weights(matches));
+    }
+    if matches.is_present("ties") {
+        model.set_tie_breaking(tie_breaking(matches));
+    }
+    if let Some(outlier) = matches.value_of("outlier") {
+        let label = match model.label_names().cloned() {
+            Some(mut names) => {
+                let label = names.encode(outlier);
+                model.set_label_names(names);
+                label
+            }
+            None => outlier
+                .parse()
+                .or_exit(format!("Invalid class label: {}", outlier), 1),
+        };
+        model.set_outlier_label(Some(label));
+    }
+    eprintln!("Tie breaking: {:?}", model.tie_breaking());
+
+    // The labels of the data are not used.
+    let test = convert_labels(read_instances(matches, test_path), |_| ());
+    let (_, features) = read_data(test, model.n_features());
+
+    if let Some(output_path) = matches.value_of("output") {
+        let alpha: f32 = parse_or(matches, "smoothing", 0.);
+        if alpha.is_nan() || alpha < 0. {
+            eprintln!("The smoothing parameter should be non-negative");
+            process::exit(1);
+        }
+
+        let file = File::create(output_path).or_exit("Cannot create output file", 1);
+        write_probabilities(
+            BufWriter::new(file),
+            &model.predict_proba_batch(&features.view(), k, alpha),
+            model.label_names(),
+        )
+        .or_exit("Cannot write probabilities", 1);
+    }
+
+    if let Some(neighbors_path) = matches.value_of("neighbors") {
+        let file = File::create(neighbors_path).or_exit("Cannot create neighbors file", 1);
+        write_neighbors(
+            BufWriter::new(file),
+            &model.kneighbors_batch(&features.view(), k),
+            model.label_names(),
+        )
+        .or_exit("Cannot write neighbors", 1);
+    }
+
+    let stdout = io::stdout();
+    let mut stdout = BufWriter::new(stdout.lock());
+    for label in classify(matches, &model, &features.view(), k) {
+        match model.label_names() {
+            Some(names) => writeln!(stdout, "{}", names.name(label).unwrap()),
+            None => writeln!(stdout, "{}", label),
+        }
+        .or_exit("Cannot write prediction", 1);
+    }
+    stdout.flush().or_exit("Cannot write prediction", 1);
+}
+
+/// Score data for anomalies with a saved model.
+///
+/// The training data of the model is the reference distribution. When a
+/// contamination rate is given, the threshold for the rate is reported
+/// on standard error and every score is followed by `outlier` or
+/// `inlier`.
+fn score(matches: &ArgMatches) {
+    let k = knearest(matches);
+    set_up_threads(matches);
+
+    let model_path = matches.value_of("MODEL").unwrap();
+    let data_path = matches.value_of("DATA").unwrap();
+
+    let score = match matches.value_of("score").unwrap_or("kth") {
+        "kth" => AnomalyScore::KthDistance,
+        "mean" => AnomalyScore::MeanDistance,
+        "lof" => AnomalyScore::LocalOutlierFactor,
+        _ => unreachable!(),
+    };
+
+    let mmap = MmapModel::open(model_path).or_exit("Cannot open model file", 1);
+    let model = mmap.model().or_exit("Cannot load model", 1);
+    if k == 0 || (score == AnomalyScore::LocalOutlierFactor && k >= model.len()) {
+        eprintln!(
+            "k should be at least 1 and, for the Local Outlier Factor, smaller than the number of training instances ({})",
+            model.len()
+        );
+        process::exit(1);
+    }
+
+    // The labels of the data are not used.
+    let data = convert_labels(read_instances(matches, data_path), |_| ());
+    let (_, features) = read_data(data, model.n_features());
+    let scores = model.anomaly_scores(&features.view(), k, score);
+
+    let threshold = matches.value_of("contamination").map(|contamination| {
+        let contamination: f32 = contamination.parse().or_exit("Invalid contamination", 1);
+        if !(contamination > 0. && contamination < 1.) || scores.is_empty() {
+            eprintln!("The contamination should be in (0, 1) and there should be data");
+            process::exit(1);
+        }
+
+        let threshold = contamination_threshold(&scores, contamination);
+        eprintln!("Threshold: {:.6}", threshold);
+        threshold
+    });
+
+    let stdout = io::stdout();
+    let mut stdout = BufWriter::new(stdout.lock());
+    for &score in &scores {
+        match threshold {
+            Some(threshold) if score > threshold => writeln!(stdout, "{:.6}\toutlier", score),
+            Some(_) => writeln!(stdout, "{:.6}\tinlier", score),
+            None => writeln!(stdout, "{:.6}", score),
+        }
+        .or_exit("Cannot write score", 1);
+    }
+    stdout.flush().or_exit("Cannot write score", 1);
+}
+
+/// Write class probabilities as tab-separated values.
+///
+/// The header contains the class names or labels, followed by one line
+/// per data point.
+fn write_probabilities<W>(
+    mut write: W,
+    probabilities: &Array2<f32>,
+    names: Option<&LabelDictionary>,
+) -> io::Result<()>
+where
+    W: Write,
+{
+    let header: Vec<_> = (0..probabilities.cols())
+        .map(|label| match names {
+            Some(names) => names.name(label).unwrap().to_owned(),
+            None => label.to_string(),
+        })
+        .collect();
+    writeln!(write, "{}", header.join("\t"))?;
+
+    for row in probabilities.outer_iter() {
+        let row: Vec<_> = row.iter().map(|p| format!("{:.6}", p)).collect();
+        writeln!(write, "{}", row.join("\t"))?;
+    }
+
+    write.flush()
+}
+
+/// Write nearest neighbors as tab-separated values.
+///
+/// After a header, every neighbor is written on its own line with the
+/// (zero-based) index of the data point, the rank of the neighbor, the ID
+/// and label of the training instance, and the distance.
+fn write_neighbors<W>(
+    mut write: W,
+    neighbors: &[Vec<TrainingNeighbor>],
+    names: Option<&LabelDictionary>,
+) -> io::Result<()>
+where
+    W: Write,
+{
+    writeln!(write, "instance\trank\tid\tlabel\tdistance")?;
+
+    for (instance, neighbors) in neighbors.iter().enumerate() {
+        for (rank, neighbor) in neighbors.iter().enumerate() {
+            let label = match names {
+                Some(names) => names.name(neighbor.label).unwrap().to_owned(),
+                None => neighbor.label.to_string(),
+            };
+            writeln!(
+                write,
+                "{}\t{}\t{}\t{}\t{:.6}",
+                instance,
+                rank + 1,
+                neighbor.id,
+                label,
+                neighbor.distance
+            )?;
+        }
+    }
+
+    write.flush()
+}
+
+/// Evaluate a classifier with k-fold cross-validation.
+///
+/// A model is trained for every fold on the remaining folds. The mean and
+/// (population) standard deviation of the metrics over the folds are
+/// reported.
+fn cross_validate(matches: &ArgMatches) {
+    let k = knearest(matches);
+    set_up_threads(matches);
+
+    let data_path = matches.value_of("DATA").unwrap();
+    let n_folds = parse_or(matches, "folds", 5);
+    let seed = parse_or(matches, "seed", 42);
+
+    let mut names = LabelDictionary::default();
+    let instances = class_instances(matches, data_path, &mut names);
+    if n_folds < 2 || n_folds > instances.len() {
+        eprintln!(
+            "The number of folds should be between 2 and the number of instances ({})",
+            instances.len()
+        );
+        process::exit(1);
+    }
+
+    let folds = if matches.is_present("stratified") {
+        let labels: Vec<_> = instances.iter().map(|instance| instance.label).collect();
+        stratified_k_fold(&labels, n_folds, seed)
+    } else {
+        k_fold(instances.len(), n_folds, seed)
+    };
+
+    println!("Tie breaking: {:?}", tie_breaking(matches));
+
+    let outlier = outlier_label(matches, &mut names);
+    let metric_names = ["Accuracy", "Macro F1", "Micro F1", "Weighted F1", "Kappa"];
+    let mut fold_metrics = Vec::new();
+    for (fold_idx, fold) in folds.iter().enumerate() {
+        let (train, test) = split_instances(&instances, fold);
+
+        let mut builder = read_builder(matches, train);
+        reduce(matches, &mut builder);
+        let mut model: KNN = builder.into();
+        model.set_weights(weights(matches));
+        model.set_tie_breaking(tie_breaking(matches));
+        model.set_outlier_label(outlier);
 
-    let k = matches
+        let (labels, features) = read_data(test, model.n_features());
+        let mut eval = Evaluator::default();
+        for (&label, predicted) in labels
+            .iter()
+            .zip(classify(matches, &model, &features.view(), k))
+        {
+            eval.count(label, predicted);
+        }
+
+        println!(
+            "Fold {}: accuracy {:.1}",
+            fold_idx + 1,
+            eval.accuracy() * 100.
+        );
+        fold_metrics.push([
+            eval.accuracy(),
+            eval.macro_f1(),
+            eval.micro_f1(),
+            eval.weighted_f1(),
+            eval.kappa(),
+        ]);
+    }
+
+    println!();
+    println!("{:<12} {:>9} {:>9}", "Metric", "Mean", "Std");
+    for (i, name) in metric_names.iter().enumerate() {
+        let values: Vec<_> = fold_metrics.iter().map(|metrics| metrics[i]).collect();
+        let (mean, std) = mean_std(&values);
+        println!("{:<12} {:>9.3} {:>9.3}", name, mean, std);
+    }
+}
+
+/// Select the number of neighbors, metric, and neighbor weighting.
+///
+/// All combinations of the given values are evaluated with
+/// cross-validation or on a held-out part of the data. With
+/// cross-validation, the metrics are computed from the predictions of all
+/// folds. For every metric, a single model is built per split and the
+/// nearest neighbors of every data point are searched once, for the
+/// largest k.
+fn select(matches: &ArgMatches) {
+    set_up_threads(matches);
+
+    let data_path = matches.value_of("DATA").unwrap();
+    let seed = parse_or(matches, "seed", 42);
+    let ks = parse_ks(matches.value_of("ks").unwrap_or("1-15"));
+    let metric_names = parse_list(matches.value_of("metrics").unwrap_or("euclidean"));
+    let weights_names = parse_list(matches.value_of("weightings").unwrap_or("uniform"));
+    let weights: Vec<_> = weights_names
+        .iter()
+        .map(|name| weights_by_name(matches, name))
+        .collect();
+    let score = matches.value_of("score").unwrap_or("accuracy");
+
+    let mut names = LabelDictionary::default();
+    let instances = class_instances(matches, data_path, &mut names);
+
+    let test_sets = match matches.value_of("holdout") {
+        Some(_) => {
+            let fraction: f32 = parse_or(matches, "holdout", 0.2);
+            if !(fraction > 0. && fraction < 1.) {
+                eprintln!("The held-out fraction should be in (0, 1)");
+                process::exit(1);
+            }
+            vec![train_test_split(instances.len(), fraction, seed).1]
+        }
+        None => {
+            let n_folds = parse_or(matches, "folds", 5);
+            if n_folds < 2 || n_folds > instances.len() {
+                eprintln!(
+                    "The number of folds should be between 2 and the number of instances ({})",
+                    instances.len()
+                );
+                process::exit(1);
+            }
+            if matches.is_present("stratified") {
+                let labels: Vec<_> = instances.iter().map(|instance| instance.label).collect();
+                stratified_k_fold(&labels, n_folds, seed)
+            } else {
+                k_fold(instances.len(), n_folds, seed)
+            }
+        }
+    };
+
+    // Evaluators, indexed by metric, weighting, and k.
+    let mut evals: Vec<Vec<Vec<_>>> = metric_names
+        .iter()
+        .map(|_| {
+            weights
+                .iter()
+                .map(|_| ks.iter().map(|_| Evaluator::default()).collect())
+                .collect()
+        })
+        .collect();
+
+    for test_set in &test_sets {
+        let (train, test) = split_instances(&instances, test_set);
+        for (metric_name, metric_evals) in metric_names.iter().zip(&mut evals) {
+            let mut builder = read_builder_with_metric(matches, train.clone(), metric_name);
+            reduce(matches, &mut builder);
+            let mut model: KNN = builder.into();
+            model.set_tie_breaking(tie_breaking(matches));
+            let (labels, features) = read_data(test.clone(), model.n_features());
+
+            let grid = model.classify_batch_grid(&features.view(), &ks, &weights);
+            for (weights_evals, weights_predictions) in metric_evals.iter_mut().zip(grid) {
+                for (eval, predictions) in weights_evals.iter_mut().zip(weights_predictions) {
+                    for (&label, predicted) in labels.iter().zip(predictions) {
+                        eval.count(label, predicted);
+                    }
+                }
+            }
+        }
+    }
+
+    println!("Tie breaking: {:?}", tie_breaking(matches));
+    println!();
+    println!(
+        "{:<12} {:<10} {:>4} {:>9} {:>9} {:>9} {:>9}",
+        "Metric", "Weights", "k", "Accuracy", "Macro F1", "Wght F1", "Kappa"
+    );
+    let mut best: Option<(f32, &str, &str, usize)> = None;
+    for (metric_name, metric_evals) in metric_names.iter().zip(&evals) {
+        for (weights_name, weights_evals) in weights_names.iter().zip(metric_evals) {
+            for (&k, eval) in ks.iter().zip(weights_evals) {
+                println!(
+                    "{:<12} {:<10} {:>4} {:>9.3} {:>9.3} {:>9.3} {:>9.3}",
+                    metric_name,
+                    weights_name,
+                    k,
+                    eval.accuracy(),
+                    eval.macro_f1(),
+                    eval.weighted_f1(),
+                    eval.kappa()
+                );
+
+                let value = match score {
+                    "accuracy" => eval.accuracy(),
+                    "macro-f1" => eval.macro_f1(),
+                    "weighted-f1" => eval.weighted_f1(),
+                    "kappa" => eval.kappa(),
+                    _ => unreachable!(),
+                };
+
+                // Ties are resolved in favor of the configuration that
+                // was listed first.
+                if best.map_or(true, |(best_value, _, _, _)| value > best_value) {
+                    best = Some((value, metric_name, weights_name, k));
+                }
+            }
+        }
+    }
+
+    let (value, metric_name, weights_name, k) = best.unwrap();
+    println!();
+    println!(
+        "Best: --metric {} --weights {} -k {} ({} {:.3})",
+        metric_name, weights_name, k, score, value
+    );
+}
+
+/// Parse a comma-separated list of values and ranges of k, such as
+/// `1-9,15,21`.
+fn parse_ks(ks: &str) -> Vec<usize> {
+    let parse = |k: &str| -> usize {
+        match k.trim().parse() {
+            Ok(k) if k > 0 => k,
+            _ => {
+                eprintln!("Invalid value of k: {}", k);
+                process::exit(1);
+            }
+        }
+    };
+
+    let mut values = Vec::new();
+    for part in ks.split(',') {
+        let mut range = part.splitn(2, '-');
+        let start = parse(range.next().unwrap());
+        match range.next() {
+            Some(end) => {
+                let end = parse(end);
+                if end < start {
+                    eprintln!("Invalid range of k: {}", part.trim());
+                    process::exit(1);
+                }
+                values.extend(start..=end)
+            }
+            None => values.push(start),
+        }
+    }
+
+    if values.is_empty() {
+        eprintln!("No values of k given");
+        process::exit(1);
+    }
+
+    values
+}
+
+fn parse_list(list: &str) -> Vec<String> {
+    list.split(',').map(|v| v.trim().to_owned()).collect()
+}
+
+/// Split data into training data and the test data with the given
+/// indices.
+fn split_instances(
+    instances: &[Instance],
+    test_indices: &[usize],
+) -> (Vec<Instance>, Vec<Instance>) {
+    let mut in_test = vec![false; instances.len()];
+    for &idx in test_indices {
+        in_test[idx] = true;
+    }
+
+    let select = |test: bool| {
+        instances
+            .iter()
+            .zip(&in_test)
+            .filter(|&(_, &in_test)| in_test == test)
+            .map(|(instance, _)| instance.clone())
+            .collect()
+    };
+
+    (select(false), select(true))
+}
+
+/// Compute the mean and population standard deviation.
+fn mean_std(values: &[f32]) -> (f32, f32) {
+    let n = values.len() as f32;
+    let mean = values.iter().sum::<f32>() / n;
+    let variance = values.iter().map(|&v| (v - mean) * (v - mean)).sum::<f32>() / n;
+    (mean, variance.sqrt())
+}
+
+/// Classify data points by their k nearest neighbors or, when a radius is
+/// given, by the training instances within the radius.
+fn classify<S>(
+    matches: &ArgMatches,
+    model: &KNN<S>,
+    features: &ArrayView2<f32>,
+    k: usize,
+) -> Vec<usize>
+where
+    S: Data<Elem = f32> + Sync,
+{
+    match matches.value_of("radius") {
+        Some(radius) => {
+            let radius: f32 = radius.parse().or_exit("Invalid radius", 1);
+            if radius.is_nan() || radius < 0. {
+                eprintln!("The radius should be non-negative");
+                process::exit(1);
+            }
+            model
+                .classify_radius_batch(features, radius)
+                .or_exit("Cannot classify", 1)
+        }
+        None => model.classify_batch(features, k),
+    }
+}
+
+fn knearest(matches: &ArgMatches) -> usize {
+    matches
         .value_of("knearest")
         .map(|v| v.parse().or_exit("k is not a valid integer", 1))
-        .unwrap_or(3);
+        .unwrap_or(3)
+}
 
-    let train_file =
-        File::open(matches.value_of("TRAIN").unwrap()).or_exit("Cannot open training file", 1);
-    let test_file =
-        File::open(matches.value_of("TEST").unwrap()).or_exit("Cannot open test file", 1);
+fn set_up_threads(matches: &ArgMatches) {
+    if let Some(n_threads) = matches.value_of("threads") {
+        ThreadPoolBuilder::new()
+            .num_threads(n_threads.parse().or_exit("Invalid number of threads", 1))
+            .build_global()
+            .or_exit("Cannot set up thread pool", 1);
+    }
+}
 
+/// Construct a model builder from training data.
+///
+/// The metric and search strategy are set from the command-line options.
+fn read_builder<L>(matches: &ArgMatches, instances: Vec<Instance<L>>) -> KNNBuilder<L> {
+    let metric_name = matches.value_of("metric").unwrap_or("euclidean");
+    read_builder_with_metric(matches, instances, metric_name)
+}
+
+/// Construct a model builder from training data with the given metric.
+///
+/// The feature scaling is set before the metric is fitted.
+fn read_builder_with_metric<L>(
+    matches: &ArgMatches,
+    instances: Vec<Instance<L>>,
+    metric_name: &str,
+) -> KNNBuilder<L> {
     let mut builder = KNNBuilder::default();
-    for instance in InstanceIter::new(BufReader::new(train_file)) {
-        let instance = instance.or_exit("Cannot read instance", 1);
+    for instance in instances {
         builder.push(instance);
     }
 
-    let model: KNN = builder.into();
+    match matches.value_of("scaling") {
+        Some("standard") => builder.set_scaling(Scaling::Standard),
+        Some("minmax") => builder.set_scaling(Scaling::MinMax),
+        Some("robust") => builder.set_scaling(Scaling::Robust),
+        Some("l2") => builder.set_scaling(Scaling::L2),
+        Some(_) => unreachable!(),
+        None => (),
+    }
 
-    let mut eval = Evaluator::default();
+    let metric = metric(matches, metric_name, &builder);
+    if let Some(index_type) = index_type(matches) {
+        if !index_type.supports_metric(&metric) {
+            eprintln!("The {:?} metric cannot be used with this index", metric);
+            process::exit(1);
+        }
+        builder.set_index_type(index_type);
+    }
 
-    for instance in InstanceIter::new(BufReader::new(test_file)) {
-        let instance = instance.or_exit("Cannot read instance", 1);
-        let predicted = model.classify(&instance.features, k);
+    builder.set_metric(metric);
 
-        eval.count(instance.label, predicted);
+    builder
+}
+
+/// Apply the prototype selection algorithms to training data.
+///
+/// The size of the training data before and after reduction is printed.
+fn reduce(matches: &ArgMatches, builder: &mut KNNBuilder) {
+    let methods = match matches.value_of("reduce") {
+        Some(methods) => parse_list(methods),
+        None => return,
+    };
+
+    let n_before = builder.len();
+    for method in methods {
+        let reduction = match method.as_str() {
+            "cnn" => Reduction::Condensed,
+            "enn" => Reduction::Edited {
+                k: parse_or(matches, "reduce_k", 3),
+            },
+            "tomek" => Reduction::TomekLinks,
+            method => {
+                eprintln!("Unknown prototype selection: {}", method);
+                process::exit(1);
+            }
+        };
+        builder.reduce(reduction);
+
+        if builder.is_empty() {
+            eprintln!("Prototype selection removed all training instances");
+            process::exit(1);
+        }
     }
 
-    println!("Accuracy: {:.1}", eval.accuracy() * 100.);
+    println!(
+        "Reduced training data from {} to {} instances ({:.1}% removed)",
+        n_before,
+        builder.len(),
+        (n_before - builder.len()) as f32 / n_before as f32 * 100.
+    );
+}
+
+/// Split test data into labels and a feature matrix.
+fn read_data<L>(instances: Vec<Instance<L>>, n_features: usize) -> (Vec<L>, Array2<f32>) {
+    let mut labels = Vec::new();
+    let mut features = Vec::new();
+    for instance in instances {
+        labels.push(instance.label);
+        features.extend(instance.features);
+    }
+    let features = Array2::from_shape_vec((labels.len(), n_features), features)
+        .or_exit("Test instances have an incorrect number of features", 1);
+
+    (labels, features)
+}
+
+fn is_csv(matches: &ArgMatches) -> bool {
+    matches.value_of("format") == Some("csv")
+}
+
+/// Read dense or CSV data with the labels as strings.
+fn read_instances(matches: &ArgMatches, path: &str) -> Vec<Instance<String>> {
+    let file = File::open(path).or_exit(format!("Cannot open {}", path), 1);
+    let read = BufReader::new(file);
+
+    let instances: Result<Vec<_>, _> = match matches.value_of("format").unwrap_or("dense") {
+        "dense" => LabeledInstanceIter::new(read).collect(),
+        "csv" => CsvIter::new(read, &csv_options(matches))
+            .or_exit(format!("Cannot read {}", path), 1)
+            .collect(),
+        "svmlight" => {
+            eprintln!("Sparse svmlight data can only be used to evaluate classifiers on test data");
+            process::exit(1);
+        }
+        _ => unreachable!(),
+    };
+
+    instances.or_exit(format!("Cannot read instance from {}", path), 1)
+}
+
+/// Read classification data.
+///
+/// CSV class names are mapped to labels with the dictionary, other data
+/// must have numeric labels.
+fn class_instances(matches: &ArgMatches, path: &str, names: &mut LabelDictionary) -> Vec<Instance> {
+    let instances = read_instances(matches, path);
+    if is_csv(matches) {
+        convert_labels(instances, |label| names.encode(label))
+    } else {
+        convert_labels(instances, |label| {
+            label
+                .parse()
+                .or_exit(format!("Invalid class label: {}", label), 1)
+        })
+    }
+}
+
+/// Read regression data.
+fn regression_instances(matches: &ArgMatches, path: &str) -> Vec<Instance<f32>> {
+    convert_labels(read_instances(matches, path), |label| {
+        label
+            .parse()
+            .or_exit(format!("Invalid target: {}", label), 1)
+    })
+}
+
+fn convert_labels<L, F>(instances: Vec<Instance<String>>, mut convert: F) -> Vec<Instance<L>>
+where
+    F: FnMut(&str) -> L,
+{
+    instances
+        .into_iter()
+        .map(|instance| Instance {
+            label: convert(&instance.label),
+            features: instance.features,
+        })
+        .collect()
+}
+
+fn csv_options(matches: &ArgMatches) -> CsvOptions {
+    let defaults = CsvOptions::default();
+
+    let delimiter = match matches.value_of("delimiter") {
+        Some("\\t") => b'\t',
+        Some(delimiter) if delimiter.len() == 1 => delimiter.as_bytes()[0],
+        Some(delimiter) => {
+            eprintln!(
+                "The delimiter should be a single character, was: {}",
+                delimiter
+            );
+            process::exit(1);
+        }
+        None => defaults.delimiter,
+    };
+
+    CsvOptions {
+        delimiter,
+        has_header: !matches.is_present("no_header"),
+        label_column: matches
+            .value_of("label_column")
+            .map(Column::from)
+            .unwrap_or(defaults.label_column),
+        feature_columns: matches
+            .value_of("columns")
+            .map(|columns| columns.split(',').map(|c| Column::from(c.trim())).collect()),
+    }
+}
+
+fn weights(matches: &ArgMatches) -> Weights {
+    weights_by_name(matches, matches.value_of("weights").unwrap_or("uniform"))
+}
+
+fn weights_by_name(matches: &ArgMatches, name: &str) -> Weights {
+    match name {
+        "uniform" => Weights::Uniform,
+        "distance" => Weights::InverseDistance,
+        "distance2" => Weights::InverseSquaredDistance,
+        "gaussian" => {
+            let bandwidth: f32 = parse_or(matches, "bandwidth", 1.);
+            if !(bandwidth.is_finite() && bandwidth > 0.) {
+                eprintln!("The bandwidth should be positive and finite");
+                process::exit(1);
+            }
+            Weights::Gaussian { bandwidth }
+        }
+        name => {
+            eprintln!("Unknown neighbor weights: {}", name);
+            process::exit(1);
+        }
+    }
+}
+
+/// Get the outlier label, adding it to the class names of CSV data.
+fn outlier_label(matches: &ArgMatches, names: &mut LabelDictionary) -> Option<usize> {
+    let outlier = matches.value_of("outlier")?;
+    if is_csv(matches) {
+        Some(names.encode(outlier))
+    } else {
+        Some(
+            outlier
+                .parse()
+                .or_exit(format!("Invalid class label: {}", outlier), 1),
+        )
+    }
+}
+
+/// Get the tie breaking. Random tie breaking uses the seed option.
+fn tie_breaking(matches: &ArgMatches) -> TieBreaking {
+    match matches.value_of("ties").unwrap_or("smallest-label") {
+        "smallest-label" => TieBreaking::SmallestLabel,
+        "nearest" => TieBreaking::NearestNeighbor,
+        "distance" => TieBreaking::SmallestDistance,
+        "random" => TieBreaking::Random {
+            seed: parse_or(matches, "seed", 42),
+        },
+        "decrease-k" => TieBreaking::DecreaseK,
+        _ => unreachable!(),
+    }
+}
+
+fn metric<L>(matches: &ArgMatches, name: &str, builder: &KNNBuilder<L>) -> Metrics {
+    match name {
+        "euclidean" => Metrics::Euclidean,
+        "sqeuclidean" => Metrics::SquaredEuclidean,
+        "manhattan" => Metrics::Manhattan,
+        "chebyshev" => Metrics::Chebyshev,
+        "minkowski" => {
+            let p: f32 = parse_or(matches, "minkowski_p", 2.);
+            if p.is_nan() || p <= 0. {
+                eprintln!("The order of the Minkowski distance should be positive");
+                process::exit(1);
+            }
+            Metrics::Minkowski(Minkowski::new(p))
+        }
+        "cosine" => Metrics::Cosine,
+        "mahalanobis" => {
+            let covariance = match matches.value_of("covariance").unwrap_or("ledoit-wolf") {
+                "empirical" => Covariance::Empirical,
+                "shrinkage" => {
+                    let shrinkage: f32 = parse_or(matches, "shrinkage", 0.1);
+                    if !(0. ..=1.).contains(&shrinkage) {
+                        eprintln!("The shrinkage intensity should be in [0, 1]");
+                        process::exit(1);
+                    }
+                    Covariance::Shrinkage(shrinkage)
+                }
+                "ledoit-wolf" => Covariance::LedoitWolf,
+                _ => unreachable!(),
+            };
+            Metrics::Mahalanobis(
+                builder
+                    .fit_mahalanobis(covariance)
+                    .or_exit("Cannot fit Mahalanobis distance", 1),
+            )
+        }
+        name => {
+            eprintln!("Unknown metric: {}", name);
+            process::exit(1);
+        }
+    }
+}
+
+/// Get the search strategy, if one is specified.
+///
+/// Otherwise, the builder uses the KD-tree if it supports the metric and
+/// brute force search otherwise.
+fn index_type(matches: &ArgMatches) -> Option<IndexType> {
+    let index_type = match matches.value_of("index")? {
+        "brute" => IndexType::BruteForce,
+        "kdtree" => IndexType::KdTree,
+        "balltree" => IndexType::BallTree,
+        "hnsw" => {
+            let defaults = HnswParams::default();
+            IndexType::Hnsw(HnswParams {
+                m: parse_or(matches, "hnsw_m", defaults.m),
+                ef_construction: parse_or(matches, "ef_construction", defaults.ef_construction),
+                ef_search: parse_or(matches, "ef_search", defaults.ef_search),
+                seed: parse_or(matches, "seed", defaults.seed),
+            })
+        }
+        "lsh" => {
+            let defaults = LshParams::default();
+            IndexType::Lsh(LshParams {
+                n_tables: parse_or(matches, "lsh_tables", defaults.n_tables),
+                n_bits: parse_or(matches, "lsh_bits", defaults.n_bits),
+                n_probes: parse_or(matches, "lsh_probes", defaults.n_probes),
+                seed: parse_or(matches, "seed", defaults.seed),
+            })
+        }
+        _ => unreachable!(),
+    };
+
+    Some(index_type)
+}
+
+/// Parse the value of an option, using a default when it is absent.
+fn parse_or<T>(matches: &ArgMatches, name: &str, default: T) -> T
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    matches
+        .value_of(name)
+        .map(|v| {
+            v.parse()
+                .or_exit(format!("Invalid value for {}: {}", name, v), 1)
+        })
+        .unwrap_or(default)
 }
@@ -0,0 +1,99 @@
+use std::fs::File;
+use std::path::Path;
+
+use failure::Error;
+use memmap::Mmap;
+
+use KNNView;
+
+/// A memory-mapped model file.
+///
+/// The feature matrix of models that are loaded from a memory-mapped
+/// file is not copied to the heap. Pages are read on demand and
+/// processes that map the same file share the page cache.
+///
+/// The model file must not be modified while it is mapped.
+pub struct MmapModel {
+    mmap: Mmap,
+}
+
+impl MmapModel {
+    /// Memory-map a model file.
+    pub fn open<P>(path: P) -> Result<Self, Error>
+    where
+        P: AsRef<Path>,
+    {
+        let file = File::open(path)?;
+
+        // The mapping is read-only. Modification of the file by another
+        // process is the responsibility of the caller.
+        let mmap = unsafe { Mmap::map(&file)? };
+
+        Ok(MmapModel { mmap })
+    }
+
+    /// Get the classifier, borrowing its feature matrix from the mapping.
+    ///
+    /// The header and index of the model are verified. The feature
+    /// matrix is not covered by the checksum and is only read on demand.
+    pub fn model(&self) -> Result<KNNView<'_>, Error> {
+        KNNView::load_view(&self.mmap)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::process;
+
+    use index::tests::{feature_matrix, read_instances};
+    use {IndexType, KNNBuilder, KNNView, MmapModel, KNN};
+
+    fn moons_model() -> KNN {
+        let mut builder = KNNBuilder::default();
+        builder.set_index_type(IndexType::BallTree);
+        for instance in read_instances(include_str!("../moons-train.txt")) {
+            builder.push(instance);
+        }
+        builder.into()
+    }
+
+    #[test]
+    fn mmap_test() {
+        let model = moons_model();
+        let test = feature_matrix(&read_instances(include_str!("../moons-test.txt")));
+
+        let path = env::temp_dir().join(format!("knn-mmap-test-{}.model", process::id()));
+        model.save(File::create(&path).unwrap()).unwrap();
+
+        {
+            let mmap = MmapModel::open(&path).unwrap();
+            let mapped = mmap.model().unwrap();
+            assert_eq!(
+                mapped.classify_batch(&test.view(), 5),
+                model.classify_batch(&test.view(), 5)
+            );
+        }
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn misaligned_test() {
+        let mut data = Vec::new();
+        moons_model().save(&mut data).unwrap();
+
+        // Copy the model to an offset that is not aligned for f32.
+        let mut buffer = vec![0; data.len() + 2];
+        let offset = if (buffer.as_ptr() as usize + 1) % 4 != 0 {
+            1
+        } else {
+            2
+        };
+        buffer[offset..offset + data.len()].copy_from_slice(&data);
+        let misaligned = &buffer[offset..offset + data.len()];
+
+        assert!(KNNView::load_view(misaligned).is_err());
+    }
+}
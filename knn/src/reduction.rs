@@ -0,0 +1,195 @@
+use ordered_float::OrderedFloat;
+
+use knn::vote;
+use search::Search;
+use {TieBreaking, Weights};
+
+/// Prototype selection algorithms, which remove training data points
+/// to make models smaller or to remove noise.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reduction {
+    /// Hart's condensed nearest neighbor rule.
+    ///
+    /// The set of prototypes starts with the first data point. Data points
+    /// are added when their nearest prototype has a different label, until
+    /// all data points are classified correctly by their nearest
+    /// prototype. This removes data points far from class boundaries.
+    Condensed,
+
+    /// Wilson's edited nearest neighbor rule.
+    ///
+    /// Data points that are misclassified by a majority vote of their k
+    /// nearest neighbors are removed. This removes noise and smooths
+    /// class boundaries.
+    Edited { k: usize },
+
+    /// Removal of Tomek links.
+    ///
+    /// Two data points with different labels form a Tomek link when they
+    /// are each other's nearest neighbor. Both data points of every link
+    /// are removed, which cleans class boundaries.
+    TomekLinks,
+}
+
+impl Reduction {
+    /// Select the data points to keep.
+    pub(crate) fn select(self, labels: &[usize], search: &Search) -> Vec<bool> {
+        match self {
+            Reduction::Condensed => condensed(labels, search),
+            Reduction::Edited { k } => {
+                assert!(k > 0, "k should at least be 1");
+                edited(labels, search, k)
+            }
+            Reduction::TomekLinks => tomek_links(labels, search),
+        }
+    }
+}
+
+fn condensed(labels: &[usize], search: &Search) -> Vec<bool> {
+    let mut keep = vec![false; labels.len()];
+    if labels.is_empty() {
+        return keep;
+    }
+
+    keep[0] = true;
+    let mut prototypes = vec![0];
+
+    // Pass over the data until no prototypes are added.
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for idx in 0..labels.len() {
+            if keep[idx] {
+                continue;
+            }
+
+            let nearest = *prototypes
+                .iter()
+                .min_by_key(|&&prototype| OrderedFloat(search.distance(idx, prototype)))
+                .unwrap();
+            if labels[nearest] != labels[idx] {
+                keep[idx] = true;
+                prototypes.push(idx);
+                changed = true;
+            }
+        }
+    }
+
+    keep
+}
+
+fn edited(labels: &[usize], search: &Search, k: usize) -> Vec<bool> {
+    search
+        .k_nearest_others(k)
+        .iter()
+        .enumerate()
+        .map(|(idx, nearest_neighbors)| {
+            nearest_neighbors.is_empty()
+                || vote(
+                    labels,
+                    None,
+                    Weights::Uniform,
+                    TieBreaking::SmallestLabel,
+                    nearest_neighbors,
+                ) == labels[idx]
+        })
+        .collect()
+}
+
+fn tomek_links(labels: &[usize], search: &Search) -> Vec<bool> {
+    let nearest: Vec<_> = search
+        .k_nearest_others(1)
+        .iter()
+        .map(|nearest_neighbors| nearest_neighbors.first().map(|neighbor| neighbor.idx))
+        .collect();
+
+    nearest
+        .iter()
+        .enumerate()
+        .map(|(idx, &nearest_idx)| match nearest_idx {
+            Some(nearest_idx) => {
+                labels[idx] == labels[nearest_idx] || nearest[nearest_idx] != Some(idx)
+            }
+            None => true,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use index::tests::{feature_matrix, read_instances};
+    use {Evaluator, IndexType, Instance, KNNBuilder, Reduction, KNN};
+
+    fn builder(points: &[(f32, usize)]) -> KNNBuilder {
+        let mut builder = KNNBuilder::default();
+        builder.set_index_type(IndexType::BruteForce);
+        for &(x, label) in points {
+            builder.push(Instance {
+                label,
+                features: vec![x],
+            });
+        }
+        builder
+    }
+
+    #[test]
+    fn reduction_test() {
+        // Two classes, with a noisy point of class 1 at 1.5.
+        let points = [
+            (0., 0),
+            (1., 0),
+            (1.5, 1),
+            (2., 0),
+            (3., 0),
+            (4., 0),
+            (7., 1),
+            (8., 1),
+            (9., 1),
+            (10., 1),
+        ];
+
+        // The noisy point is removed by editing.
+        let mut edited = builder(&points);
+        edited.reduce(Reduction::Edited { k: 3 });
+        assert_eq!(edited.len(), 9);
+        let model: KNN = edited.into();
+        assert_eq!(model.classify(&[1.5], 1), 0);
+
+        // The noisy point forms a Tomek link with its nearest neighbor 1.
+        let mut tomek = builder(&points);
+        tomek.reduce(Reduction::TomekLinks);
+        assert_eq!(tomek.len(), 8);
+        let model: KNN = tomek.into();
+        assert_eq!(model.classify(&[1.], 1), 0);
+
+        // Condensing keeps the points near the boundaries.
+        let mut condensed = builder(&points);
+        condensed.reduce(Reduction::Condensed);
+        assert!(condensed.len() < points.len());
+        let model: KNN = condensed.into();
+        for &(x, label) in &points {
+            assert_eq!(model.classify(&[x], 1), label);
+        }
+    }
+
+    #[test]
+    fn condensed_moons_test() {
+        let train = read_instances(include_str!("../moons-train.txt"));
+        let mut builder = KNNBuilder::default();
+        for instance in train.iter().cloned() {
+            builder.push(instance);
+        }
+        builder.reduce(Reduction::Condensed);
+        assert!(builder.len() < train.len() / 2);
+
+        // The condensed set classifies the training data correctly with
+        // the nearest neighbor.
+        let model: KNN = builder.into();
+        let features = feature_matrix(&train);
+        let mut eval = Evaluator::default();
+        for (instance, predicted) in train.iter().zip(model.classify_batch(&features.view(), 1)) {
+            eval.count(instance.label, predicted);
+        }
+        assert_eq!(eval.accuracy(), 1.);
+    }
+}
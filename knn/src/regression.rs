@@ -0,0 +1,117 @@
+use ndarray::prelude::*;
+
+use index::Neighbor;
+use search::Search;
+use {KNNBuilder, Weights};
+
+/// A K Nearest Neighbor regressor.
+///
+/// The prediction for a data point is the (weighted) mean of the targets
+/// of its nearest neighbors.
+pub struct KNNRegressor {
+    targets: Vec<f32>,
+    search: Search,
+    weights: Weights,
+}
+
+impl From<KNNBuilder<f32>> for KNNRegressor {
+    fn from(builder: KNNBuilder<f32>) -> Self {
+        let (targets, search) = builder.build();
+        KNNRegressor {
+            targets,
+            search,
+            weights: Weights::default(),
+        }
+    }
+}
+
+impl KNNRegressor {
+    /// Get the number of features of data points.
+    pub fn n_features(&self) -> usize {
+        self.search.n_features()
+    }
+
+    /// Set the weighting of neighbors.
+    ///
+    /// By default, all neighbors have the same weight, so the prediction
+    /// is the mean of the targets of the neighbors.
+    /// Panics when the Gaussian bandwidth is not positive and finite.
+    pub fn set_weights(&mut self, weights: Weights) {
+        assert!(
+            weights.is_valid(),
+            "The Gaussian bandwidth should be positive and finite"
+        );
+        self.weights = weights;
+    }
+
+    /// Predict the target of a data point.
+    ///
+    /// The feature vector and the number of nearest neighbors to use are
+    /// specified as arguments.
+    pub fn predict(&self, features: &[f32], k: usize) -> f32 {
+        let features = ArrayView1::from_shape([features.len()], features).unwrap();
+        self.aggregate(&self.search.k_nearest(features, k))
+    }
+
+    /// Predict the targets of a batch of data points.
+    ///
+    /// Every row of the feature matrix is a data point. The data points
+    /// are processed in parallel, using the global rayon thread pool.
+    pub fn predict_batch(&self, features: &ArrayView2<f32>, k: usize) -> Vec<f32> {
+        self.search
+            .k_nearest_batch(features, k, |nearest_neighbors| {
+                self.aggregate(nearest_neighbors)
+            })
+    }
+
+    /// Compute the weighted mean of the targets of the nearest neighbors.
+    fn aggregate(&self, nearest_neighbors: &[Neighbor]) -> f32 {
+        let weights = self.weights.neighbor_weights(nearest_neighbors);
+
+        let weighted_sum: f32 = nearest_neighbors
+            .iter()
+            .zip(&weights)
+            .map(|(neighbor, &weight)| weight * self.targets[neighbor.idx])
+            .sum();
+
+        weighted_sum / weights.iter().sum::<f32>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {Instance, KNNBuilder, KNNRegressor, Weights};
+
+    fn regressor() -> KNNRegressor {
+        let mut builder = KNNBuilder::default();
+        for &(x, target) in &[(0., 1.), (1., 2.), (3., 4.), (10., 100.)] {
+            builder.push(Instance {
+                label: target,
+                features: vec![x],
+            });
+        }
+        builder.into()
+    }
+
+    #[test]
+    fn mean_test() {
+        let model = regressor();
+        assert_abs_diff_eq!(model.predict(&[0.5], 2), 1.5);
+        assert_abs_diff_eq!(model.predict(&[2.], 3), 7. / 3., epsilon = 1e-6);
+    }
+
+    #[test]
+    fn distance_weighted_mean_test() {
+        let mut model = regressor();
+        model.set_weights(Weights::InverseDistance);
+
+        assert_abs_diff_eq!(
+            model.predict(&[1.6], 2),
+            (2. / 0.6 + 4. / 1.4) / (1. / 0.6 + 1. / 1.4),
+            epsilon = 1e-5
+        );
+
+        // Exact match.
+        assert_eq!(model.predict(&[3.], 3), 4.);
+    }
+}
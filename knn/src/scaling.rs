@@ -0,0 +1,229 @@
+use std::io::Write;
+
+use byteorder::WriteBytesExt;
+use failure::Error;
+use ndarray::prelude::*;
+
+use serialization::{invalid_model, ModelReader, ModelWriter};
+
+/// Scaling of the features of data points.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Scaling {
+    /// Standardize every feature to zero mean and unit variance.
+    Standard,
+
+    /// Scale every feature to the range [0, 1] of the training data.
+    MinMax,
+
+    /// Center every feature on its median and divide it by its
+    /// interquartile range. This scaling is robust to outliers.
+    Robust,
+
+    /// Normalize every data point to unit L2 norm.
+    L2,
+}
+
+/// A feature scaler that is fitted on training data.
+///
+/// With the exception of L2 normalization, every feature is transformed
+/// as `(x - offset) / scale`, where the offset and scale of the feature
+/// are estimated from the training data. Features that are constant in
+/// the training data are only shifted.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Scaler {
+    scaling: Scaling,
+    offsets: Array1<f32>,
+    scales: Array1<f32>,
+}
+
+impl Scaler {
+    /// Fit a scaler on a feature matrix.
+    ///
+    /// Every row of the feature matrix is a data point.
+    pub fn fit(features: ArrayView2<f32>, scaling: Scaling) -> Self {
+        assert!(features.rows() > 0, "Cannot fit a scaler without data");
+
+        let (offsets, scales): (Vec<_>, Vec<_>) = match scaling {
+            Scaling::Standard => features
+                .gencolumns()
+                .into_iter()
+                .map(|column| {
+                    let n = column.len() as f32;
+                    let mean = column.scalar_sum() / n;
+                    let variance = column.iter().map(|&v| (v - mean) * (v - mean)).sum::<f32>() / n;
+                    (mean, variance.sqrt())
+                })
+                .unzip(),
+            Scaling::MinMax => features
+                .gencolumns()
+                .into_iter()
+                .map(|column| {
+                    let min = column.iter().cloned().fold(f32::INFINITY, f32::min);
+                    let max = column.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+                    (min, max - min)
+                })
+                .unzip(),
+            Scaling::Robust => features
+                .gencolumns()
+                .into_iter()
+                .map(|column| {
+                    let mut sorted = column.to_vec();
+                    sorted.sort_by(|a, b| a.partial_cmp(b).expect("Feature value is NaN"));
+                    (
+                        quantile(&sorted, 0.5),
+                        quantile(&sorted, 0.75) - quantile(&sorted, 0.25),
+                    )
+                })
+                .unzip(),
+            Scaling::L2 => (Vec::new(), Vec::new()),
+        };
+
+        Scaler {
+            scaling,
+            offsets: Array1::from_vec(offsets),
+            scales: scales
+                .into_iter()
+                .map(|scale| if scale > 0. { scale } else { 1. })
+                .collect(),
+        }
+    }
+
+    /// Get the scaling that the scaler was fitted for.
+    pub fn scaling(&self) -> Scaling {
+        self.scaling
+    }
+
+    /// Scale a feature vector.
+    pub fn transform(&self, features: ArrayView1<f32>) -> Array1<f32> {
+        match self.scaling {
+            Scaling::L2 => {
+                let norm = features.iter().map(|&v| v * v).sum::<f32>().sqrt();
+                if norm > 0. {
+                    &features / norm
+                } else {
+                    features.to_owned()
+                }
+            }
+            _ => (&features - &self.offsets) / &self.scales,
+        }
+    }
+
+    /// Scale every row of a feature matrix.
+    pub fn transform_rows(&self, features: ArrayView2<f32>) -> Array2<f32> {
+        match self.scaling {
+            Scaling::L2 => {
+                let mut scaled = features.to_owned();
+                for mut row in scaled.outer_iter_mut() {
+                    let norm = row.iter().map(|&v| v * v).sum::<f32>().sqrt();
+                    if norm > 0. {
+                        row /= norm;
+                    }
+                }
+                scaled
+            }
+            _ => (&features - &self.offsets) / &self.scales,
+        }
+    }
+
+    pub(crate) fn write<W>(&self, writer: &mut ModelWriter<W>) -> Result<(), Error>
+    where
+        W: Write,
+    {
+        let tag = match self.scaling {
+            Scaling::Standard => 0,
+            Scaling::MinMax => 1,
+            Scaling::Robust => 2,
+            Scaling::L2 => 3,
+        };
+        writer.write_u8(tag)?;
+        writer.write_f32s(&self.offsets)?;
+        writer.write_f32s(&self.scales)?;
+
+        Ok(())
+    }
+
+    /// Read a scaler for feature vectors of the given length.
+    pub(crate) fn read(reader: &mut ModelReader, n_features: usize) -> Result<Self, Error> {
+        let scaling = match reader.read_u8()? {
+            0 => Scaling::Standard,
+            1 => Scaling::MinMax,
+            2 => Scaling::Robust,
+            3 => Scaling::L2,
+            _ => return Err(invalid_model("unknown feature scaling")),
+        };
+
+        let n_params = if scaling == Scaling::L2 {
+            0
+        } else {
+            n_features
+        };
+        let offsets = Array1::from_vec(reader.read_f32s(n_params)?);
+        let scales = Array1::from_vec(reader.read_f32s(n_params)?);
+        if scales.iter().any(|&scale| scale.is_nan() || scale <= 0.) {
+            return Err(invalid_model("invalid feature scale"));
+        }
+
+        Ok(Scaler {
+            scaling,
+            offsets,
+            scales,
+        })
+    }
+}
+
+/// Get a quantile of sorted values, interpolating linearly between the
+/// closest values.
+pub(crate) fn quantile(sorted: &[f32], q: f32) -> f32 {
+    let pos = q * (sorted.len() - 1) as f32;
+    let lower = pos.floor() as usize;
+    let upper = pos.ceil() as usize;
+    sorted[lower] + (pos - lower as f32) * (sorted[upper] - sorted[lower])
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::prelude::*;
+
+    use {Scaler, Scaling};
+
+    #[test]
+    fn scaler_test() {
+        // The second feature is constant.
+        let features = arr2(&[[1., 5.], [2., 5.], [3., 5.], [10., 5.]]);
+
+        let standard = Scaler::fit(features.view(), Scaling::Standard);
+        let scaled = standard.transform_rows(features.view());
+        assert_abs_diff_eq!(scaled.column(0).scalar_sum(), 0., epsilon = 1e-5);
+        assert_abs_diff_eq!(
+            scaled.column(0).mapv(|v| v * v).scalar_sum(),
+            4.,
+            epsilon = 1e-5
+        );
+        assert_eq!(scaled.column(1), arr1(&[0., 0., 0., 0.]));
+
+        let min_max = Scaler::fit(features.view(), Scaling::MinMax);
+        assert!(min_max
+            .transform(arr1(&[5.5, 6.]).view())
+            .all_close(&arr1(&[0.5, 1.]), 1e-6));
+
+        // Median 2.5, interquartile range 4.75 - 1.75 = 3.
+        let robust = Scaler::fit(features.view(), Scaling::Robust);
+        assert!(robust
+            .transform(arr1(&[8.5, 5.]).view())
+            .all_close(&arr1(&[2., 0.]), 1e-6));
+
+        let l2 = Scaler::fit(features.view(), Scaling::L2);
+        assert!(l2
+            .transform(arr1(&[3., 4.]).view())
+            .all_close(&arr1(&[0.6, 0.8]), 1e-6));
+        assert_eq!(l2.transform(arr1(&[0., 0.]).view()), arr1(&[0., 0.]));
+
+        // Rows are transformed like individual vectors.
+        for scaler in &[standard, min_max, robust, l2] {
+            let scaled = scaler.transform_rows(features.view());
+            for (row, scaled) in features.outer_iter().zip(scaled.outer_iter()) {
+                assert_eq!(scaler.transform(row), scaled);
+            }
+        }
+    }
+}
@@ -0,0 +1,579 @@
+use std::collections::HashSet;
+use std::io::Write;
+
+use byteorder::WriteBytesExt;
+
+use failure::Error;
+use ndarray::prelude::*;
+use ndarray::{Data, OwnedRepr, ViewRepr};
+use rayon::prelude::*;
+
+use batch::{euclidean_k_nearest_batch, squared_row_norms};
+use index::{brute_force_k_nearest, push_within_radius, Index, NearestNeighbors, Neighbor};
+use serialization::{invalid_model, ModelReader, ModelWriter, FEATURES_ALIGNMENT};
+use {IndexType, Mahalanobis, Metric, Metrics, Scaler};
+
+/// Number of data points per block in batch search.
+const QUERY_BLOCK_SIZE: usize = 64;
+
+/// Nearest neighbor search over the training instances of a model.
+///
+/// When a feature scaler is used, the training instances are stored
+/// scaled and queries are scaled before search. With the Mahalanobis
+/// distance, the (scaled) training instances are stored whitened and
+/// queries are whitened before Euclidean search.
+///
+/// The feature matrix is owned or borrowed, for instance from a
+/// memory-mapped model file.
+///
+/// Instances can be inserted and removed after construction. Inserted
+/// instances are stored after the rows of the feature matrix and searched
+/// by brute force, removed instances are skipped during search. Compaction
+/// rebuilds the feature matrix and the index from the remaining instances.
+pub(crate) struct Search<S = OwnedRepr<f32>>
+where
+    S: Data<Elem = f32>,
+{
+    features: ArrayBase<S, Ix2>,
+    metric: Metrics,
+    scaler: Option<Scaler>,
+    whitening: Option<Mahalanobis>,
+    index: Index,
+    inserted: Vec<f32>,
+    removed: HashSet<usize>,
+}
+
+impl Search {
+    pub fn new(
+        features: Array2<f32>,
+        index_type: IndexType,
+        metric: Metrics,
+        scaler: Option<Scaler>,
+    ) -> Self {
+        let features = match scaler {
+            Some(ref scaler) => scaler.transform_rows(features.view()),
+            None => features,
+        };
+
+        let (features, metric, whitening) = match metric {
+            Metrics::Mahalanobis(mahalanobis) => {
+                assert_eq!(
+                    mahalanobis.whitening().cols(),
+                    features.cols(),
+                    "Mahalanobis distance was fitted on a different number of features"
+                );
+                (
+                    mahalanobis.whiten_rows(features.view()),
+                    Metrics::Euclidean,
+                    Some(mahalanobis),
+                )
+            }
+            metric => (features, metric, None),
+        };
+
+        let index = Index::new(index_type, features.view(), &metric);
+
+        Search {
+            features,
+            metric,
+            scaler,
+            whitening,
+            index,
+            inserted: Vec::new(),
+            removed: HashSet::new(),
+        }
+    }
+
+    /// Rebuild the feature matrix and the index from the instances that
+    /// were not removed, including inserted instances.
+    ///
+    /// Returns for every instance before compaction whether it was kept.
+    /// The kept instances retain their order.
+    pub fn compact(&mut self) -> Vec<bool> {
+        let keep: Vec<_> = (0..self.n_instances())
+            .map(|idx| !self.removed.contains(&idx))
+            .collect();
+        let n_kept = keep.iter().filter(|&&keep| keep).count();
+        assert!(n_kept > 0, "Cannot compact a model without instances");
+
+        let mut features = Vec::with_capacity(n_kept * self.n_features());
+        for (instance, &keep) in self
+            .features
+            .outer_iter()
+            .chain(self.inserted_features().outer_iter())
+            .zip(&keep)
+        {
+            if keep {
+                features.extend(instance.iter());
+            }
+        }
+        let features = Array2::from_shape_vec((n_kept, self.n_features()), features)
+            .expect("Number of elements does not correspond to the shape");
+
+        self.index = Index::new(self.index.index_type(), features.view(), &self.metric);
+        self.features = features;
+        self.inserted.clear();
+        self.removed.clear();
+
+        keep
+    }
+
+    /// Read a search structure, copying the feature matrix.
+    pub fn read(reader: &mut ModelReader) -> Result<Self, Error> {
+        Self::read_with(reader, |reader, n_instances, n_features| {
+            let n_values = n_instances
+                .checked_mul(n_features)
+                .ok_or_else(|| invalid_model("file is truncated"))?;
+            Ok(
+                Array2::from_shape_vec((n_instances, n_features), reader.read_f32s(n_values)?)
+                    .expect("Number of elements does not correspond to the shape"),
+            )
+        })
+    }
+}
+
+impl<'a> Search<ViewRepr<&'a f32>> {
+    /// Read a search structure, borrowing the feature matrix from the
+    /// data of the reader.
+    pub fn read_view(reader: &mut ModelReader<'a>) -> Result<Self, Error> {
+        Self::read_with(reader, |reader, n_instances, n_features| {
+            reader.read_f32_view(n_instances, n_features)
+        })
+    }
+}
+
+impl<S> Search<S>
+where
+    S: Data<Elem = f32>,
+{
+    fn read_with<'a, F>(reader: &mut ModelReader<'a>, read_features: F) -> Result<Self, Error>
+    where
+        F: FnOnce(&mut ModelReader<'a>, usize, usize) -> Result<ArrayBase<S, Ix2>, Error>,
+    {
+        let n_instances = reader.read_usize()?;
+        let n_features = reader.read_usize()?;
+
+        let scaler = match reader.read_u8()? {
+            0 => None,
+            1 => Some(Scaler::read(reader, n_features)?),
+            _ => return Err(invalid_model("invalid feature scaler")),
+        };
+
+        let (metric, whitening) = match Metrics::read(reader, n_features)? {
+            Metrics::Mahalanobis(mahalanobis) => (Metrics::Euclidean, Some(mahalanobis)),
+            metric => (metric, None),
+        };
+
+        let index = Index::read(reader, n_instances, n_features)?;
+
+        reader.align(FEATURES_ALIGNMENT)?;
+        let features = read_features(reader, n_instances, n_features)?;
+
+        Ok(Search {
+            features,
+            metric,
+            scaler,
+            whitening,
+            index,
+            inserted: Vec::new(),
+            removed: HashSet::new(),
+        })
+    }
+
+    /// Write the feature scaler, metric, index, and feature matrix.
+    ///
+    /// The feature matrix is written last, aligned to
+    /// `FEATURES_ALIGNMENT` bytes. It is not included in the checksum, so
+    /// that it is not read when a memory-mapped model is opened. Only
+    /// compacted search structures can be written.
+    pub fn write<W>(&self, writer: &mut ModelWriter<W>) -> Result<(), Error>
+    where
+        W: Write,
+    {
+        assert!(
+            self.is_compact(),
+            "Search structure should be compacted before writing"
+        );
+
+        writer.write_usize(self.features.rows())?;
+        writer.write_usize(self.features.cols())?;
+
+        match self.scaler {
+            Some(ref scaler) => {
+                writer.write_u8(1)?;
+                scaler.write(writer)?;
+            }
+            None => writer.write_u8(0)?,
+        }
+
+        match self.whitening {
+            Some(ref mahalanobis) => Metrics::Mahalanobis(mahalanobis.clone()).write(writer)?,
+            None => self.metric.write(writer)?,
+        }
+
+        self.index.write(writer)?;
+
+        writer.align(FEATURES_ALIGNMENT)?;
+        writer.end_checksum();
+        writer.write_f32s(&self.features)?;
+
+        Ok(())
+    }
+
+    /// Get the number of data points, including inserted and removed
+    /// data points.
+    pub fn n_instances(&self) -> usize {
+        self.features.rows() + self.inserted.len() / self.n_features().max(1)
+    }
+
+    /// Get the number of removed data points.
+    pub fn n_removed(&self) -> usize {
+        self.removed.len()
+    }
+
+    /// Check whether no data points were inserted or removed since
+    /// construction or compaction.
+    pub fn is_compact(&self) -> bool {
+        self.inserted.is_empty() && self.removed.is_empty()
+    }
+
+    /// Check whether a data point was removed.
+    pub fn is_removed(&self, idx: usize) -> bool {
+        self.removed.contains(&idx)
+    }
+
+    /// Insert a data point, returning its index.
+    pub fn insert(&mut self, features: ArrayView1<f32>) -> usize {
+        self.check_n_features(features.len());
+
+        let idx = self.n_instances();
+        match self.transform(features) {
+            Some(transformed) => self.inserted.extend(transformed.iter()),
+            None => self.inserted.extend(features.iter()),
+        }
+
+        idx
+    }
+
+    /// Remove a data point.
+    ///
+    /// Returns `false` if the data point was already removed.
+    pub fn remove(&mut self, idx: usize) -> bool {
+        assert!(idx < self.n_instances(), "Index out of bounds: {}", idx);
+        self.removed.insert(idx)
+    }
+
+    /// Get the (scaled and whitened) features of the inserted data points.
+    fn inserted_features(&self) -> ArrayView2<'_, f32> {
+        let n_features = self.n_features();
+        ArrayView2::from_shape(
+            (self.inserted.len() / n_features.max(1), n_features),
+            &self.inserted,
+        )
+        .expect("Number of elements does not correspond to the shape")
+    }
+
+    /// Get the number of features of data points.
+    pub fn n_features(&self) -> usize {
+        self.features.cols()
+    }
+
+    /// Get the (scaled and whitened) features of a data point, which may
+    /// be an inserted data point.
+    fn instance(&self, idx: usize) -> ArrayView1<'_, f32> {
+        let n_indexed = self.features.rows();
+        if idx < n_indexed {
+            self.features.row(idx)
+        } else {
+            let n_features = self.n_features();
+            let start = (idx - n_indexed) * n_features;
+            ArrayView1::from_shape([n_features], &self.inserted[start..start + n_features])
+                .expect("Number of elements does not correspond to the shape")
+        }
+    }
+
+    /// Compute the distance between two data points of the search
+    /// structure.
+    pub fn distance(&self, idx1: usize, idx2: usize) -> f32 {
+        self.metric
+            .distance(self.instance(idx1), self.instance(idx2))
+    }
+
+    /// Find the k nearest neighbors of every data point of the search
+    /// structure, excluding the data point itself.
+    ///
+    /// The data points are processed in parallel, using the global rayon
+    /// thread pool. Removed data points do not have neighbors.
+    pub fn k_nearest_others(&self, k: usize) -> Vec<Vec<Neighbor>>
+    where
+        S: Sync,
+    {
+        assert!(k > 0, "k should at least be 1");
+
+        (0..self.n_instances())
+            .into_par_iter()
+            .map(|idx| {
+                if self.removed.contains(&idx) {
+                    return Vec::new();
+                }
+
+                // Identical data points can precede the data point itself,
+                // so it is filtered rather than skipped.
+                self.k_nearest_transformed(self.instance(idx), k + 1)
+                    .into_iter()
+                    .filter(|neighbor| neighbor.idx != idx)
+                    .take(k)
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn check_n_features(&self, n_features: usize) {
+        assert_eq!(
+            n_features,
+            self.features.cols(),
+            "Expected a feature vector of size {}, got {}",
+            self.features.cols(),
+            n_features
+        );
+    }
+
+    /// Find the k nearest neighbors of a data point.
+    pub fn k_nearest(&self, features: ArrayView1<f32>, k: usize) -> Vec<Neighbor> {
+        assert!(k > 0, "k should at least be 1");
+        self.check_n_features(features.len());
+
+        match self.transform(features) {
+            Some(transformed) => self.k_nearest_transformed(transformed.view(), k),
+            None => self.k_nearest_transformed(features, k),
+        }
+    }
+
+    /// Scale and whiten a data point, if necessary.
+    fn transform(&self, features: ArrayView1<f32>) -> Option<Array1<f32>> {
+        let scaled = self
+            .scaler
+            .as_ref()
+            .map(|scaler| scaler.transform(features));
+
+        match self.whitening {
+            Some(ref mahalanobis) => Some(match scaled {
+                Some(scaled) => mahalanobis.whiten(scaled.view()),
+                None => mahalanobis.whiten(features),
+            }),
+            None => scaled,
+        }
+    }
+
+    /// Scale and whiten every row of a feature matrix, if necessary.
+    fn transform_rows(&self, features: ArrayView2<f32>) -> Option<Array2<f32>> {
+        let scaled = self
+            .scaler
+            .as_ref()
+            .map(|scaler| scaler.transform_rows(features));
+
+        match self.whitening {
+            Some(ref mahalanobis) => Some(match scaled {
+                Some(scaled) => mahalanobis.whiten_rows(scaled.view()),
+                None => mahalanobis.whiten_rows(features),
+            }),
+            None => scaled,
+        }
+    }
+
+    /// Find the k nearest neighbors of a data point that is already
+    /// scaled and whitened.
+    fn k_nearest_transformed(&self, features: ArrayView1<f32>, k: usize) -> Vec<Neighbor> {
+        // Removed data points are skipped, so more neighbors are requested
+        // from the index.
+        let indexed = self.index.k_nearest(
+            self.features.view(),
+            &self.metric,
+            features,
+            k + self.removed.len(),
+        );
+        self.with_inserted(indexed, features, k)
+    }
+
+    /// Combine neighbors from the feature matrix with the inserted data
+    /// points, skipping removed data points.
+    fn with_inserted(
+        &self,
+        indexed: Vec<Neighbor>,
+        features: ArrayView1<f32>,
+        k: usize,
+    ) -> Vec<Neighbor> {
+        if self.is_compact() {
+            return indexed;
+        }
+
+        let mut nearest_neighbors = NearestNeighbors::new(k);
+        for neighbor in indexed {
+            if !self.removed.contains(&neighbor.idx) {
+                nearest_neighbors.push(neighbor.idx, neighbor.distance.into_inner());
+            }
+        }
+
+        let n_indexed = self.features.rows();
+        for (idx, instance) in self.inserted_features().outer_iter().enumerate() {
+            if !self.removed.contains(&(n_indexed + idx)) {
+                nearest_neighbors.push(n_indexed + idx, self.metric.distance(instance, features));
+            }
+        }
+
+        nearest_neighbors.into_sorted_vec()
+    }
+
+    /// Find the k nearest neighbors of a batch of data points and map
+    /// them to predictions.
+    ///
+    /// Every row of the feature matrix is a data point. The data points
+    /// are processed in parallel, using the global rayon thread pool. The
+    /// predictions are returned in the order of the rows.
+    ///
+    /// With brute force search and the Euclidean distance, the distances
+    /// for blocks of data points are computed using matrix multiplication.
+    /// The neighbors are the same as those found by `k_nearest`.
+    pub fn k_nearest_batch<F, T>(&self, features: &ArrayView2<f32>, k: usize, predict: F) -> Vec<T>
+    where
+        F: Fn(&[Neighbor]) -> T + Sync,
+        S: Sync,
+        T: Send,
+    {
+        assert!(k > 0, "k should at least be 1");
+        self.check_n_features(features.cols());
+
+        let transformed = self.transform_rows(features.view());
+        let features = match transformed {
+            Some(ref transformed) => transformed.view(),
+            None => features.view(),
+        };
+
+        match (&self.index, &self.metric) {
+            (&Index::BruteForce, &Metrics::Euclidean) if self.is_compact() => {
+                let squared_norms = squared_row_norms(self.features.view());
+                let blocks: Vec<_> = features
+                    .axis_chunks_iter(Axis(0), QUERY_BLOCK_SIZE)
+                    .collect();
+                blocks
+                    .into_par_iter()
+                    .flat_map_iter(|block| {
+                        euclidean_k_nearest_batch(
+                            self.features.view(),
+                            squared_norms.view(),
+                            block,
+                            k,
+                        )
+                        .into_iter()
+                        .map(|nearest_neighbors| predict(&nearest_neighbors))
+                    })
+                    .collect()
+            }
+            _ => (0..features.rows())
+                .into_par_iter()
+                .map(|idx| predict(&self.k_nearest_transformed(features.row(idx), k)))
+                .collect(),
+        }
+    }
+
+    /// Find the neighbors of a data point within the given radius, sorted
+    /// from nearest to farthest.
+    pub fn within_radius(&self, features: ArrayView1<f32>, radius: f32) -> Vec<Neighbor> {
+        self.check_n_features(features.len());
+
+        match self.transform(features) {
+            Some(transformed) => self.within_radius_transformed(transformed.view(), radius),
+            None => self.within_radius_transformed(features, radius),
+        }
+    }
+
+    /// Find the neighbors of a batch of data points within the given
+    /// radius and map them to predictions.
+    ///
+    /// Every row of the feature matrix is a data point. The data points
+    /// are processed in parallel, using the global rayon thread pool. The
+    /// predictions are returned in the order of the rows.
+    pub fn within_radius_batch<F, T>(
+        &self,
+        features: &ArrayView2<f32>,
+        radius: f32,
+        predict: F,
+    ) -> Vec<T>
+    where
+        F: Fn(&[Neighbor]) -> T + Sync,
+        S: Sync,
+        T: Send,
+    {
+        self.check_n_features(features.cols());
+
+        let transformed = self.transform_rows(features.view());
+        let features = match transformed {
+            Some(ref transformed) => transformed.view(),
+            None => features.view(),
+        };
+
+        (0..features.rows())
+            .into_par_iter()
+            .map(|idx| predict(&self.within_radius_transformed(features.row(idx), radius)))
+            .collect()
+    }
+
+    /// Find the neighbors of a data point that is already scaled and
+    /// whitened within the given radius.
+    fn within_radius_transformed(&self, features: ArrayView1<f32>, radius: f32) -> Vec<Neighbor> {
+        assert!(
+            radius >= 0.,
+            "The radius should be non-negative, was: {}",
+            radius
+        );
+
+        let mut neighbors =
+            self.index
+                .within_radius(self.features.view(), &self.metric, features, radius);
+        if self.is_compact() {
+            return neighbors;
+        }
+
+        neighbors.retain(|neighbor| !self.removed.contains(&neighbor.idx));
+        let n_indexed = self.features.rows();
+        for (idx, instance) in self.inserted_features().outer_iter().enumerate() {
+            if !self.removed.contains(&(n_indexed + idx)) {
+                let distance = self.metric.distance(instance, features);
+                push_within_radius(&mut neighbors, n_indexed + idx, distance, radius);
+            }
+        }
+
+        neighbors.sort_unstable();
+        neighbors
+    }
+
+    /// Compute the recall of the nearest neighbor search for a data point.
+    ///
+    /// Returns the fraction of the k nearest neighbors found by brute
+    /// force search that are also found by the index. The recall is
+    /// always 1 for exact search strategies.
+    pub fn recall(&self, features: ArrayView1<f32>, k: usize) -> f32 {
+        self.check_n_features(features.len());
+
+        let transformed = self.transform(features);
+        let features = match transformed {
+            Some(ref transformed) => transformed.view(),
+            None => features.view(),
+        };
+
+        let exact = self.with_inserted(
+            brute_force_k_nearest(
+                self.features.view(),
+                &self.metric,
+                features,
+                k + self.removed.len(),
+            ),
+            features,
+            k,
+        );
+        let found = self.k_nearest_transformed(features, k);
+
+        let n_found = found.iter().filter(|n| exact.contains(n)).count();
+        n_found as f32 / exact.len() as f32
+    }
+}
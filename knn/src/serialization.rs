@@ -0,0 +1,398 @@
+use std::io::{self, Write};
+use std::mem;
+use std::slice;
+use std::str;
+
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use failure::{err_msg, Error};
+use ndarray::prelude::*;
+
+/// Magic number at the start of model files.
+const MAGIC: &[u8; 8] = b"KNNMODEL";
+
+/// Version of the model format.
+///
+/// The version must be incremented whenever the format changes, so that
+/// older model files are rejected rather than misread.
+pub(crate) const MODEL_VERSION: u32 = 7;
+
+/// Length of the magic number and version.
+const HEADER_LEN: usize = 12;
+
+/// Length of the checksummed length and the checksum at the end of the
+/// file.
+const TRAILER_LEN: usize = 16;
+
+/// The feature matrix is aligned to this number of bytes in model files.
+pub(crate) const FEATURES_ALIGNMENT: usize = 64;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+/// Update a 64-bit FNV-1a hash with the given bytes.
+fn fnv1a(mut hash: u64, data: &[u8]) -> u64 {
+    for &byte in data {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+
+    hash
+}
+
+/// Error for model files with invalid contents.
+pub(crate) fn invalid_model(msg: &str) -> Error {
+    err_msg(format!("Invalid model file: {}", msg))
+}
+
+/// Writer for model files.
+///
+/// The writer writes the header on construction. The checksum of the
+/// data that was written before `end_checksum` is appended by `finish`,
+/// together with the length of that data.
+pub(crate) struct ModelWriter<W> {
+    inner: W,
+    checksum: u64,
+    len: usize,
+    checked_len: Option<usize>,
+}
+
+impl<W> ModelWriter<W>
+where
+    W: Write,
+{
+    pub fn new(inner: W) -> io::Result<Self> {
+        let mut writer = ModelWriter {
+            inner,
+            checksum: FNV_OFFSET_BASIS,
+            len: 0,
+            checked_len: None,
+        };
+
+        writer.write_all(MAGIC)?;
+        writer.write_u32::<LittleEndian>(MODEL_VERSION)?;
+
+        Ok(writer)
+    }
+
+    /// Pad the file with zeros to a multiple of `alignment` bytes.
+    pub fn align(&mut self, alignment: usize) -> io::Result<()> {
+        let padding = (alignment - self.len % alignment) % alignment;
+        self.write_all(&vec![0; padding])
+    }
+
+    /// Exclude the data that is written after this call from the
+    /// checksum.
+    ///
+    /// This is used for large sections, such as the feature matrix, that
+    /// should not be read when a model file is opened.
+    pub fn end_checksum(&mut self) {
+        self.checked_len = Some(self.len);
+    }
+
+    pub fn write_usize(&mut self, v: usize) -> io::Result<()> {
+        self.write_u64::<LittleEndian>(v as u64)
+    }
+
+    /// Write a slice of indices, preceded by its length.
+    pub fn write_usizes(&mut self, data: &[usize]) -> io::Result<()> {
+        self.write_usize(data.len())?;
+        for &v in data {
+            self.write_usize(v)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write a string, preceded by its length in bytes.
+    pub fn write_str(&mut self, s: &str) -> io::Result<()> {
+        self.write_usize(s.len())?;
+        self.write_all(s.as_bytes())
+    }
+
+    /// Write floats, without their length.
+    pub fn write_f32s<'a, I>(&mut self, data: I) -> io::Result<()>
+    where
+        I: IntoIterator<Item = &'a f32>,
+    {
+        for &v in data {
+            self.write_f32::<LittleEndian>(v)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the checksummed length and the checksum, and flush the
+    /// underlying writer.
+    pub fn finish(mut self) -> io::Result<()> {
+        let checked_len = self.checked_len.unwrap_or(self.len);
+        let checksum = self.checksum;
+        self.inner.write_u64::<LittleEndian>(checked_len as u64)?;
+        self.inner.write_u64::<LittleEndian>(checksum)?;
+        self.inner.flush()
+    }
+}
+
+impl<W> Write for ModelWriter<W>
+where
+    W: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if self.checked_len.is_none() {
+            self.checksum = fnv1a(self.checksum, &buf[..n]);
+        }
+        self.len += n;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reader for model files.
+///
+/// All reads are bounds-checked, so that truncated or corrupted files
+/// result in an error.
+pub(crate) struct ModelReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ModelReader<'a> {
+    /// Open a model file, verifying its header and checksum.
+    ///
+    /// Only the data before the end of the checksum is read, so that
+    /// opening a memory-mapped file does not read the feature matrix.
+    pub fn new(data: &'a [u8]) -> Result<Self, Error> {
+        if data.len() < MAGIC.len() || &data[..MAGIC.len()] != MAGIC {
+            return Err(err_msg("File is not a KNN model"));
+        }
+
+        if data.len() < HEADER_LEN + TRAILER_LEN {
+            return Err(invalid_model("file is truncated"));
+        }
+
+        let version = LittleEndian::read_u32(&data[MAGIC.len()..HEADER_LEN]);
+        if version != MODEL_VERSION {
+            return Err(err_msg(format!(
+                "Unsupported model version {}, expected version {}",
+                version, MODEL_VERSION
+            )));
+        }
+
+        let (data, trailer) = data.split_at(data.len() - TRAILER_LEN);
+        let checked_len = LittleEndian::read_u64(&trailer[..8]);
+        if checked_len < HEADER_LEN as u64 || checked_len > data.len() as u64 {
+            return Err(invalid_model("checksum mismatch, the file is corrupted"));
+        }
+        let checked = &data[..checked_len as usize];
+        if fnv1a(FNV_OFFSET_BASIS, checked) != LittleEndian::read_u64(&trailer[8..]) {
+            return Err(invalid_model("checksum mismatch, the file is corrupted"));
+        }
+
+        Ok(ModelReader {
+            data,
+            pos: HEADER_LEN,
+        })
+    }
+
+    /// Read the given number of bytes.
+    pub fn bytes(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        if n > self.data.len() - self.pos {
+            return Err(invalid_model("file is truncated"));
+        }
+
+        let bytes = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    /// Skip the padding up to a multiple of `alignment` bytes.
+    pub fn align(&mut self, alignment: usize) -> Result<(), Error> {
+        let padding = (alignment - self.pos % alignment) % alignment;
+        self.bytes(padding).map(|_| ())
+    }
+
+    /// Check that all data was read.
+    pub fn finish(self) -> Result<(), Error> {
+        if self.pos == self.data.len() {
+            Ok(())
+        } else {
+            Err(invalid_model("trailing data"))
+        }
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, Error> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, Error> {
+        Ok(LittleEndian::read_u64(self.bytes(8)?))
+    }
+
+    pub fn read_f32(&mut self) -> Result<f32, Error> {
+        Ok(LittleEndian::read_f32(self.bytes(4)?))
+    }
+
+    pub fn read_usize(&mut self) -> Result<usize, Error> {
+        let v = self.read_u64()?;
+        if v > usize::MAX as u64 {
+            return Err(invalid_model("value is too large for this platform"));
+        }
+
+        Ok(v as usize)
+    }
+
+    /// Read a length, verifying that the file contains enough data for
+    /// `len` elements of `elem_size` bytes.
+    pub fn read_len(&mut self, elem_size: usize) -> Result<usize, Error> {
+        let len = self.read_usize()?;
+        self.check_len(len, elem_size)?;
+        Ok(len)
+    }
+
+    fn check_len(&self, len: usize, elem_size: usize) -> Result<(), Error> {
+        match len.checked_mul(elem_size) {
+            Some(n_bytes) if n_bytes <= self.data.len() - self.pos => Ok(()),
+            _ => Err(invalid_model("file is truncated")),
+        }
+    }
+
+    /// Read an index that must be smaller than `bound`.
+    pub fn read_index(&mut self, bound: usize) -> Result<usize, Error> {
+        let idx = self.read_usize()?;
+        if idx >= bound {
+            return Err(invalid_model("index out of bounds"));
+        }
+
+        Ok(idx)
+    }
+
+    /// Read a slice of indices that must be smaller than `bound`,
+    /// preceded by its length.
+    pub fn read_indices(&mut self, bound: usize) -> Result<Vec<usize>, Error> {
+        let len = self.read_len(8)?;
+        (0..len).map(|_| self.read_index(bound)).collect()
+    }
+
+    /// Read a string, preceded by its length in bytes.
+    pub fn read_str(&mut self) -> Result<&'a str, Error> {
+        let len = self.read_len(1)?;
+        str::from_utf8(self.bytes(len)?).map_err(|_| invalid_model("string is not valid UTF-8"))
+    }
+
+    /// Read `len` floats.
+    pub fn read_f32s(&mut self, len: usize) -> Result<Vec<f32>, Error> {
+        self.check_len(len, 4)?;
+        (0..len).map(|_| self.read_f32()).collect()
+    }
+
+    /// Read a matrix of floats without copying.
+    ///
+    /// This requires a little-endian platform and data that is aligned
+    /// for `f32`.
+    pub fn read_f32_view(
+        &mut self,
+        rows: usize,
+        cols: usize,
+    ) -> Result<ArrayView2<'a, f32>, Error> {
+        if cfg!(target_endian = "big") {
+            return Err(err_msg(
+                "Models can only be loaded without copying on little-endian platforms",
+            ));
+        }
+
+        let len = rows
+            .checked_mul(cols)
+            .ok_or_else(|| invalid_model("file is truncated"))?;
+        self.check_len(len, 4)?;
+        let bytes = self.bytes(len * 4)?;
+        if bytes.as_ptr() as usize % mem::align_of::<f32>() != 0 {
+            return Err(err_msg(
+                "Model data is not aligned for loading without copying",
+            ));
+        }
+
+        // The bytes are in bounds and aligned. Every bit pattern is a
+        // valid f32 and the platform byte order matches the file.
+        let values = unsafe { slice::from_raw_parts(bytes.as_ptr() as *const f32, len) };
+
+        Ok(ArrayView2::from_shape((rows, cols), values)
+            .expect("Number of elements does not correspond to the shape"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::{ModelReader, ModelWriter, FEATURES_ALIGNMENT};
+
+    fn model_file() -> Vec<u8> {
+        let mut data = Vec::new();
+        {
+            let mut writer = ModelWriter::new(&mut data).unwrap();
+            writer.write_usizes(&[3, 1, 2]).unwrap();
+            writer.write_str("ham").unwrap();
+            writer.align(FEATURES_ALIGNMENT).unwrap();
+            writer.end_checksum();
+            writer.write_f32s(&[0.5, -1.]).unwrap();
+            writer.finish().unwrap();
+        }
+        data
+    }
+
+    #[test]
+    fn roundtrip_test() {
+        let data = model_file();
+        let mut reader = ModelReader::new(&data).unwrap();
+        assert_eq!(reader.read_indices(4).unwrap(), vec![3, 1, 2]);
+        assert_eq!(reader.read_str().unwrap(), "ham");
+        reader.align(FEATURES_ALIGNMENT).unwrap();
+        assert_eq!(reader.read_f32s(2).unwrap(), vec![0.5, -1.]);
+        reader.finish().unwrap();
+    }
+
+    #[test]
+    fn invalid_file_test() {
+        let data = model_file();
+
+        let mut corrupted = data.clone();
+        corrupted[20] ^= 1;
+        assert!(ModelReader::new(&corrupted).is_err());
+
+        // Data after the end of the checksum is not verified.
+        let mut unchecked = data.clone();
+        let len = unchecked.len();
+        unchecked[len - 20] ^= 1;
+        assert!(ModelReader::new(&unchecked).is_ok());
+
+        let mut version = data.clone();
+        version[8] = 42;
+        let err = ModelReader::new(&version).err().unwrap();
+        assert!(err.to_string().contains("version 42"));
+
+        assert!(ModelReader::new(&data[..data.len() - 1]).is_err());
+        assert!(ModelReader::new(b"KNNMODEL").is_err());
+        assert!(ModelReader::new(b"label 1.0 2.0\n").is_err());
+
+        // Indices are checked against their bound.
+        let mut reader = ModelReader::new(&data).unwrap();
+        assert!(reader.read_indices(3).is_err());
+    }
+
+    #[test]
+    fn truncated_length_test() {
+        let mut data = Vec::new();
+        {
+            let mut writer = ModelWriter::new(&mut data).unwrap();
+            writer.write_usize(1 << 40).unwrap();
+            writer.flush().unwrap();
+            writer.finish().unwrap();
+        }
+
+        let mut reader = ModelReader::new(&data).unwrap();
+        assert!(reader.read_len(8).is_err());
+    }
+}
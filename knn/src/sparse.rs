@@ -0,0 +1,245 @@
+use std::cmp::Ordering;
+
+use {Chebyshev, Cosine, Euclidean, Manhattan, Metrics, Minkowski, SquaredEuclidean};
+
+/// A sparse feature vector.
+///
+/// Only the non-zero features are stored, as pairs of feature indices
+/// and values. The indices are strictly increasing.
+#[derive(Clone, Debug, Default, PartialEq, PartialOrd)]
+pub struct SparseVector {
+    indices: Vec<usize>,
+    values: Vec<f32>,
+}
+
+impl SparseVector {
+    /// Construct a sparse vector from feature indices and values.
+    ///
+    /// The indices must be strictly increasing.
+    pub fn new(indices: Vec<usize>, values: Vec<f32>) -> Self {
+        assert_eq!(
+            indices.len(),
+            values.len(),
+            "Number of indices and values should be equal"
+        );
+        assert!(
+            indices.windows(2).all(|w| w[0] < w[1]),
+            "Indices should be strictly increasing"
+        );
+
+        SparseVector { indices, values }
+    }
+
+    /// Construct a sparse vector from a dense feature vector.
+    pub fn from_dense(features: &[f32]) -> Self {
+        let (indices, values) = features
+            .iter()
+            .enumerate()
+            .filter(|&(_, &v)| v != 0.)
+            .unzip();
+        SparseVector { indices, values }
+    }
+
+    /// Get the indices of the stored features.
+    pub fn indices(&self) -> &[usize] {
+        &self.indices
+    }
+
+    /// Get the values of the stored features.
+    pub fn values(&self) -> &[f32] {
+        &self.values
+    }
+
+    /// Get a view of the vector.
+    pub fn view(&self) -> SparseView<'_> {
+        SparseView {
+            indices: &self.indices,
+            values: &self.values,
+        }
+    }
+}
+
+/// A borrowed sparse feature vector.
+#[derive(Clone, Copy, Debug)]
+pub struct SparseView<'a> {
+    indices: &'a [usize],
+    values: &'a [f32],
+}
+
+impl<'a> SparseView<'a> {
+    pub(crate) fn new(indices: &'a [usize], values: &'a [f32]) -> Self {
+        SparseView { indices, values }
+    }
+
+    /// Get the indices of the stored features.
+    pub fn indices(&self) -> &'a [usize] {
+        self.indices
+    }
+
+    /// Get the values of the stored features.
+    pub fn values(&self) -> &'a [f32] {
+        self.values
+    }
+
+    /// Compute the dot product with another sparse vector.
+    pub fn dot(&self, other: SparseView) -> f32 {
+        fold_union(*self, other, 0., |acc, a, b| acc + a * b)
+    }
+
+    /// Compute the L2 norm.
+    pub fn l2_norm(&self) -> f32 {
+        self.values.iter().map(|&v| v * v).sum::<f32>().sqrt()
+    }
+}
+
+/// Fold over the union of the features of two sparse vectors.
+///
+/// The folding function is called with the accumulator and the values of
+/// a feature in both vectors, where a missing feature has value zero.
+/// Features are visited in index order.
+fn fold_union<F>(v1: SparseView, v2: SparseView, init: f32, mut f: F) -> f32
+where
+    F: FnMut(f32, f32, f32) -> f32,
+{
+    let mut acc = init;
+    let (mut i, mut j) = (0, 0);
+
+    while i < v1.indices.len() && j < v2.indices.len() {
+        match v1.indices[i].cmp(&v2.indices[j]) {
+            Ordering::Less => {
+                acc = f(acc, v1.values[i], 0.);
+                i += 1;
+            }
+            Ordering::Greater => {
+                acc = f(acc, 0., v2.values[j]);
+                j += 1;
+            }
+            Ordering::Equal => {
+                acc = f(acc, v1.values[i], v2.values[j]);
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+
+    for &v in &v1.values[i..] {
+        acc = f(acc, v, 0.);
+    }
+    for &v in &v2.values[j..] {
+        acc = f(acc, 0., v);
+    }
+
+    acc
+}
+
+/// Distance metrics between sparse feature vectors.
+///
+/// The distances are computed directly on the stored features and are
+/// equal to the distances between the corresponding dense vectors.
+pub trait SparseMetric {
+    /// Compute the distance between two sparse feature vectors.
+    fn sparse_distance(&self, v1: SparseView, v2: SparseView) -> f32;
+}
+
+impl SparseMetric for Euclidean {
+    fn sparse_distance(&self, v1: SparseView, v2: SparseView) -> f32 {
+        SquaredEuclidean.sparse_distance(v1, v2).sqrt()
+    }
+}
+
+impl SparseMetric for SquaredEuclidean {
+    fn sparse_distance(&self, v1: SparseView, v2: SparseView) -> f32 {
+        fold_union(v1, v2, 0., |acc, a, b| acc + (a - b) * (a - b))
+    }
+}
+
+impl SparseMetric for Manhattan {
+    fn sparse_distance(&self, v1: SparseView, v2: SparseView) -> f32 {
+        fold_union(v1, v2, 0., |acc, a, b| acc + (a - b).abs())
+    }
+}
+
+impl SparseMetric for Chebyshev {
+    fn sparse_distance(&self, v1: SparseView, v2: SparseView) -> f32 {
+        fold_union(v1, v2, 0., |acc, a, b| acc.max((a - b).abs()))
+    }
+}
+
+impl SparseMetric for Minkowski {
+    fn sparse_distance(&self, v1: SparseView, v2: SparseView) -> f32 {
+        let p = self.p();
+        fold_union(v1, v2, 0., |acc, a, b| acc + (a - b).abs().powf(p)).powf(1. / p)
+    }
+}
+
+impl SparseMetric for Cosine {
+    fn sparse_distance(&self, v1: SparseView, v2: SparseView) -> f32 {
+        let norms = v1.l2_norm() * v2.l2_norm();
+        if norms == 0. {
+            1.
+        } else {
+            1. - v1.dot(v2) / norms
+        }
+    }
+}
+
+/// Distances between sparse vectors for the built-in metrics.
+///
+/// Panics for metrics that do not support sparse vectors, see
+/// `Metrics::supports_sparse`.
+impl SparseMetric for Metrics {
+    fn sparse_distance(&self, v1: SparseView, v2: SparseView) -> f32 {
+        match *self {
+            Metrics::Euclidean => Euclidean.sparse_distance(v1, v2),
+            Metrics::SquaredEuclidean => SquaredEuclidean.sparse_distance(v1, v2),
+            Metrics::Manhattan => Manhattan.sparse_distance(v1, v2),
+            Metrics::Chebyshev => Chebyshev.sparse_distance(v1, v2),
+            Metrics::Minkowski(ref minkowski) => minkowski.sparse_distance(v1, v2),
+            Metrics::Cosine => Cosine.sparse_distance(v1, v2),
+            Metrics::Mahalanobis(_) | Metrics::Custom(_) => {
+                panic!("The {:?} metric does not support sparse vectors", self)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::ArrayView1;
+
+    use super::{SparseMetric, SparseVector};
+    use {Chebyshev, Cosine, Euclidean, Manhattan, Metric, Minkowski, SquaredEuclidean};
+
+    fn check_against_dense<M>(metric: M, v1: &[f32], v2: &[f32])
+    where
+        M: Metric + SparseMetric,
+    {
+        let dense = metric.distance(ArrayView1::from(v1), ArrayView1::from(v2));
+        let sparse = metric.sparse_distance(
+            SparseVector::from_dense(v1).view(),
+            SparseVector::from_dense(v2).view(),
+        );
+        assert_abs_diff_eq!(dense, sparse, epsilon = 1e-6);
+    }
+
+    #[test]
+    fn sparse_distance_test() {
+        let v1 = [0., 1., 0., -2., 0., 3.];
+        let v2 = [1., 0., 0., 2., 0., 0.];
+
+        for &(a, b) in &[(&v1, &v2), (&v2, &v1), (&v1, &v1), (&v1, &[0.; 6])] {
+            check_against_dense(Euclidean, a, b);
+            check_against_dense(SquaredEuclidean, a, b);
+            check_against_dense(Manhattan, a, b);
+            check_against_dense(Chebyshev, a, b);
+            check_against_dense(Minkowski::new(3.), a, b);
+            check_against_dense(Cosine, a, b);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn unsorted_indices_test() {
+        SparseVector::new(vec![3, 1], vec![1., 2.]);
+    }
+}
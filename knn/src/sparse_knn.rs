@@ -0,0 +1,181 @@
+use rayon::prelude::*;
+
+use index::{NearestNeighbors, Neighbor};
+use knn::vote;
+use sparse::SparseView;
+use {Metrics, SparseInstance, SparseMetric, SparseVector, TieBreaking, Weights};
+
+/// `SparseKNNBuilder` collects data points with sparse features for KNN
+/// classification.
+#[derive(Default)]
+pub struct SparseKNNBuilder {
+    labels: Vec<usize>,
+    offsets: Vec<usize>,
+    indices: Vec<usize>,
+    values: Vec<f32>,
+    metric: Metrics,
+}
+
+impl SparseKNNBuilder {
+    /// Set the distance metric.
+    ///
+    /// The default metric is the Euclidean distance. The metric must
+    /// support sparse vectors, see `Metrics::supports_sparse`.
+    pub fn set_metric(&mut self, metric: Metrics) {
+        assert!(
+            metric.supports_sparse(),
+            "The {:?} metric does not support sparse vectors",
+            metric
+        );
+        self.metric = metric;
+    }
+
+    /// Push a new data point into the builder.
+    pub fn push(&mut self, inst: SparseInstance) {
+        self.labels.push(inst.label);
+        self.indices.extend_from_slice(inst.features.indices());
+        self.values.extend_from_slice(inst.features.values());
+        self.offsets.push(self.indices.len());
+    }
+}
+
+/// A K Nearest Neighbor classifier for sparse feature vectors.
+///
+/// The training data is stored in compressed sparse row format and
+/// distances are computed directly on the non-zero features, so the cost
+/// of a distance is linear in the number of non-zero features rather
+/// than in the dimensionality. Nearest neighbors are found with brute
+/// force search.
+pub struct SparseKNN {
+    labels: Vec<usize>,
+    offsets: Vec<usize>,
+    indices: Vec<usize>,
+    values: Vec<f32>,
+    metric: Metrics,
+    weights: Weights,
+    tie_breaking: TieBreaking,
+}
+
+impl From<SparseKNNBuilder> for SparseKNN {
+    fn from(builder: SparseKNNBuilder) -> Self {
+        SparseKNN {
+            labels: builder.labels,
+            offsets: builder.offsets,
+            indices: builder.indices,
+            values: builder.values,
+            metric: builder.metric,
+            weights: Weights::default(),
+            tie_breaking: TieBreaking::default(),
+        }
+    }
+}
+
+impl SparseKNN {
+    /// Set the weighting of neighbors in voting.
+    ///
+    /// By default, every neighbor has one vote.
+    /// Panics when the Gaussian bandwidth is not positive and finite.
+    pub fn set_weights(&mut self, weights: Weights) {
+        assert!(
+            weights.is_valid(),
+            "The Gaussian bandwidth should be positive and finite"
+        );
+        self.weights = weights;
+    }
+
+    /// Set the breaking of ties between classes.
+    ///
+    /// By default, the smallest of the tied labels is predicted.
+    pub fn set_tie_breaking(&mut self, tie_breaking: TieBreaking) {
+        self.tie_breaking = tie_breaking;
+    }
+
+    /// Classify a data point.
+    ///
+    /// The feature vector and the number of nearest neighbors to used are
+    /// specified as arguments. The predicted class is returned.
+    pub fn classify(&self, features: &SparseVector, k: usize) -> usize {
+        vote(
+            &self.labels,
+            None,
+            self.weights,
+            self.tie_breaking,
+            &self.k_nearest(features.view(), k),
+        )
+    }
+
+    /// Classify a batch of data points.
+    ///
+    /// The data points are classified in parallel, using the global rayon
+    /// thread pool. The predicted classes are returned in the order of the
+    /// data points.
+    pub fn classify_batch(&self, features: &[SparseVector], k: usize) -> Vec<usize> {
+        features
+            .par_iter()
+            .map(|features| self.classify(features, k))
+            .collect()
+    }
+
+    fn instance(&self, idx: usize) -> SparseView<'_> {
+        let start = if idx == 0 { 0 } else { self.offsets[idx - 1] };
+        let end = self.offsets[idx];
+        SparseView::new(&self.indices[start..end], &self.values[start..end])
+    }
+
+    fn k_nearest(&self, query: SparseView, k: usize) -> Vec<Neighbor> {
+        let mut nearest_neighbors = NearestNeighbors::new(k);
+        for idx in 0..self.labels.len() {
+            nearest_neighbors.push(idx, self.metric.sparse_distance(self.instance(idx), query));
+        }
+
+        nearest_neighbors.into_sorted_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use index::tests::{feature_matrix, read_instances};
+    use {
+        IndexType, Instance, KNNBuilder, Metrics, SparseKNN, SparseKNNBuilder, SparseVector,
+        Weights, KNN,
+    };
+
+    #[test]
+    fn sparse_dense_agreement_test() {
+        let train = read_instances(include_str!("../moons-train.txt"));
+        let test = read_instances(include_str!("../moons-test.txt"));
+        let test_features = feature_matrix(&test);
+        let sparse_test: Vec<_> = test
+            .iter()
+            .map(|inst| SparseVector::from_dense(&inst.features))
+            .collect();
+
+        for metric in [Metrics::Euclidean, Metrics::Manhattan, Metrics::Cosine] {
+            let mut builder = KNNBuilder::default();
+            builder.set_index_type(IndexType::BruteForce);
+            builder.set_metric(metric.clone());
+            let mut sparse_builder = SparseKNNBuilder::default();
+            sparse_builder.set_metric(metric);
+            for inst in &train {
+                builder.push(Instance {
+                    label: inst.label,
+                    features: inst.features.clone(),
+                });
+                sparse_builder.push(Instance {
+                    label: inst.label,
+                    features: SparseVector::from_dense(&inst.features),
+                });
+            }
+
+            let mut model: KNN = builder.into();
+            model.set_weights(Weights::InverseDistance);
+            let mut sparse_model: SparseKNN = sparse_builder.into();
+            sparse_model.set_weights(Weights::InverseDistance);
+
+            assert_eq!(
+                sparse_model.classify_batch(&sparse_test, 5),
+                model.classify_batch(&test_features.view(), 5)
+            );
+        }
+    }
+}
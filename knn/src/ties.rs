@@ -0,0 +1,62 @@
+use std::io::{self, Write};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use failure::Error;
+
+use serialization::{invalid_model, ModelReader, ModelWriter};
+
+/// Breaking of ties between classes with the same vote.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TieBreaking {
+    /// Predict the smallest of the tied labels.
+    #[default]
+    SmallestLabel,
+
+    /// Predict the label of the nearest neighbor with a tied label.
+    NearestNeighbor,
+
+    /// Predict the tied label with the smallest summed distance of its
+    /// neighbors. Remaining ties are broken by the smallest label.
+    SmallestDistance,
+
+    /// Predict a random tied label.
+    ///
+    /// The choice is determined by the seed and the nearest neighbors, so
+    /// that predictions are reproducible and do not depend on the order
+    /// in which data points are classified.
+    Random { seed: u64 },
+
+    /// Remove the farthest neighbor until the tie is broken.
+    DecreaseK,
+}
+
+impl TieBreaking {
+    pub(crate) fn write<W>(self, writer: &mut ModelWriter<W>) -> io::Result<()>
+    where
+        W: Write,
+    {
+        match self {
+            TieBreaking::SmallestLabel => writer.write_u8(0),
+            TieBreaking::NearestNeighbor => writer.write_u8(1),
+            TieBreaking::SmallestDistance => writer.write_u8(2),
+            TieBreaking::Random { seed } => {
+                writer.write_u8(3)?;
+                writer.write_u64::<LittleEndian>(seed)
+            }
+            TieBreaking::DecreaseK => writer.write_u8(4),
+        }
+    }
+
+    pub(crate) fn read(reader: &mut ModelReader) -> Result<Self, Error> {
+        match reader.read_u8()? {
+            0 => Ok(TieBreaking::SmallestLabel),
+            1 => Ok(TieBreaking::NearestNeighbor),
+            2 => Ok(TieBreaking::SmallestDistance),
+            3 => Ok(TieBreaking::Random {
+                seed: reader.read_u64()?,
+            }),
+            4 => Ok(TieBreaking::DecreaseK),
+            _ => Err(invalid_model("unknown tie breaking")),
+        }
+    }
+}
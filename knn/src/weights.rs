@@ -0,0 +1,174 @@
+use std::io::{self, Write};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use failure::Error;
+
+use index::Neighbor;
+use serialization::{invalid_model, ModelReader, ModelWriter};
+
+/// Weighting of the nearest neighbors in predictions.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Weights {
+    /// All neighbors have the same weight.
+    #[default]
+    Uniform,
+
+    /// Neighbors are weighted by the inverse of their distance.
+    InverseDistance,
+
+    /// Neighbors are weighted by the inverse of their squared distance.
+    InverseSquaredDistance,
+
+    /// Neighbors are weighted by a Gaussian kernel with the given
+    /// bandwidth, `exp(-d² / (2 bandwidth²))`.
+    Gaussian { bandwidth: f32 },
+}
+
+impl Weights {
+    /// Check that the bandwidth of Gaussian weights is positive and
+    /// finite.
+    pub(crate) fn is_valid(self) -> bool {
+        match self {
+            Weights::Gaussian { bandwidth } => bandwidth.is_finite() && bandwidth > 0.,
+            _ => true,
+        }
+    }
+
+    pub(crate) fn write<W>(self, writer: &mut ModelWriter<W>) -> io::Result<()>
+    where
+        W: Write,
+    {
+        match self {
+            Weights::Uniform => writer.write_u8(0),
+            Weights::InverseDistance => writer.write_u8(1),
+            Weights::InverseSquaredDistance => writer.write_u8(2),
+            Weights::Gaussian { bandwidth } => {
+                writer.write_u8(3)?;
+                writer.write_f32::<LittleEndian>(bandwidth)
+            }
+        }
+    }
+
+    pub(crate) fn read(reader: &mut ModelReader) -> Result<Self, Error> {
+        match reader.read_u8()? {
+            0 => Ok(Weights::Uniform),
+            1 => Ok(Weights::InverseDistance),
+            2 => Ok(Weights::InverseSquaredDistance),
+            3 => {
+                let weights = Weights::Gaussian {
+                    bandwidth: reader.read_f32()?,
+                };
+                if !weights.is_valid() {
+                    return Err(invalid_model("invalid Gaussian bandwidth"));
+                }
+                Ok(weights)
+            }
+            _ => Err(invalid_model("unknown neighbor weights")),
+        }
+    }
+
+    /// Compute the weights of the nearest neighbors.
+    ///
+    /// When inverse distance weights are used and one or more neighbors
+    /// are at distance zero, these neighbors get weight 1 and all other
+    /// neighbors weight 0. When all weights are zero, for instance because
+    /// the Gaussian kernel underflows for distant neighbors, all neighbors
+    /// get the same weight.
+    pub(crate) fn neighbor_weights(self, nearest_neighbors: &[Neighbor]) -> Vec<f32> {
+        let distances = nearest_neighbors.iter().map(|n| n.distance.into_inner());
+
+        let weights: Vec<f32> = match self {
+            Weights::Uniform => return vec![1.; nearest_neighbors.len()],
+            Weights::InverseDistance | Weights::InverseSquaredDistance
+                if nearest_neighbors
+                    .iter()
+                    .any(|n| n.distance.into_inner() == 0.) =>
+            {
+                distances.map(|d| if d == 0. { 1. } else { 0. }).collect()
+            }
+            Weights::InverseDistance => distances.map(|d| 1. / d).collect(),
+            Weights::InverseSquaredDistance => distances.map(|d| 1. / (d * d)).collect(),
+            Weights::Gaussian { bandwidth } => distances
+                .map(|d| (-(d * d) / (2. * bandwidth * bandwidth)).exp())
+                .collect(),
+        };
+
+        if weights.iter().all(|&w| w == 0.) {
+            vec![1.; nearest_neighbors.len()]
+        } else {
+            weights
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ordered_float::OrderedFloat;
+
+    use index::Neighbor;
+    use Weights;
+
+    fn neighbors(distances: &[f32]) -> Vec<Neighbor> {
+        distances
+            .iter()
+            .enumerate()
+            .map(|(idx, &distance)| Neighbor {
+                distance: OrderedFloat(distance),
+                idx,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn uniform_weights_test() {
+        assert_eq!(
+            Weights::Uniform.neighbor_weights(&neighbors(&[0., 1., 2.])),
+            vec![1., 1., 1.]
+        );
+    }
+
+    #[test]
+    fn inverse_distance_weights_test() {
+        assert_eq!(
+            Weights::InverseDistance.neighbor_weights(&neighbors(&[0.5, 1., 4.])),
+            vec![2., 1., 0.25]
+        );
+    }
+
+    #[test]
+    fn inverse_squared_distance_weights_test() {
+        assert_eq!(
+            Weights::InverseSquaredDistance.neighbor_weights(&neighbors(&[0.5, 1., 4.])),
+            vec![4., 1., 0.0625]
+        );
+    }
+
+    #[test]
+    fn gaussian_weights_test() {
+        let weights =
+            Weights::Gaussian { bandwidth: 2. }.neighbor_weights(&neighbors(&[0., 2., 4.]));
+        assert_abs_diff_eq!(weights[0], 1.);
+        assert_abs_diff_eq!(weights[1], (-0.5f32).exp());
+        assert_abs_diff_eq!(weights[2], (-2f32).exp());
+    }
+
+    #[test]
+    fn gaussian_underflow_test() {
+        assert_eq!(
+            Weights::Gaussian { bandwidth: 1e-3 }.neighbor_weights(&neighbors(&[10., 20.])),
+            vec![1., 1.]
+        );
+    }
+
+    #[test]
+    fn inverse_distance_zero_test() {
+        assert_eq!(
+            Weights::InverseDistance.neighbor_weights(&neighbors(&[0., 0., 4.])),
+            vec![1., 1., 0.]
+        );
+        assert_eq!(
+            Weights::InverseSquaredDistance.neighbor_weights(&neighbors(&[0., 1.])),
+            vec![1., 0.]
+        );
+    }
+}